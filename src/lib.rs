@@ -1,4 +1,4 @@
-/**
+/*!
  * Tyler Hardin
  * 8/29/2016
  *
@@ -6,10 +6,25 @@
  * repeats, and literal chars.
  */
 
-use std::collections::{BTreeSet,BTreeMap};
+#![allow(clippy::needless_return, clippy::redundant_field_names, clippy::should_implement_trait)]
+
+use std::borrow::Cow;
+use std::cmp;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fmt;
-use std::rc::Rc;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::io::BufRead;
+use std::io::Read;
+use std::iter;
+use std::ops::Range;
 use std::str::Chars;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
 
 /**
  * A collection mapping group number to matched string.
@@ -17,572 +32,8140 @@ use std::str::Chars;
 pub type MatchResult = BTreeMap<usize,String>;
 
 /**
- * A struct for representing and using regular expressions.
+ * The groups matched by a regex, along with the names of named groups, and
+ * every match of each group if the regex keeps capture history (see
+ * Captures::get_all).
  */
-pub struct Regex {
-    root : GrpNode
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Captures {
+    /// The string matched by each group that participated in the match.
+    groups : MatchResult,
+    /// The name of each group, indexed by group number.
+    names : Vec<Option<String>>,
+    /// Every string matched by each group, indexed by group number.
+    history : Vec<Vec<String>>,
+    /// The span (start and end position) of each group matched, indexed by
+    /// group number.
+    spans : Vec<Option<(usize, usize)>>
 }
 
-impl Regex {
+impl Captures {
     /**
-     * Creates a regex from a str that represents a regex. Panics if the
-     * regex is not well-formed.
+     * Returns the string matched by a group, or None if the group didn't
+     * participate in the match. Group 0 is the whole match.
      */
-    pub fn from_str(s : &str) -> Regex {
-        Regex {
-            root : GrpNode::parse(&mut s.chars(), &mut 0, true)
-        }
+    pub fn get(&self, num : usize) -> Option<&str> {
+        self.groups.get(&num).map(|s| s.as_str())
     }
 
     /**
-     * Matches a str against a regex.
-     *
-     * * regex - the regular expression
-     * * s     - a str to match
+     * Returns an iterator over the range (in bytes) of the input each group
+     * matched, or None for groups that didn't participate, in order of group
+     * number, starting with group 0 (the whole match).
      */
-    pub fn match_str(&self, s : &str) -> Option<MatchResult> {
-        self.match_chars(&mut s.chars())
+    pub fn iter_spans<'a>(&'a self) -> impl Iterator<Item=Option<Range<usize>>> + 'a {
+        self.spans.iter().map(|span| span.map(|(start, end)| start..end))
     }
 
     /**
-     * Matches a char iterator against a regex.
-     *
-     * * regex - the regular expression
-     * * itr   - an iterator to match
+     * Returns the string matched by a named group, or None if there is no
+     * such group or it didn't participate in the match.
      */
-     pub fn match_chars(&self, itr : &mut Chars) -> Option<MatchResult> {
-        let mut mr = MatchResult::new();
-        let res = self.root.match_chars(itr, &mut mr);
-
-        // Did it match and did it match the whole string?
-        if res.is_some() && itr.count() == 0 {
-            Some(mr)
-        } else {
-            None
-        }
-     }
-}
-
-impl fmt::Debug for Regex {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Regex {}", self.root.debug())
+    pub fn name(&self, name : &str) -> Option<&str> {
+        self.names.iter()
+            .position(|n| n.as_ref().map(|n| n.as_str()) == Some(name))
+            .and_then(|num| self.get(num))
     }
-}
 
-/// Interface for regex tree nodes.
-trait Node {
     /**
-     * Matches this node against (part of) a string. The match must start at
-     * the first char of itr.
-     *
-     * Returns the string that the node matched.
-     *
-     * * itr -  current position in the string
-     * * mr  -  MatchResult in which to store group matches
+     * Returns every string matched by a group, in order. A group in a repeat
+     * can match once per repetition, but get only returns the last match;
+     * this returns them all if the regex was built with capture history (see
+     * RegexBuilder::capture_history). Otherwise, it returns just the last.
      */
-    fn match_chars(&self, &mut Chars, &mut MatchResult) -> Option<String>;
+    pub fn get_all(&self, num : usize) -> &[String] {
+        self.history.get(num).map_or(&[], |all| all.as_slice())
+    }
 
     /**
-     * Prints this node in normal regex syntax.
+     * Returns the group matches as a MatchResult.
      */
-    fn debug(&self) -> String;
-}
-
-/// Represents an alternation.
-struct AltNode {
-    /// A vec of alternative sequences.
-    alts : Vec<SeqNode>
-}
-
-/// Represents a char literal.
-struct CharNode {
-    /// The char literal this node represents.
-    c : char
-}
-
-/// Represents a character class.
-struct CharClassNode {
-    /// Elements matched by this class.
-    elems : BTreeSet<char>,
-    /// Whether the class is negated.
-    negated : bool
-}
+    pub fn match_result(&self) -> &MatchResult {
+        &self.groups
+    }
 
-/// Represents a group.
-struct GrpNode {
-    /// The number of this group.
-    num : usize,
-    /// The list of alternative sequences.
-    alt : AltNode
-}
+    /**
+     * Fills in a template with the groups matched.
+     *
+     * "$N" and "$name" are replaced with the string matched by the group with
+     * that number or name, where the reference is the longest run of
+     * alphanumerics and '_' following the '$'. "${N}" and "${name}" can be
+     * used to delimit a reference, and "$$" is a literal '$'. References to
+     * groups that don't exist or didn't participate are replaced with
+     * nothing. A '$' that doesn't start a reference is kept as is.
+     *
+     * * template - the template to fill in
+     */
+    pub fn expand(&self, template : &str) -> String {
+        let mut out = String::new();
+        let mut itr = template.chars();
 
-/// Represents a *.
-struct RptNode {
-    /// The node to be repeated.
-    node : Rc<Node>
-}
+        while let Some(c) = itr.next() {
+            if c != '$' {
+                out.push(c);
+                continue;
+            }
 
-/// Represents a sequence.
-struct SeqNode {
-    /// Nodes that together form a sequence.
-    nodes : Vec<Rc<Node>>
-}
+            let mut peek = itr.clone();
+            let mut reference = String::new();
+            match peek.next() {
+                Some('$') => {
+                    out.push('$');
+                    itr.clone_from(&peek);
+                    continue;
+                }
+                Some('{') => {
+                    // Delimited reference. Take everything up to the '}'.
+                    let mut closed = false;
+                    for c in peek.by_ref() {
+                        if c == '}' {
+                            closed = true;
+                            break;
+                        }
+                        reference.push(c);
+                    }
 
-impl Node for AltNode {
-    fn match_chars(&self, itr : &mut Chars, mr : &mut MatchResult) -> Option<String> {
-        // Try each alternative.
-        for alt in &self.alts {
-            // Store for backtracking.
-            let mut clone = itr.clone();
+                    if !closed || reference.is_empty() {
+                        out.push('$');
+                        continue;
+                    }
+                    itr.clone_from(&peek);
+                }
+                _ => {
+                    // Undelimited reference. Take the longest possible name.
+                    let mut after = itr.clone();
+                    while let Some(c) = after.next() {
+                        if !(c.is_alphanumeric() || c == '_') {
+                            break;
+                        }
+                        reference.push(c);
+                        itr.clone_from(&after);
+                    }
 
-            // Return the first successful match.
-            let res = alt.match_chars(&mut clone, mr);
-            if res.is_some() {
-                itr.clone_from(&clone);
-                return res;
+                    if reference.is_empty() {
+                        out.push('$');
+                        continue;
+                    }
+                }
             }
+
+            let value = match reference.parse::<usize>() {
+                Ok(num) => self.get(num),
+                Err(_) => self.name(&reference)
+            };
+            out.push_str(value.unwrap_or(""));
         }
 
-        return None;
+        return out;
     }
 
-    fn debug(&self) -> String {
-        let mut s = String::new();
-
-        for alt in &self.alts {
-            s = s + &alt.debug();
-            s.push('|');
+    /**
+     * Returns the groups as a JSON object, mapping each group's name (or
+     * number, for unnamed groups) to the string it matched, or null if it
+     * didn't participate. The keys are in order of group number, so
+     * "(a)(?<x>b)?" matching "a" gives {"0":"a","1":"a","x":null}. A name
+     * can be shared by groups of a regex built with Regex::concat or
+     * Regex::or; it's only used for the first, and the others are keyed by
+     * number so that keys are unique.
+     */
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{");
+        let mut seen = BTreeSet::new();
+        for (num, name) in self.names.iter().enumerate() {
+            if num > 0 {
+                out.push(',');
+            }
+            match *name {
+                Some(ref name) if seen.insert(name) => push_json_string(&mut out, name),
+                _ => push_json_string(&mut out, &num.to_string())
+            }
+            out.push(':');
+            match self.get(num) {
+                Some(s) => push_json_string(&mut out, s),
+                None => out.push_str("null")
+            }
         }
-        s.pop();
-
-        return s;
+        out.push('}');
+        out
     }
 }
 
-impl Node for CharNode {
-    fn match_chars(&self, itr : &mut Chars, _ : &mut MatchResult) -> Option<String> {
-        match itr.next() {
-            Some(c) if c == self.c => { Some(c.to_string()) }
-            _ => { None }
+/// Appends s to out as a JSON string, quoted and escaped.
+fn push_json_string(out : &mut String, s : &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c)
         }
     }
+    out.push('"');
+}
 
-    fn debug(&self) -> String {
-        let mut s = String::new();
-        s.push_str("Char{");
-        s.push(self.c);
-        s.push_str("}");
-        return s;
-    }
+/**
+ * A match of a regex in a str, as found by Regex::find: where it is, and
+ * the str it's in.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Match<'a> {
+    /// The str that was searched.
+    text : &'a str,
+    /// The position (in bytes) the match starts at.
+    start : usize,
+    /// The position (in bytes) just after the match.
+    end : usize
 }
 
-impl Node for CharClassNode {
-    fn match_chars(&self, itr : &mut Chars, _ : &mut MatchResult) -> Option<String> {
-        if let Some(c) = itr.next() {
-            let in_elems = self.elems.contains(&c);
-            if (self.negated && !in_elems) || (!self.negated && in_elems) {
-                Some(c.to_string())
-            } else {
-                None
-            }
-        } else {
-            None
-        }
+impl<'a> Match<'a> {
+    /**
+     * Returns the text matched.
+     */
+    pub fn as_str(&self) -> &'a str {
+        &self.text[self.start..self.end]
     }
 
-    fn debug(&self) -> String {
-        let mut s = String::new();
-        s.push_str("[");
-
-        if self.negated {
-            s.push_str("^");
-        }
+    /**
+     * Returns the position (in bytes) the match starts at.
+     */
+    pub fn start(&self) -> usize {
+        self.start
+    }
 
-        for c in &self.elems {
-            s.push(*c);
-        }
+    /**
+     * Returns the position (in bytes) just after the match.
+     */
+    pub fn end(&self) -> usize {
+        self.end
+    }
 
-        s.push_str("]");
-        s
+    /**
+     * Returns the range (in bytes) of the str the match covers.
+     */
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
     }
 }
 
-impl Node for GrpNode {
-    fn match_chars(&self, itr : &mut Chars, mr : &mut MatchResult) -> Option<String> {
-        let res = self.alt.match_chars(itr, mr);
+/**
+ * An iterator over the non-overlapping matches of a regex in a str, yielding
+ * the start and end position (in bytes) of each. Created by
+ * Regex::find_iter.
+ */
+pub struct Matches<'r, 's> {
+    regex : &'r Regex,
+    s : &'s str,
+    /// Where to search from next, or None once the search is over.
+    pos : Option<usize>,
+    /// Where the last match ended, which is where \\G matches.
+    last_end : usize
+}
 
-        match res {
-            Some(ref s) => {
-                mr.insert(self.num, s.clone());
-            }
-            None => {}
-        };
+impl<'r, 's> Iterator for Matches<'r, 's> {
+    type Item = (usize, usize);
 
-        return res;
+    fn next(&mut self) -> Option<(usize, usize)> {
+        self.regex.next_match(self.s, &mut self.pos, &mut self.last_end)
+            .map(|(_, start, end)| (start, end))
     }
+}
 
-    fn debug(&self) -> String {
-        let mut s = String::new();
-
-        // Dont print parens around the entire regex, they are implicit.
-        if self.num == 0 {
-            s = self.alt.debug();
-        } else {
-            s.push_str("(");
-            s = s + &self.alt.debug();
-            s.push_str(")");
-        }
-        return s;
-    }
+/**
+ * An iterator over the non-overlapping matches of a regex in a str, yielding
+ * the groups matched by each. Created by Regex::captures_iter.
+ */
+pub struct CaptureMatches<'r, 's> {
+    regex : &'r Regex,
+    s : &'s str,
+    /// Where to search from next, or None once the search is over.
+    pos : Option<usize>,
+    /// Where the last match ended, which is where \\G matches.
+    last_end : usize
 }
 
-impl Node for RptNode {
-    fn match_chars(&self, itr : &mut Chars, mr : &mut MatchResult) -> Option<String> {
-        let mut clone = itr.clone();
-        let mut out = String::new();
+impl<'r, 's> Iterator for CaptureMatches<'r, 's> {
+    type Item = Captures;
 
-        let mut res = self.node.match_chars(itr, mr);
-        while res.is_some() {
-            // Store file position for backtracking.
-            clone.clone_from(itr);
+    fn next(&mut self) -> Option<Captures> {
+        self.regex.next_match(self.s, &mut self.pos, &mut self.last_end)
+            .map(|(m, _, _)| m.captures(&self.regex.names))
+    }
+}
 
-            // Append the previous match to our total match.
-            out = out + &res.expect("");
+/**
+ * A scanner over the non-overlapping matches of a regex in a str, like
+ * CaptureMatches but reusing one MatchResult for every match instead of
+ * allocating a new one each time. Created by Regex::captures_scan.
+ *
+ * Each call to next clears the buffer and refills it with the groups of the
+ * next match, so the borrow it returns is only valid until the next call.
+ * Copy out whatever needs to outlive that.
+ */
+pub struct CaptureScanner<'r, 's> {
+    regex : &'r Regex,
+    s : &'s str,
+    /// Where to search from next, or None once the search is over.
+    pos : Option<usize>,
+    /// Where the last match ended, which is where \\G matches.
+    last_end : usize,
+    /// The groups of the last match, reused between matches.
+    groups : MatchResult
+}
 
-            // Try to match again.
-            res = self.node.match_chars(itr, mr);
-        }
+impl<'r, 's> CaptureScanner<'r, 's> {
+    /**
+     * Finds the next match and returns the groups it matched, or None once
+     * there are no more matches. The returned groups are overwritten by the
+     * next call.
+     */
+    pub fn next(&mut self) -> Option<&MatchResult> {
+        let (m, _, _) = self.regex.next_match(self.s, &mut self.pos, &mut self.last_end)?;
+        m.fill_match_result(&mut self.groups);
+        Some(&self.groups)
+    }
+}
 
-        // Backtrack to the point after the last successful match.
-        itr.clone_from(&clone);
+/**
+ * Matches a regex against input fed in in chunks, as match_str would match
+ * the chunks joined together. Created by Regex::incremental.
+ *
+ * The chunks are buffered until finish is called, since a match can depend
+ * on any part of the input.
+ */
+pub struct IncrementalMatcher<'r> {
+    regex : &'r Regex,
+    /// The chunks fed in so far.
+    buffer : String
+}
 
-        // Zero or more, so we always match. If zero matches were made,
-        // this returns Some(""), which is what we want.
-        Some(out)
+impl<'r> IncrementalMatcher<'r> {
+    /**
+     * Adds a chunk to the end of the input.
+     *
+     * * chunk - the next part of the input
+     */
+    pub fn feed(&mut self, chunk : &str) {
+        self.buffer.push_str(chunk);
     }
 
-    fn debug(&self) -> String {
-        return self.node.debug() + "*";
+    /**
+     * Matches the input fed in so far against the regex, returning the groups
+     * matched, as match_str does.
+     */
+    pub fn finish(self) -> Option<MatchResult> {
+        self.regex.match_str(&self.buffer)
     }
 }
 
-impl Node for SeqNode {
-    fn match_chars(&self, itr : &mut Chars, mr : &mut MatchResult) -> Option<String> {
-        let mut out = String::new();
+/**
+ * A view of the structure of a parsed regex, for analyzing patterns. This is
+ * a copy of the tree used for matching, so changing it doesn't affect the
+ * regex it came from.
+ *
+ * Sequences and alternations with a single element are represented by that
+ * element.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Ast {
+    /// A char literal.
+    Char(char),
+    /// Any char but '\n'.
+    Any,
+    /// An assertion about the current position, matching no chars.
+    Anchor(Anchor),
+    /// A char class, matching chars in (or, if negated, not in) a list of
+    /// inclusive ranges. Classes from a regex have their ranges sorted and
+    /// merged; others are printed and built as if they were.
+    Class { ranges : Vec<(char, char)>, negated : bool },
+    /// A sequence of nodes, matched one after the other.
+    Concat(Vec<Ast>),
+    /// A list of alternatives, tried in order.
+    Alternation(Vec<Ast>),
+    /// A capturing group.
+    Group { num : usize, name : Option<String>, ast : Box<Ast> },
+    /// A node repeated at least min and at most max (None for unbounded)
+    /// times. A greedy repeat prefers more repetitions, a lazy one fewer. A
+    /// possessive repeat is greedy, and never gives back a repetition once
+    /// matched.
+    Repeat { min : usize, max : Option<usize>, greedy : bool, possessive : bool, ast : Box<Ast> },
+    /// A conditional, matching yes if group num participated and no if not.
+    Conditional { num : usize, yes : Box<Ast>, no : Box<Ast> },
+    /// A backreference, matching the text last matched by group num (in any
+    /// case, if case_insensitive is set, as under the i flag). The name is
+    /// set if the reference was made by name.
+    Backref { num : usize, name : Option<String>, case_insensitive : bool },
+    /// A lookbehind, matching nothing, but only where the text just before
+    /// matches ast (or, if negated, doesn't).
+    Lookbehind { negated : bool, ast : Box<Ast> }
+}
 
-        for n in &self.nodes {
-            let res = n.match_chars(itr, mr);
-            if res.is_some() {
-                out = out + &res.expect("");
-            } else {
-                return None;
+/**
+ * A position an Ast::Anchor asserts the match is at.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Anchor {
+    /// The start of the input, '^'.
+    Start,
+    /// The end of the input, '$'.
+    End,
+    /// Where the search started, or where the previous match ended when
+    /// iterating over matches, "\\G".
+    SearchStart,
+    /// The start of the input, "\\A".
+    TextStart,
+    /// The end of the input, "\\z".
+    TextEnd,
+    /// The end of the input, or just before a '\n' ending it, "\\Z". This is
+    /// also what '$' matches when built with
+    /// RegexBuilder::dollar_before_newline.
+    EndBeforeNewline,
+    /// A boundary between a word char and a non-word char (or the start or
+    /// end of the input), "\\b".
+    WordBoundary,
+    /// Anywhere but a word boundary, "\\B".
+    NotWordBoundary
+}
+
+impl Ast {
+    /**
+     * Returns a copy of this tree with every quantifier made lazy. Possessive
+     * quantifiers are left as they are, since they never backtrack.
+     */
+    pub fn to_lazy(&self) -> Ast {
+        match *self {
+            Ast::Char(_) | Ast::Any | Ast::Anchor(_) | Ast::Class { .. } | Ast::Backref { .. } => {
+                self.clone()
+            }
+            Ast::Concat(ref asts) => Ast::Concat(asts.iter().map(|a| a.to_lazy()).collect()),
+            Ast::Alternation(ref asts) => {
+                Ast::Alternation(asts.iter().map(|a| a.to_lazy()).collect())
+            }
+            Ast::Group { num, ref name, ref ast } => Ast::Group {
+                num : num,
+                name : name.clone(),
+                ast : Box::new(ast.to_lazy())
+            },
+            Ast::Repeat { min, max, greedy, possessive, ref ast } => Ast::Repeat {
+                min : min,
+                max : max,
+                greedy : greedy && possessive,
+                possessive : possessive,
+                ast : Box::new(ast.to_lazy())
+            },
+            Ast::Conditional { num, ref yes, ref no } => Ast::Conditional {
+                num : num,
+                yes : Box::new(yes.to_lazy()),
+                no : Box::new(no.to_lazy())
+            },
+            Ast::Lookbehind { negated, ref ast } => Ast::Lookbehind {
+                negated : negated,
+                ast : Box::new(ast.to_lazy())
             }
         }
-        return Some(out);
     }
 
-    fn debug(&self) -> String {
-        let mut s = String::new();
-
-        for n in &self.nodes {
-            s = s + &n.debug();
+    /**
+     * Returns a copy of the tree with offset added to the number of every
+     * group, and of every group referred to.
+     */
+    fn renumber(&self, offset : usize) -> Ast {
+        match *self {
+            Ast::Char(_) | Ast::Any | Ast::Anchor(_) | Ast::Class { .. } => self.clone(),
+            Ast::Concat(ref asts) => {
+                Ast::Concat(asts.iter().map(|a| a.renumber(offset)).collect())
+            }
+            Ast::Alternation(ref asts) => {
+                Ast::Alternation(asts.iter().map(|a| a.renumber(offset)).collect())
+            }
+            Ast::Group { num, ref name, ref ast } => Ast::Group {
+                num : num + offset,
+                name : name.clone(),
+                ast : Box::new(ast.renumber(offset))
+            },
+            Ast::Repeat { min, max, greedy, possessive, ref ast } => Ast::Repeat {
+                min : min,
+                max : max,
+                greedy : greedy,
+                possessive : possessive,
+                ast : Box::new(ast.renumber(offset))
+            },
+            Ast::Conditional { num, ref yes, ref no } => Ast::Conditional {
+                num : num + offset,
+                yes : Box::new(yes.renumber(offset)),
+                no : Box::new(no.renumber(offset))
+            },
+            Ast::Backref { num, ref name, case_insensitive } => Ast::Backref {
+                num : num + offset,
+                name : name.clone(),
+                case_insensitive : case_insensitive
+            },
+            Ast::Lookbehind { negated, ref ast } => Ast::Lookbehind {
+                negated : negated,
+                ast : Box::new(ast.renumber(offset))
+            }
         }
-
-        return s;
     }
-}
-
-impl CharClassNode {
-    fn parse(mut itr : &mut Chars) -> Self {
-        let mut elems = BTreeSet::new();
-        let mut negated = false;
 
-        let handle_escape = |itr : &mut Chars, elems : &mut BTreeSet<char>| {
-            if let Some(next) = itr.next() {
-                if let Some(mapping) = parse_escape_char(next) {
-                    elems.insert(mapping);
-                } else {
-                    panic!("Syntax error. Invalid escape.");
+    /**
+     * Adds the number of each group referred to by a backreference or
+     * conditional in this tree to nums.
+     */
+    fn referenced_groups(&self, nums : &mut BTreeSet<usize>) {
+        match *self {
+            Ast::Char(_) | Ast::Any | Ast::Anchor(_) | Ast::Class { .. } => {}
+            Ast::Concat(ref asts) | Ast::Alternation(ref asts) => {
+                for ast in asts {
+                    ast.referenced_groups(nums);
                 }
-            } else {
-                panic!("Syntax error. Expected char following escape.");
             }
-        };
-
-        match itr.next() {
-            Some(c) if c == '^' => { negated = true; }
-            Some(c) if c == ']' => { panic!("Syntax error. Empty char class."); }
-            Some(c) if c == '\\' => { handle_escape(&mut itr, &mut elems); }
-            Some(c) => { elems.insert(c); }
-            None => { panic!("Syntax error. Unterminated char class."); }
-        }
-
-        let mut done = false;
-        while let Some(c) = itr.next() {
-            if c == ']' {
-                done = true;
-                break;
-            } else if c == '\\' {
-                handle_escape(itr, &mut elems);
-            } else {
-                elems.insert(c);
+            Ast::Group { ref ast, .. } | Ast::Repeat { ref ast, .. }
+                | Ast::Lookbehind { ref ast, .. } => {
+                ast.referenced_groups(nums);
+            }
+            Ast::Conditional { num, ref yes, ref no } => {
+                nums.insert(num);
+                yes.referenced_groups(nums);
+                no.referenced_groups(nums);
+            }
+            Ast::Backref { num, .. } => {
+                nums.insert(num);
             }
         }
+    }
 
-        if !done {
-            panic!("Syntax error. Unterminated char class.");
-        } else if elems.is_empty() {
-            panic!("Syntax error. Empty char class.");
-        }
-
-        CharClassNode {
-            elems : elems,
-            negated : negated
+    /**
+     * Returns a copy of this tree with every group not in kept replaced by
+     * its body, and the groups that are kept (and references to them)
+     * renumbered as kept maps them. A reference to a group that's not
+     * kept can never see it participate: a conditional on it becomes its
+     * "no" branch, and a backreference to it something that never matches.
+     *
+     * * kept - the new number of each group to keep, by its old number
+     */
+    fn strip_groups(&self, kept : &BTreeMap<usize, usize>) -> Ast {
+        match *self {
+            Ast::Char(_) | Ast::Any | Ast::Anchor(_) | Ast::Class { .. } => self.clone(),
+            Ast::Concat(ref asts) => {
+                Ast::Concat(asts.iter().map(|a| a.strip_groups(kept)).collect())
+            }
+            Ast::Alternation(ref asts) => {
+                Ast::Alternation(asts.iter().map(|a| a.strip_groups(kept)).collect())
+            }
+            Ast::Group { num, ref name, ref ast } => match kept.get(&num) {
+                Some(&new_num) => Ast::Group {
+                    num : new_num,
+                    name : name.clone(),
+                    ast : Box::new(ast.strip_groups(kept))
+                },
+                None => ast.strip_groups(kept)
+            },
+            Ast::Repeat { min, max, greedy, possessive, ref ast } => Ast::Repeat {
+                min : min,
+                max : max,
+                greedy : greedy,
+                possessive : possessive,
+                ast : Box::new(ast.strip_groups(kept))
+            },
+            Ast::Conditional { num, ref yes, ref no } => match kept.get(&num) {
+                Some(&new_num) => Ast::Conditional {
+                    num : new_num,
+                    yes : Box::new(yes.strip_groups(kept)),
+                    no : Box::new(no.strip_groups(kept))
+                },
+                None => no.strip_groups(kept)
+            },
+            Ast::Backref { num, ref name, case_insensitive } => match kept.get(&num) {
+                Some(&new_num) => Ast::Backref {
+                    num : new_num,
+                    name : name.clone(),
+                    case_insensitive : case_insensitive
+                },
+                // An empty negative lookbehind, which never matches.
+                None => Ast::Lookbehind {
+                    negated : true,
+                    ast : Box::new(Ast::Concat(Vec::new()))
+                }
+            },
+            Ast::Lookbehind { negated, ref ast } => Ast::Lookbehind {
+                negated : negated,
+                ast : Box::new(ast.strip_groups(kept))
+            }
         }
     }
 
-    fn from_vec(elems : Vec<char>, negated : bool) -> CharClassNode {
-        CharClassNode {
-            elems : elems.iter().cloned().collect(),
-            negated : negated
+    /**
+     * Returns a tree matching the reverse of each string this one matches, or
+     * an error naming the first thing it uses with no reverse:
+     * backreferences, conditionals, lookbehinds and the \\G and \\Z anchors
+     * (including '$' built to match before a final newline).
+     */
+    fn reverse(&self) -> Result<Ast, ReverseError> {
+        let unsupported = |construct| Err(ReverseError { construct : construct });
+        match *self {
+            Ast::Char(_) | Ast::Any | Ast::Class { .. } => Ok(self.clone()),
+            Ast::Anchor(anchor) => {
+                let reversed = match anchor {
+                    Anchor::Start => Anchor::End,
+                    Anchor::End => Anchor::Start,
+                    Anchor::TextStart => Anchor::TextEnd,
+                    Anchor::TextEnd => Anchor::TextStart,
+                    Anchor::WordBoundary | Anchor::NotWordBoundary => anchor,
+                    Anchor::SearchStart => { return unsupported("\\G"); }
+                    Anchor::EndBeforeNewline => { return unsupported("\\Z"); }
+                };
+                Ok(Ast::Anchor(reversed))
+            }
+            Ast::Concat(ref asts) => {
+                asts.iter().rev().map(|a| a.reverse()).collect::<Result<_, _>>().map(Ast::Concat)
+            }
+            Ast::Alternation(ref asts) => {
+                asts.iter().map(|a| a.reverse()).collect::<Result<_, _>>().map(Ast::Alternation)
+            }
+            Ast::Group { num, ref name, ref ast } => Ok(Ast::Group {
+                num : num,
+                name : name.clone(),
+                ast : Box::new(ast.reverse()?)
+            }),
+            Ast::Repeat { min, max, greedy, possessive, ref ast } => Ok(Ast::Repeat {
+                min : min,
+                max : max,
+                greedy : greedy,
+                possessive : possessive,
+                ast : Box::new(ast.reverse()?)
+            }),
+            Ast::Conditional { .. } => unsupported("conditionals"),
+            Ast::Backref { .. } => unsupported("backreferences"),
+            Ast::Lookbehind { .. } => unsupported("lookbehinds")
         }
     }
-}
 
-impl GrpNode {
     /**
-     * Helper function for Regex constructors. Does the actual parsing. The
-     * type hierarchy goes:
-     * group > alternation > sequence > (group or repeat or char).
-     *
-     * Returns the root node, a group, of the string passed.
-     *
-     * * itr - pointer to current position in regex string
-     * * num - current group number (used to keep track of group numbers)
+     * Returns the number of chars every match of this tree consumes, or None
+     * if matches can have different lengths.
      */
-    fn parse(itr : &mut Chars, num : &mut usize, root : bool) -> Self {
-        let mut grp = GrpNode {
-            num : *num,
-            alt : AltNode {
-                alts : vec!(SeqNode {
-                    nodes : Vec::new()
-                })
+    fn fixed_len(&self) -> Option<usize> {
+        match *self {
+            Ast::Char(_) | Ast::Any | Ast::Class { .. } => Some(1),
+            Ast::Concat(ref asts) => {
+                asts.iter().map(|a| a.fixed_len()).sum()
             }
-        };
-
-        while let Some(c) = itr.next() {
-            match c {
-                '(' => {
-                    // Parse this nested group.
-                    *num += 1;
-                    grp.get_seq().push_grp(GrpNode::parse(itr, num, false));
+            Ast::Alternation(ref asts) => {
+                let first = asts.first().map_or(Some(0), |a| a.fixed_len())?;
+                if asts.iter().all(|a| a.fixed_len() == Some(first)) {
+                    Some(first)
+                } else {
+                    None
                 }
-                '|' => {
-                    // Create a new alternative sequence.
-                    grp.add_alt();
+            }
+            Ast::Group { ref ast, .. } => ast.fixed_len(),
+            Ast::Repeat { min, max : Some(max), ref ast, .. } if min == max => {
+                ast.fixed_len().map(|len| len * min)
+            }
+            Ast::Repeat { .. } | Ast::Backref { .. } => None,
+            Ast::Conditional { ref yes, ref no, .. } => {
+                let len = yes.fixed_len()?;
+                if no.fixed_len() == Some(len) { Some(len) } else { None }
+            }
+            Ast::Anchor(_) | Ast::Lookbehind { .. } => Some(0)
+        }
+    }
+
+    /**
+     * Returns the chars a match of this tree can start with, and whether it
+     * can match without consuming any chars, or None if it could start with
+     * nearly any char (or more than FIRST_CHARS_LIMIT different chars).
+     */
+    fn first_chars(&self) -> Option<(BTreeSet<char>, bool)> {
+        match *self {
+            Ast::Char(c) => Some((vec!(c).into_iter().collect(), false)),
+            Ast::Any | Ast::Class { negated : true, .. } | Ast::Backref { .. } => None,
+            Ast::Class { ref ranges, .. } => {
+                let count = ranges.iter().map(|&(lo, hi)| hi as usize - lo as usize + 1).sum::<usize>();
+                if count > FIRST_CHARS_LIMIT {
+                    return None;
                 }
-                ')' => {
-                    // lparens should always be removed by the
-                    // subgroup parse. So this must be an error.
-                    if root {
-                        panic!("Syntax error. Extra ')'.");
-                    } else {
-                        break;
+                Some((ranges.iter().flat_map(|&(lo, hi)| lo..=hi).collect(), false))
+            }
+            Ast::Anchor(_) | Ast::Lookbehind { .. } => Some((BTreeSet::new(), true)),
+            Ast::Concat(ref asts) => {
+                // Later elements can only start the match if the ones before
+                // them can be empty.
+                let mut chars = BTreeSet::new();
+                for ast in asts {
+                    let (first, empty) = ast.first_chars()?;
+                    chars.extend(first);
+                    if !empty {
+                        return Some((chars, false));
                     }
                 }
-                '*' => {
-                    // Pop the previous node and nest it under a
-                    // repeat node.
-                    let n = grp.get_seq()
-                        .pop()
-                        .expect("Syntax error. * requires a preceeding node.");
-                    let rpt = Rc::new(RptNode {
-                        node : n
-                    });
-                    grp.get_seq().push(rpt);
+                Some((chars, true))
+            }
+            Ast::Alternation(ref asts) => {
+                let mut chars = BTreeSet::new();
+                let mut can_be_empty = false;
+                for ast in asts {
+                    let (first, empty) = ast.first_chars()?;
+                    chars.extend(first);
+                    can_be_empty |= empty;
                 }
-                '+' => {
-                    // Clone the previous node and add a RptNode after it.
-                    let n = grp.get_seq()
-                        .clone_back()
-                        .expect("Syntax error. + requires a preceeding node.");
-                    let rpt = Rc::new(RptNode {
-                        node : n
-                    });
-                    grp.get_seq().push(rpt);
+                Some((chars, can_be_empty))
+            }
+            Ast::Group { ref ast, .. } => ast.first_chars(),
+            Ast::Repeat { min, ref ast, .. } => {
+                let (chars, empty) = ast.first_chars()?;
+                Some((chars, empty || min == 0))
+            }
+            Ast::Conditional { ref yes, ref no, .. } => {
+                let (mut chars, yes_empty) = yes.first_chars()?;
+                let (first, no_empty) = no.first_chars()?;
+                chars.extend(first);
+                Some((chars, yes_empty || no_empty))
+            }
+        }
+    }
+
+    /**
+     * Returns how deeply groups, repeats, lookbehinds and conditionals are
+     * nested in this tree.
+     */
+    fn depth(&self) -> usize {
+        let max = |asts : &[Ast]| asts.iter().map(|a| a.depth()).max().unwrap_or(0);
+        match *self {
+            Ast::Char(_) | Ast::Any | Ast::Anchor(_) | Ast::Class { .. } | Ast::Backref { .. } => 0,
+            Ast::Concat(ref asts) | Ast::Alternation(ref asts) => max(asts),
+            Ast::Group { ref ast, .. } | Ast::Lookbehind { ref ast, .. } |
+            Ast::Repeat { ref ast, .. } => ast.depth() + 1,
+            Ast::Conditional { ref yes, ref no, .. } => cmp::max(yes.depth(), no.depth()) + 1
+        }
+    }
+
+    /**
+     * Returns every string this tree can match, ignoring what anchors,
+     * lookbehinds and conditions require, or None if there are infinitely
+     * many (or too many to list, for '.' and negated classes) or they depend
+     * on a backreference. The strings may have duplicates.
+     */
+    fn strings(&self) -> Option<Vec<String>> {
+        match *self {
+            Ast::Char(c) => Some(vec!(c.to_string())),
+            Ast::Any | Ast::Class { negated : true, .. } => None,
+            Ast::Class { ref ranges, .. } => {
+                Some(ranges.iter().flat_map(|&(lo, hi)| lo..=hi).map(|c| c.to_string()).collect())
+            }
+            Ast::Anchor(_) | Ast::Lookbehind { .. } => Some(vec!(String::new())),
+            Ast::Concat(ref asts) => {
+                let mut out = vec!(String::new());
+                for ast in asts {
+                    out = product(&out, &ast.strings()?);
                 }
-                '[' => {
-                    let n = Rc::new(CharClassNode::parse(itr));
-                    grp.get_seq().push(n);
+                Some(out)
+            }
+            Ast::Alternation(ref asts) => {
+                let mut out = Vec::new();
+                for ast in asts {
+                    out.extend(ast.strings()?);
                 }
-                '\\' => {
-                    if let Some(c) = itr.next() {
-                        if let Some(node) = parse_escape(c) {
-                            grp.get_seq().push(node);
-                        } else {
-                            panic!("Syntax error. Invalid escape.");
-                        }
-                    } else {
-                        panic!("Syntax error. Expected char following escape.");
-                    }
+                Some(out)
+            }
+            Ast::Group { ref ast, .. } => ast.strings(),
+            Ast::Repeat { min, max : Some(max), ref ast, .. } => {
+                let once = ast.strings()?;
+                let mut reps = vec!(String::new());
+                for _ in 0..min {
+                    reps = product(&reps, &once);
                 }
-                c => {
-                    // Char literal. Just push it on the
-                    // current senquence.
-                    grp.get_seq().push_char(c);
+                let mut out = reps.clone();
+                for _ in min..max {
+                    reps = product(&reps, &once);
+                    out.extend(reps.iter().cloned());
                 }
+                Some(out)
+            }
+            Ast::Repeat { max : None, .. } | Ast::Backref { .. } => None,
+            Ast::Conditional { ref yes, ref no, .. } => {
+                let mut out = yes.strings()?;
+                out.extend(no.strings()?);
+                Some(out)
             }
         }
-        grp
     }
 
-    fn add_alt(&mut self) {
-        self.alt.alts.push(SeqNode {
-            nodes : Vec::new()
-        });
+    /**
+     * Returns whether every match of this tree must start at the start of the
+     * input.
+     */
+    fn is_anchored_start(&self) -> bool {
+        match *self {
+            Ast::Anchor(Anchor::Start) | Ast::Anchor(Anchor::TextStart) => true,
+            Ast::Concat(ref asts) => asts.first().is_some_and(|a| a.is_anchored_start()),
+            Ast::Alternation(ref asts) => asts.iter().all(|a| a.is_anchored_start()),
+            Ast::Group { ref ast, .. } => ast.is_anchored_start(),
+            _ => false
+        }
     }
 
-    fn get_seq(&mut self) -> &mut SeqNode {
-        let len = self.alt.alts.len();
-        return self.alt.alts.get_mut(len - 1).expect("");
+    /**
+     * Returns whether every match of this tree must end at the end of the
+     * input.
+     */
+    fn is_anchored_end(&self) -> bool {
+        match *self {
+            Ast::Anchor(Anchor::End) | Ast::Anchor(Anchor::TextEnd) => true,
+            Ast::Concat(ref asts) => asts.last().is_some_and(|a| a.is_anchored_end()),
+            Ast::Alternation(ref asts) => asts.iter().all(|a| a.is_anchored_end()),
+            Ast::Group { ref ast, .. } => ast.is_anchored_end(),
+            _ => false
+        }
     }
-}
 
-/**
- * Parses the char following an escape, but restricts matches to those which
- * map directly to a another char (rather than, e.g., full nodes like a char
- * class).
- */
-fn parse_escape_char(c : char) -> Option<char> {
-    match c {
-        '\\'|'('|')'|'['|']'|'*'|'+'|'^' => Some(c),
-        't' => Some('\t'),
-        _   => None
+    /**
+     * Writes this tree to s, one node per line, with children indented under
+     * their parent.
+     */
+    fn write_tree(&self, s : &mut String, depth : usize) {
+        for _ in 0..depth {
+            s.push_str("  ");
+        }
+
+        let children : Vec<&Ast> = match *self {
+            Ast::Char(c) => {
+                s.push_str(&format!("Char {:?}\n", c));
+                Vec::new()
+            }
+            Ast::Any => {
+                s.push_str("Any\n");
+                Vec::new()
+            }
+            Ast::Anchor(_) => {
+                s.push_str(&format!("Anchor {}\n", self));
+                Vec::new()
+            }
+            Ast::Class { .. } => {
+                s.push_str(&format!("Class {}\n", self));
+                Vec::new()
+            }
+            Ast::Concat(ref asts) => {
+                s.push_str("Seq\n");
+                asts.iter().collect()
+            }
+            Ast::Alternation(ref asts) => {
+                s.push_str("Alt\n");
+                asts.iter().collect()
+            }
+            Ast::Group { num, ref name, ref ast } => {
+                match *name {
+                    Some(ref name) => s.push_str(&format!("Grp #{} <{}>\n", num, name)),
+                    None => s.push_str(&format!("Grp #{}\n", num))
+                }
+                vec!(&**ast)
+            }
+            Ast::Repeat { min, max, greedy, possessive, ref ast } => {
+                let suffix = if possessive { "+" } else { lazy_suffix(greedy) };
+                s.push_str(&format!("Rpt {}{}\n", quantifier(min, max), suffix));
+                vec!(&**ast)
+            }
+            Ast::Conditional { num, ref yes, ref no } => {
+                s.push_str(&format!("Cond #{}\n", num));
+                vec!(&**yes, &**no)
+            }
+            Ast::Backref { num, .. } => {
+                s.push_str(&format!("Backref #{}\n", num));
+                Vec::new()
+            }
+            Ast::Lookbehind { negated, ref ast } => {
+                s.push_str(if negated { "Lookbehind !\n" } else { "Lookbehind\n" });
+                vec!(&**ast)
+            }
+        };
+
+        for child in children {
+            child.write_tree(s, depth + 1);
+        }
+    }
+
+    /**
+     * Returns the size of this tree, as counted against a RegexBuilder's size
+     * limit: the number of nodes, with the body of a bounded repeat counted
+     * once per repetition allowed (or required, if unbounded).
+     */
+    fn size(&self) -> usize {
+        let sum = |asts : &[Ast]| asts.iter().fold(0, |n : usize, a| n.saturating_add(a.size()));
+        let body = match *self {
+            Ast::Char(_) | Ast::Any | Ast::Anchor(_) | Ast::Class { .. } | Ast::Backref { .. } => 0,
+            Ast::Concat(ref asts) | Ast::Alternation(ref asts) => sum(asts),
+            Ast::Group { ref ast, .. } | Ast::Lookbehind { ref ast, .. } => ast.size(),
+            Ast::Repeat { min, max, ref ast, .. } => {
+                ast.size().saturating_mul(cmp::max(max.unwrap_or(min), 1))
+            }
+            Ast::Conditional { ref yes, ref no, .. } => yes.size().saturating_add(no.size())
+        };
+        body.saturating_add(1)
     }
 }
 
+/// The number of regexes Regex::cached keeps.
+pub const CACHE_CAPACITY : usize = 64;
+
+/// The most chars Regex::first_bytes lists before giving up.
+const FIRST_CHARS_LIMIT : usize = 256;
+
 /**
- * Parses the char following an escape ('/'), allowing any result. (This is 
- * used outside of character classes.)
+ * Prints the tree in regex syntax. Parsing the result gives back the same
+ * tree.
  */
-fn parse_escape(c : char) -> Option<Rc<Node>> {
-    match c {
-        's' => Some(Rc::new(CharClassNode::from_vec(vec!(' ', '\t'), false))),
-        'S' => Some(Rc::new(CharClassNode::from_vec(vec!(' ', '\t'), true))),
-        c   => {
-            if let Some(c) = parse_escape_char(c) {
-                Some(Rc::new(CharNode { c : c}))
-            } else { 
-                None 
+impl fmt::Display for Ast {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Ast::Char(c) => {
+                write_escaped(f, c, "\\()[]*+?.|{}^$")
+            }
+            Ast::Any => {
+                write!(f, ".")
+            }
+            Ast::Anchor(Anchor::Start) => {
+                write!(f, "^")
+            }
+            Ast::Anchor(Anchor::End) => {
+                write!(f, "$")
+            }
+            Ast::Anchor(Anchor::EndBeforeNewline) => {
+                write!(f, "\\Z")
+            }
+            Ast::Anchor(Anchor::SearchStart) => {
+                write!(f, "\\G")
+            }
+            Ast::Anchor(Anchor::TextStart) => {
+                write!(f, "\\A")
+            }
+            Ast::Anchor(Anchor::TextEnd) => {
+                write!(f, "\\z")
+            }
+            Ast::Anchor(Anchor::WordBoundary) => {
+                write!(f, "\\b")
+            }
+            Ast::Anchor(Anchor::NotWordBoundary) => {
+                write!(f, "\\B")
+            }
+            Ast::Class { ref ranges, negated } => {
+                // Print the ranges as the class matches them, sorted and
+                // merged, so equal classes print the same.
+                let mut set = CharSet {
+                    ranges : ranges.clone()
+                };
+                set.normalize();
+                if negated && set.ranges.is_empty() {
+                    // Any char, which there's no class syntax for.
+                    return write!(f, "(?s:.)");
+                }
+                write!(f, "[")?;
+                if negated {
+                    write!(f, "^")?;
+                }
+                for &(lo, hi) in &set.ranges {
+                    write_escaped(f, lo, "\\[]^-&")?;
+                    if hi != lo {
+                        write!(f, "-")?;
+                        write_escaped(f, hi, "\\[]^-&")?;
+                    }
+                }
+                write!(f, "]")
+            }
+            Ast::Concat(ref asts) => {
+                for (i, ast) in asts.iter().enumerate() {
+                    // A digit after "\\1" would be read as part of the
+                    // group number.
+                    let before_digit = asts.get(i + 1).is_some_and(|next| {
+                        next.to_string().starts_with(|c : char| c.is_ascii_digit())
+                    });
+                    match *ast {
+                        Ast::Alternation(_) => write!(f, "(?:{})", ast)?,
+                        Ast::Backref { name : None, case_insensitive : false, .. }
+                            if before_digit => {
+                            write!(f, "(?:{})", ast)?
+                        }
+                        _ => write!(f, "{}", ast)?
+                    }
+                }
+                Ok(())
+            }
+            Ast::Alternation(ref asts) => {
+                for (i, ast) in asts.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "|")?;
+                    }
+                    write!(f, "{}", ast)?;
+                }
+                Ok(())
+            }
+            Ast::Group { ref name, ref ast, .. } => {
+                match *name {
+                    Some(ref name) => write!(f, "(?<{}>{})", name, ast),
+                    None => write!(f, "({})", ast)
+                }
+            }
+            Ast::Repeat { min, max, greedy, possessive, ref ast } => {
+                let suffix = if possessive { "+" } else { lazy_suffix(greedy) };
+                match **ast {
+                    Ast::Concat(_) | Ast::Alternation(_) | Ast::Repeat { .. } => {
+                        write!(f, "(?:{}){}{}", ast, quantifier(min, max), suffix)
+                    }
+                    _ => write!(f, "{}{}{}", ast, quantifier(min, max), suffix)
+                }
+            }
+            Ast::Conditional { num, ref yes, ref no } => {
+                write!(f, "(?({})", num)?;
+                for (i, branch) in [yes, no].iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "|")?;
+                    }
+                    match ***branch {
+                        Ast::Alternation(_) => write!(f, "(?:{})", branch)?,
+                        _ => write!(f, "{}", branch)?
+                    }
+                }
+                write!(f, ")")
+            }
+            Ast::Backref { num, ref name, case_insensitive } => {
+                if case_insensitive {
+                    write!(f, "(?i:")?;
+                }
+                match *name {
+                    Some(ref name) => write!(f, "\\k<{}>", name)?,
+                    None => write!(f, "\\{}", num)?
+                }
+                if case_insensitive {
+                    write!(f, ")")?;
+                }
+                Ok(())
+            }
+            Ast::Lookbehind { negated, ref ast } => {
+                write!(f, "(?<{}{})", if negated { '!' } else { '=' }, ast)
             }
         }
     }
 }
 
-impl SeqNode {
-    fn push_char(&mut self, c : char) {
-        self.nodes.push(Rc::new(CharNode { c : c }));
+/// Returns every string made of one of firsts followed by one of seconds.
+fn product(firsts : &[String], seconds : &[String]) -> Vec<String> {
+    let mut out = Vec::new();
+    for first in firsts {
+        for second in seconds {
+            out.push(first.clone() + second);
+        }
     }
+    out
+}
 
-    fn push_grp(&mut self, grp : GrpNode) {
-        self.nodes.push(Rc::new(grp));
+/// Writes c, escaping it if it's one of the special chars.
+fn write_escaped(f : &mut fmt::Formatter, c : char, special : &str) -> fmt::Result {
+    if special.contains(c) {
+        write!(f, "\\{}", c)
+    } else {
+        write!(f, "{}", c)
     }
+}
 
-    fn push(&mut self, node : Rc<Node>) {
-        self.nodes.push(node);
-    }
+/**
+ * An error encountered while parsing a regex. Positions are byte offsets into
+ * the pattern.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// A ')' without a matching '('.
+    UnbalancedParen { position : usize },
+    /// A quantifier with no preceding node to repeat.
+    QuantifierWithoutOperand { position : usize },
+    /// A '\\' followed by a char that can't be escaped.
+    InvalidEscape { position : usize },
+    /// A '\\' at the end of the pattern.
+    UnterminatedEscape { position : usize },
+    /// A \\u{...} escape naming a value that isn't a char.
+    InvalidCodePoint { value : u32, position : usize },
+    /// A char class with no elements.
+    EmptyCharClass { position : usize },
+    /// A range in a char class whose end comes before its start.
+    InvalidRange { position : usize },
+    /// A '[' without a matching ']'.
+    UnterminatedCharClass { position : usize },
+    /// A "(?" not followed by a supported group kind.
+    InvalidGroup { position : usize },
+    /// A malformed conditional, or one on a group that doesn't exist.
+    InvalidConditional { position : usize },
+    /// A group with the same name as an earlier group.
+    DuplicateGroupName { name : String, position : usize },
+    /// A malformed backreference, or one to a group that hasn't been opened.
+    InvalidBackreference { position : usize },
+    /// A backreference to a name no group has.
+    UnknownGroupName { name : String, position : usize },
+    /// A lookbehind whose body can match different numbers of chars.
+    VariableLengthLookbehind { position : usize },
+    /// A "{n,m}" quantifier whose bounds are out of order or too large.
+    InvalidRepeat { position : usize },
+    /// A pattern whose size exceeds the limit set on a RegexBuilder. The
+    /// position is always 0, since it's the whole pattern that's too large.
+    PatternTooLarge { limit : usize, position : usize },
+    /// A pattern read by Regex::from_chars that ended before its terminator.
+    MissingTerminator { position : usize },
+    /// Groups, char classes or quantifiers nested more deeply than the limit
+    /// (MAX_NESTING, or a lower one set on a RegexBuilder).
+    NestingTooDeep { limit : usize, position : usize },
+    /// Syntax for a construct the crate doesn't support, such as lookahead.
+    UnsupportedConstruct { construct : &'static str, position : usize }
+}
 
-    fn pop(&mut self) -> Option<Rc<Node>> {
-        self.nodes.pop()
+impl ParseError {
+    /**
+     * Returns the position in the pattern at which the error was found.
+     */
+    pub fn position(&self) -> usize {
+        match *self {
+            ParseError::UnbalancedParen { position } |
+            ParseError::QuantifierWithoutOperand { position } |
+            ParseError::InvalidEscape { position } |
+            ParseError::UnterminatedEscape { position } |
+            ParseError::InvalidCodePoint { position, .. } |
+            ParseError::EmptyCharClass { position } |
+            ParseError::InvalidRange { position } |
+            ParseError::UnterminatedCharClass { position } |
+            ParseError::InvalidGroup { position } |
+            ParseError::InvalidConditional { position } |
+            ParseError::DuplicateGroupName { position, .. } |
+            ParseError::InvalidBackreference { position } |
+            ParseError::UnknownGroupName { position, .. } |
+            ParseError::VariableLengthLookbehind { position } |
+            ParseError::InvalidRepeat { position } |
+            ParseError::PatternTooLarge { position, .. } |
+            ParseError::MissingTerminator { position } |
+            ParseError::NestingTooDeep { position, .. } |
+            ParseError::UnsupportedConstruct { position, .. } => position
+        }
     }
 
-    fn clone_back(&self) -> Option<Rc<Node>> {
-        let len = self.nodes.len();
-        if let Some(node) = self.nodes.get(len - 1) {
-            Some(node.clone())
-        } else {
-            None
-        }
+    /**
+     * Returns the error message followed by the pattern, with a '^' on the
+     * line below it marking where the error was found.
+     *
+     * * pattern - the pattern that gave the error
+     */
+    pub fn render(&self, pattern : &str) -> String {
+        let column = pattern.char_indices().take_while(|&(i, _)| i < self.position()).count();
+        format!("{}\n{}\n{}^", self, pattern, " ".repeat(column))
     }
 }
 
-#[cfg(test)]
-fn test_match(r : &str, testcase : &str) {
-    let regex = Regex::from_str(r);
-    assert!(regex.match_str(testcase).is_some());
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match *self {
+            ParseError::UnbalancedParen { .. } => {
+                "Unbalanced parenthesis.".to_string()
+            }
+            ParseError::QuantifierWithoutOperand { .. } => {
+                "Quantifier requires a preceeding node.".to_string()
+            }
+            ParseError::InvalidEscape { .. } => {
+                "Invalid escape.".to_string()
+            }
+            ParseError::UnterminatedEscape { .. } => {
+                "Expected char following escape.".to_string()
+            }
+            ParseError::InvalidCodePoint { value, .. } => {
+                format!("Invalid code point {:X}.", value)
+            }
+            ParseError::EmptyCharClass { .. } => {
+                "Empty char class.".to_string()
+            }
+            ParseError::InvalidRange { .. } => {
+                "Invalid char class range.".to_string()
+            }
+            ParseError::UnterminatedCharClass { .. } => {
+                "Unterminated char class.".to_string()
+            }
+            ParseError::InvalidGroup { .. } => {
+                "Unsupported group syntax.".to_string()
+            }
+            ParseError::InvalidConditional { .. } => {
+                "Invalid conditional.".to_string()
+            }
+            ParseError::DuplicateGroupName { ref name, .. } => {
+                format!("Duplicate group name '{}'.", name)
+            }
+            ParseError::InvalidBackreference { .. } => {
+                "Invalid backreference.".to_string()
+            }
+            ParseError::UnknownGroupName { ref name, .. } => {
+                format!("Unknown group name '{}'.", name)
+            }
+            ParseError::VariableLengthLookbehind { .. } => {
+                "Lookbehind must match a fixed number of chars.".to_string()
+            }
+            ParseError::InvalidRepeat { .. } => {
+                "Invalid repeat bounds.".to_string()
+            }
+            ParseError::PatternTooLarge { limit, .. } => {
+                format!("Pattern exceeds the size limit of {}.", limit)
+            }
+            ParseError::MissingTerminator { .. } => {
+                "Pattern is missing its terminator.".to_string()
+            }
+            ParseError::NestingTooDeep { limit, .. } => {
+                format!("Pattern is nested more than {} deep.", limit)
+            }
+            ParseError::UnsupportedConstruct { construct, .. } => {
+                format!("Unsupported construct: {}.", construct)
+            }
+        };
+        write!(f, "Syntax error at position {}. {}", self.position(), msg)
+    }
 }
 
-#[cfg(test)]
-fn test_result(r : &str, testcase : &str, mut mr : MatchResult) {
-    let regex = Regex::from_str(r);
-    mr.insert(0, testcase.to_string());
-    println!("Regex: {:?}", regex);
-
-    let res = regex.match_str(testcase);
-    println!("Expected result: {:?}", &mr);
-    println!("Actual result: {:?}", &res);
+impl std::error::Error for ParseError {}
 
-    assert!(res == Some(mr));
+/**
+ * How a regex matched a whole str, as found by Regex::full_match.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FullMatch {
+    /// The regex doesn't match the start of the str.
+    NoMatch,
+    /// The regex matches the start of the str, but not all of it. consumed is
+    /// the number of chars the match consumed.
+    PrefixOnly { consumed : usize },
+    /// The regex matches the whole str, with these groups.
+    Full(MatchResult)
 }
 
-#[test]
-fn test_escape_escape() {
-    test_match("\\\\", "\\");
+/**
+ * How a regex matched a str that more input may follow, as found by
+ * Regex::partial_match.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PartialResult {
+    /// The regex matches the whole str, with these groups.
+    Full(MatchResult),
+    /// The regex doesn't match the str, but the str is the start of what it
+    /// could match, so more input could complete a match.
+    Partial,
+    /// The regex doesn't match the str, whatever input follows.
+    None
 }
 
-#[test]
-fn test_tab() {
-    test_match("\t", "\t");
-    test_match("\\t", "\t");
+/**
+ * A piece of a str split by Regex::split_captures: either text between
+ * matches, or a match itself.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Piece<'a> {
+    /// The text before, between or after the matches.
+    Text(&'a str),
+    /// A match, with the groups it matched.
+    Delim(MatchResult)
 }
 
-#[test]
-fn test_char_class_escape() {
-    test_match("[\\t]", "\t");
-    test_match("[\\^]", "^");
+/**
+ * An error that stopped a match before it could finish, or from starting.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MatchError {
+    /// The deadline for the match passed.
+    Timeout,
+    /// The quantifiers attempted more repetitions than the limit allowed.
+    RepeatLimitExceeded { limit : usize },
+    /// The regex uses something the matcher asked for can't handle, named
+    /// by construct.
+    Unsupported { construct : &'static str }
 }
 
-#[test]
-fn test_star() {
-    let mut mr = MatchResult::new();
-    mr.insert(1, "aa".to_string());
-    test_result("(a*)bc", "aabc", mr);
+impl fmt::Display for MatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MatchError::Timeout => write!(f, "Match timed out."),
+            MatchError::RepeatLimitExceeded { limit } => {
+                write!(f, "Match exceeded the limit of {} repetitions.", limit)
+            }
+            MatchError::Unsupported { construct } => {
+                write!(f, "Matching without backtracking doesn't support {}.", construct)
+            }
+        }
+    }
 }
 
-#[test]
-fn test_plus() {
-    let mut mr = MatchResult::new();
-    mr.insert(1, "aaa".to_string());
-    test_result("(a+)b", "aaab", mr);
-}
+impl std::error::Error for MatchError {}
 
-#[test]
-fn test_groups() {
-    let mut mr = MatchResult::new();
-    mr.insert(1, "ac".to_string());
-    mr.insert(2, "c".to_string());
-    mr.insert(3, "cdcdd".to_string());
-    mr.insert(4, "d".to_string());
-    test_result("(a(b|c))b((c|d)*)", "acbcdcdd", mr);
+/**
+ * An error from Regex::reverse, naming what the regex uses that has no
+ * reverse.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReverseError {
+    /// What can't be reversed, such as "backreferences" or "\\G".
+    pub construct : &'static str
 }
 
-#[test]
-fn test_alts() {
-    let mut mr = MatchResult::new();
-    mr.insert(3, "cdcdd".to_string());
-    mr.insert(4, "d".to_string());
-    test_result("(a(b|c)*)|((c|d)*)", "cdcdd", mr);
+impl fmt::Display for ReverseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Can't reverse a regex using {}.", self.construct)
+    }
 }
 
-#[test]
-fn test_char_class() {
-    let mut mr = MatchResult::new();
-    mr.insert(1, "a".to_string());
-    mr.insert(2, "z".to_string());
-    test_result("([abc])([xyz])", "az", mr);
+impl std::error::Error for ReverseError {}
+
+/**
+ * A struct for representing and using regular expressions.
+ */
+pub struct Regex {
+    root : GrpNode,
+    /// The name of each group, indexed by group number.
+    names : Vec<Option<String>>,
+    /// Whether to record every match of each group, not just the last.
+    capture_history : bool,
+    /// Whether every match must start at the start of the input.
+    anchored_start : bool,
+    /// Whether every match must end at the end of the input.
+    anchored_end : bool,
+    /// The predicate for word chars, if not the default class.
+    is_word : Option<Arc<WordFn>>,
+    /// Whether '.' matches a grapheme cluster rather than a char.
+    graphemes : bool,
+    /// Whether \\b only takes ASCII chars as word chars.
+    ascii : bool,
+    /// Whether alternations try the alternative that gets furthest first.
+    longest_alt : bool,
+    /// The only groups (besides group 0) to report, if not all of them.
+    capture_only : Option<Arc<BTreeSet<usize>>>,
+    /// The number of alternatives at the top level of the pattern, counted
+    /// before those with a common prefix are merged.
+    alternatives : usize
 }
 
-#[test]
-fn test_char_class_negated() {
-    test_result("[^z]", "a", MatchResult::new());
+impl Regex {
+    /**
+     * Creates a regex from a str that represents a regex. Returns an error
+     * if the regex is not well-formed.
+     *
+     * The empty pattern is well-formed, and matches only the empty string.
+     *
+     * ```
+     * use regex::Regex;
+     *
+     * let regex = Regex::new("a+").unwrap();
+     * assert!(regex.match_str("aaa").is_some());
+     * assert!(Regex::new("a)").is_err());
+     * ```
+     */
+    pub fn new(s : &str) -> Result<Regex, ParseError> {
+        Regex::parse(&mut s.chars(), ParseState::new(s))
+    }
+
+    /**
+     * Creates a regex from a pattern at the start of a char iterator, ending
+     * at the first terminator char outside of any group or class, like the
+     * '/' in "a+/rest". The iterator is left just after the terminator. A
+     * terminator in the pattern itself can be escaped with '\\'.
+     *
+     * Returns an error if the regex is not well-formed or the terminator
+     * isn't found. Error positions are relative to where the iterator
+     * started.
+     *
+     * * itr        - an iterator positioned at the start of the pattern
+     * * terminator - the char that ends the pattern
+     */
+    pub fn from_chars(itr : &mut Chars, terminator : char) -> Result<Regex, ParseError> {
+        let mut st = ParseState::new(itr.as_str());
+        st.terminator = Some(terminator);
+        Regex::parse(itr, st)
+    }
+
+    /**
+     * Parses a regex, starting from the given parse state (which holds the
+     * initial flags).
+     */
+    fn parse(itr : &mut Chars, mut st : ParseState) -> Result<Regex, ParseError> {
+        // Count the alternatives as written, before factoring merges any.
+        let mut alt = AltNode::parse_unfactored(itr, &mut st, None)?;
+        let alternatives = alt.alts.len();
+        alt.factor();
+        let root = GrpNode {
+            num : 0,
+            name : None,
+            alt : alt
+        };
+        let ast = root.alt.ast();
+        Ok(Regex {
+            root : root,
+            names : st.names,
+            capture_history : false,
+            anchored_start : ast.is_anchored_start(),
+            anchored_end : ast.is_anchored_end(),
+            is_word : None,
+            graphemes : false,
+            ascii : false,
+            longest_alt : false,
+            capture_only : None,
+            alternatives : alternatives
+        })
+    }
+
+    /**
+     * Creates a regex from a str that represents a regex. Panics if the
+     * regex is not well-formed.
+     *
+     * This is the original constructor, kept for compatibility. Prefer
+     * Regex::new, which returns the error instead.
+     */
+    pub fn from_str(s : &str) -> Regex {
+        match Regex::new(s) {
+            Ok(regex) => regex,
+            Err(e) => panic!("{}", e)
+        }
+    }
+
+    /**
+     * Creates a regex from a str that represents a regex. Returns an error
+     * if the regex is not well-formed. The same as Regex::new.
+     */
+    pub fn try_from_str(s : &str) -> Result<Regex, ParseError> {
+        Regex::new(s)
+    }
+
+    /**
+     * Creates a regex from a str, like Regex::new, but reuses the regex made
+     * by an earlier call with the same pattern if there is one. The last
+     * CACHE_CAPACITY patterns compiled are kept, shared by all threads.
+     * Patterns that fail to parse aren't kept.
+     *
+     * * pattern - a str that represents a regex
+     */
+    pub fn cached(pattern : &str) -> Result<Arc<Regex>, ParseError> {
+        // The most recently used regexes are at the end.
+        static CACHE : Mutex<Vec<(String, Arc<Regex>)>> = Mutex::new(Vec::new());
+
+        {
+            let mut cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(i) = cache.iter().position(|entry| entry.0 == pattern) {
+                let entry = cache.remove(i);
+                let regex = entry.1.clone();
+                cache.push(entry);
+                return Ok(regex);
+            }
+        }
+
+        // Parse without holding the lock, so other threads aren't held up.
+        let regex = Arc::new(Regex::new(pattern)?);
+        let mut cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+        cache.push((pattern.to_string(), regex.clone()));
+        if cache.len() > CACHE_CAPACITY {
+            cache.remove(0);
+        }
+        Ok(regex)
+    }
+
+    /**
+     * Builds a regex from a tree, with the given group names. Options other
+     * than those the nodes are built with are left off.
+     */
+    fn from_ast(ast : &Ast, names : Vec<Option<String>>, options : NodeOptions) -> Regex {
+        Regex {
+            root : GrpNode {
+                num : 0,
+                name : None,
+                alt : AltNode::from_ast(ast, options)
+            },
+            names : names,
+            capture_history : false,
+            anchored_start : ast.is_anchored_start(),
+            anchored_end : ast.is_anchored_end(),
+            is_word : None,
+            graphemes : options.graphemes,
+            ascii : false,
+            longest_alt : false,
+            capture_only : None,
+            alternatives : match *ast {
+                Ast::Alternation(ref asts) => asts.len(),
+                _ => 1
+            }
+        }
+    }
+
+    /**
+     * Builds a regex from a tree derived from this regex's, with the given
+     * group names and this regex's options. The groups to report are kept
+     * as they are, so they must be remapped if the groups are renumbered.
+     */
+    fn derive(&self, ast : &Ast, names : Vec<Option<String>>) -> Regex {
+        let options = NodeOptions {
+            graphemes : self.graphemes,
+            word_chars : self.is_word.is_some()
+        };
+        let mut regex = Regex::from_ast(ast, names, options);
+        regex.capture_history = self.capture_history;
+        regex.is_word = self.is_word.clone();
+        regex.ascii = self.ascii;
+        regex.longest_alt = self.longest_alt;
+        regex.capture_only = self.capture_only.clone();
+        regex.alternatives = self.alternatives;
+        regex
+    }
+
+    /// Returns the numbers of the groups (besides group 0) reported in match
+    /// results.
+    fn reported_groups(&self) -> BTreeSet<usize> {
+        (1..self.names.len()).filter(|num| {
+            self.capture_only.as_ref().is_none_or(|only| only.contains(num))
+        }).collect()
+    }
+
+    /**
+     * Returns the structure of the regex. Group 0, the whole match, isn't
+     * in the tree, which only holds the groups written in the pattern; every
+     * match has group 0 regardless, holding the whole text matched.
+     */
+    pub fn ast(&self) -> Ast {
+        self.root.alt.ast()
+    }
+
+    /**
+     * Returns the structure of the regex as an indented tree, one node per
+     * line, for seeing how a pattern was parsed. Like ast, sequences and
+     * alternations of one element are shown as just that element.
+     */
+    pub fn debug_tree(&self) -> String {
+        let mut s = String::new();
+        self.ast().write_tree(&mut s, 0);
+        s
+    }
+
+    /**
+     * Returns whether every match of the regex must start at the start of the
+     * input, because the pattern begins with '^' or "\\A" (in every
+     * alternative).
+     */
+    pub fn is_anchored_start(&self) -> bool {
+        self.anchored_start
+    }
+
+    /**
+     * Returns whether every match of the regex must end at the end of the
+     * input, because the pattern ends with '$' or "\\z" (in every
+     * alternative).
+     */
+    pub fn is_anchored_end(&self) -> bool {
+        self.anchored_end
+    }
+
+    /**
+     * Returns whether the regex is anchored at both ends, as "^abc$" is, so
+     * it can only match the whole input (or all but a final '\n', with
+     * RegexBuilder::dollar_before_newline). Searches only try matching at the
+     * start of the input.
+     */
+    pub fn is_fully_anchored(&self) -> bool {
+        self.anchored_start && self.anchored_end
+    }
+
+    /**
+     * Returns every string the regex matches, in the order the pattern lists
+     * them, or None if there are infinitely many. Patterns with '.' or
+     * negated classes (which match nearly any char) or backreferences also
+     * give None. This is meant for small patterns: the number of strings
+     * grows quickly with the number of repeats and classes.
+     */
+    pub fn enumerate(&self) -> Option<Vec<String>> {
+        let mut seen = HashSet::new();
+        let strings = self.ast().strings()?;
+        Some(strings.into_iter()
+             .filter(|s| seen.insert(s.clone()) && self.match_str(s).is_some())
+             .collect())
+    }
+
+    /**
+     * Returns whether this regex and other match exactly the same strings,
+     * found by listing the strings each matches (see enumerate). Returns
+     * None if either can't be listed.
+     *
+     * * other - the regex to compare with
+     */
+    pub fn equivalent(&self, other : &Regex) -> Option<bool> {
+        let mine : HashSet<String> = self.enumerate()?.into_iter().collect();
+        let theirs : HashSet<String> = other.enumerate()?.into_iter().collect();
+        Some(mine == theirs)
+    }
+
+    /**
+     * Returns whether some str matches both this regex and other as a whole,
+     * as match_str does, so "a.*" and ".*b" intersect (both match "ab") but
+     * "abc" and "abd" don't.
+     *
+     * This is found by running the automata (see is_match_dfa) for both
+     * together, so it works for infinite sets of strings too. If either
+     * regex can't be made into one, because it uses something like
+     * backreferences, the strings of one of them are listed and matched
+     * against the other (see enumerate). Returns None if neither works, or
+     * the automata together would need more than DFA_STATE_LIMIT states.
+     *
+     * * other - the regex to compare with
+     */
+    pub fn intersects(&self, other : &Regex) -> Option<bool> {
+        let automaton = |regex : &Regex| {
+            if regex.is_word.is_some() || regex.graphemes {
+                None
+            } else {
+                Nfa::new(&regex.ast()).ok()
+            }
+        };
+        if let (Some(mine), Some(theirs)) = (automaton(self), automaton(other)) {
+            return mine.intersects(&theirs);
+        }
+
+        if let Some(strings) = self.enumerate() {
+            return Some(strings.iter().any(|s| other.match_str(s).is_some()));
+        }
+        let strings = other.enumerate()?;
+        Some(strings.iter().any(|s| self.match_str(s).is_some()))
+    }
+
+    /**
+     * Returns the chars that every match of the regex starts with one of, so
+     * regexes that can't match at a char can be skipped. Returns None if the
+     * regex can match the empty string, or can start with nearly any char
+     * (as with '.', negated classes or backreferences) or more than 256
+     * different chars.
+     */
+    pub fn first_bytes(&self) -> Option<BTreeSet<char>> {
+        match self.ast().first_chars() {
+            Some((chars, false)) => Some(chars),
+            _ => None
+        }
+    }
+
+    /**
+     * Returns a copy of the regex with every quantifier made lazy, so that
+     * each matches as little as possible.
+     */
+    pub fn to_lazy(&self) -> Regex {
+        self.derive(&self.ast().to_lazy(), self.names.clone())
+    }
+
+    /**
+     * Returns a regex matching this regex followed by other. The groups of
+     * other are numbered after this regex's, so a group numbered n in other
+     * is numbered n plus the number of groups in this regex. If both have a
+     * group with the same name, the name refers to this regex's group. The
+     * result has this regex's options, capture history if either has it,
+     * and reports the groups each reports.
+     *
+     * * other - the regex to match after this one
+     */
+    pub fn concat(&self, other : &Regex) -> Regex {
+        let mut regex = self.combine(other, Ast::Concat);
+        regex.alternatives = 1;
+        regex
+    }
+
+    /**
+     * Returns a regex matching either this regex or other, preferring this
+     * one. The groups of other are numbered after this regex's, as with
+     * concat.
+     *
+     * * other - the regex to try if this one doesn't match
+     */
+    pub fn or(&self, other : &Regex) -> Regex {
+        let mut regex = self.combine(other, Ast::Alternation);
+        regex.alternatives = self.alternatives + other.alternatives;
+        regex
+    }
+
+    /**
+     * Returns a regex matching this regex repeated from min to max times (or
+     * any number of times from min on, if max is None), preferring more
+     * repetitions, like "{min,max}". Panics if max is less than min.
+     *
+     * * min - the fewest repetitions allowed
+     * * max - the most repetitions allowed, if there's a limit
+     */
+    pub fn repeat(&self, min : usize, max : Option<usize>) -> Regex {
+        assert!(max.is_none_or(|max| max >= min),
+                "repeat: max {:?} is less than min {}", max, min);
+
+        let ast = Ast::Repeat {
+            min : min,
+            max : max,
+            greedy : true,
+            possessive : false,
+            ast : Box::new(self.ast())
+        };
+        let mut regex = self.derive(&ast, self.names.clone());
+        regex.alternatives = 1;
+        regex
+    }
+
+    /**
+     * Returns a regex matching the reverse of each string this regex matches,
+     * so it can be run over reversed input to match from the end. Sequences
+     * are reversed and '^' and '$' (and "\\A" and "\\z") swap. Groups keep
+     * their numbers, and capture the reverse of what they captured.
+     *
+     * Returns an error if the regex uses something that can't be reversed:
+     * backreferences, conditionals, lookbehinds and the "\\G" and "\\Z"
+     * anchors (including '$' built to match before a final newline).
+     */
+    pub fn reverse(&self) -> Result<Regex, ReverseError> {
+        Ok(self.derive(&self.ast().reverse()?, self.names.clone()))
+    }
+
+    /**
+     * Builds a regex from the trees of this regex and other, with the groups
+     * of other renumbered to follow this regex's.
+     *
+     * * other - the second regex
+     * * join  - builds the new tree from the two trees
+     */
+    fn combine(&self, other : &Regex, join : fn(Vec<Ast>) -> Ast) -> Regex {
+        let offset = self.names.len() - 1;
+        let ast = join(vec!(self.ast(), other.ast().renumber(offset)));
+        let mut names = self.names.clone();
+        names.extend(other.names.iter().skip(1).cloned());
+
+        let mut regex = self.derive(&ast, names);
+        regex.capture_history = self.capture_history || other.capture_history;
+        if self.capture_only.is_some() || other.capture_only.is_some() {
+            let mut only = self.reported_groups();
+            only.extend(other.reported_groups().iter().map(|num| num + offset));
+            regex.capture_only = Some(Arc::new(only));
+        }
+        regex
+    }
+
+    /**
+     * Returns a copy of the regex with its groups made non-capturing, as if
+     * each "(...)" were "(?:...)", so it matches the same strings without
+     * the cost of capturing. Groups referred to by a backreference or
+     * conditional still capture, since the match depends on them; they're
+     * renumbered from 1 in order.
+     */
+    pub fn without_captures(&self) -> Regex {
+        let ast = self.ast();
+        let mut referenced = BTreeSet::new();
+        ast.referenced_groups(&mut referenced);
+
+        let mut names = vec!(None);
+        let mut kept = BTreeMap::new();
+        for num in referenced {
+            if let Some(name) = self.names.get(num) {
+                kept.insert(num, names.len());
+                names.push(name.clone());
+            }
+        }
+
+        let mut regex = self.derive(&ast.strip_groups(&kept), names);
+        regex.capture_only = self.capture_only.as_ref().map(|only| {
+            Arc::new(only.iter().filter_map(|num| kept.get(num).cloned()).collect())
+        });
+        regex
+    }
+
+    /**
+     * Returns the number of alternatives at the top level of the pattern, so
+     * 3 for "a|b|c" and 1 for a regex without a top-level '|'. They're
+     * counted as written, even though alternatives with a common prefix are
+     * merged when the regex is built (so "ab|ac" becomes "a(?:b|c)"). A
+     * regex built by Regex::or has the alternatives of both.
+     */
+    pub fn top_level_alternatives(&self) -> usize {
+        self.alternatives
+    }
+
+    /**
+     * Returns the number of capturing groups, not counting group 0 (the
+     * whole match).
+     */
+    pub fn group_count(&self) -> usize {
+        self.names.len() - 1
+    }
+
+    /**
+     * Returns the name of each group, in order of group number, or None for
+     * unnamed groups. The first entry is for group 0, the whole match, which
+     * is never named.
+     */
+    pub fn capture_names<'a>(&'a self) -> impl Iterator<Item=Option<&'a str>> + 'a {
+        self.names.iter().map(|name| name.as_ref().map(|s| s.as_str()))
+    }
+
+    /**
+     * Matches a str against a regex. Anything that can be borrowed as a str
+     * can be matched, such as a String or a Cow<str>, without taking
+     * ownership of it.
+     *
+     * * regex - the regular expression
+     * * s     - a str to match
+     */
+    pub fn match_str(&self, s : impl AsRef<str>) -> Option<MatchResult> {
+        let s = s.as_ref();
+        let mut m = self.matcher(Input::Str(s));
+        self.exec(&mut m, 0, &mut |end| end == s.len()).map(|_| m.match_result())
+    }
+
+    /**
+     * Matches a str against a regex, as match_str does, but treating every
+     * quantifier as lazy, as find_shortest does. Whether the str matches is
+     * the same, but groups take the shortest strings they can, rather than
+     * the longest.
+     *
+     * * s - a str to match
+     */
+    pub fn match_str_lazy(&self, s : &str) -> Option<MatchResult> {
+        let mut m = self.matcher(Input::Str(s));
+        m.lazy = true;
+        self.exec(&mut m, 0, &mut |end| end == s.len()).map(|_| m.match_result())
+    }
+
+    /**
+     * Matches each of a number of strs against a regex, as match_str does,
+     * returning whether each matched, in order. One matcher is reused for
+     * every str, so beyond its groups being reset, nothing is allocated per
+     * input.
+     *
+     * * inputs - the strs to match
+     */
+    pub fn match_all<'a>(&self, inputs : impl IntoIterator<Item=&'a str>) -> Vec<bool> {
+        let mut m = self.matcher(Input::Str(""));
+        inputs.into_iter().map(|s| {
+            m.input = Input::Str(s);
+            self.exec(&mut m, 0, &mut |end| end == s.len()).is_some()
+        }).collect()
+    }
+
+    /**
+     * Matches a slice of chars against a regex.
+     *
+     * * chars - the chars to match
+     */
+    pub fn match_char_slice(&self, chars : &[char]) -> Option<MatchResult> {
+        let mut m = self.matcher(Input::Chars(chars));
+        self.exec(&mut m, 0, &mut |end| end == chars.len()).map(|_| m.match_result())
+    }
+
+    /**
+     * Matches a str against a regex, as match_str does, but gives up once a
+     * deadline passes. The clock is only checked every so often, so the match
+     * may run a little past the deadline.
+     *
+     * Returns MatchError::Timeout if the deadline passed.
+     *
+     * * s        - a str to match
+     * * deadline - the time by which to give up
+     */
+    pub fn match_until(&self, s : &str, deadline : Instant)
+                       -> Result<Option<MatchResult>, MatchError> {
+        let mut m = self.matcher(Input::Str(s));
+        m.deadline = Some(deadline);
+        let res = self.exec(&mut m, 0, &mut |end| end == s.len()).map(|_| m.match_result());
+        match m.abandoned {
+            Some(e) => Err(e),
+            None => Ok(res)
+        }
+    }
+
+    /**
+     * Matches a str against a regex, as match_str does, but gives up once
+     * the quantifiers have made more than limit attempts at a repetition in
+     * total, counting those retried while backtracking. This bounds the work
+     * done by patterns like "(a*)*b", which try many ways of splitting the
+     * input between repetitions, however fast the machine is.
+     *
+     * Returns MatchError::RepeatLimitExceeded if the limit was reached.
+     *
+     * * s     - a str to match
+     * * limit - the most repetitions to attempt
+     */
+    pub fn match_with_repeat_limit(&self, s : &str, limit : usize)
+                                   -> Result<Option<MatchResult>, MatchError> {
+        let mut m = self.matcher(Input::Str(s));
+        m.repeat_limit = Some(limit);
+        let res = self.exec(&mut m, 0, &mut |end| end == s.len()).map(|_| m.match_result());
+        match m.abandoned {
+            Some(e) => Err(e),
+            None => Ok(res)
+        }
+    }
+
+    /**
+     * Matches a regex against the start of a str. Unlike match_str, the match
+     * needn't reach the end of the string; the first match found (so the
+     * longest, if the quantifiers are greedy) is taken.
+     *
+     * Returns the number of chars the match consumed.
+     *
+     * * s - a str to match the start of
+     */
+    pub fn match_prefix(&self, s : &str) -> Option<usize> {
+        let mut m = self.matcher(Input::Str(s));
+        self.exec(&mut m, 0, &mut |_| true).map(|end| s[..end].chars().count())
+    }
+
+    /**
+     * Matches a regex against the start of a str, as match_prefix does, and
+     * returns the groups matched along with the rest of the str after the
+     * match. Matching the rest with further regexes chains them along s.
+     *
+     * * s - a str to match the start of
+     */
+    pub fn consume<'a>(&self, s : &'a str) -> Option<(MatchResult, &'a str)> {
+        let mut m = self.matcher(Input::Str(s));
+        let end = self.exec(&mut m, 0, &mut |_| true)?;
+        Some((m.match_result(), &s[end..]))
+    }
+
+    /**
+     * Finds how far into a str a regex can get, matching from the start,
+     * even if it can't match completely. This tries every way the regex can
+     * match, so it can be slow for patterns that backtrack a lot.
+     *
+     * Returns the number of chars consumed by the partial match that got
+     * furthest, which for a regex matching the start of s is at least as
+     * many as match_prefix gives.
+     *
+     * * s - a str to match the start of
+     */
+    pub fn longest_prefix_match(&self, s : &str) -> usize {
+        let mut m = self.matcher(Input::Str(s));
+        m.furthest = Some(0);
+        self.exec(&mut m, 0, &mut |_| false);
+        s[..m.furthest.unwrap_or(0)].chars().count()
+    }
+
+    /**
+     * Matches a str against a regex, like match_str, for input that may
+     * still be arriving. If the regex doesn't match the whole str, this tells
+     * whether it got all the way to the end of the str before failing, when
+     * more input could complete a match: so "abc" gives PartialResult::Partial
+     * for "ab", and PartialResult::None for "abd".
+     *
+     * Anchors and lookbehinds aren't checked against the input to come, so
+     * this may give Partial when one of them would stop any match, as with
+     * "a$b" and "a".
+     *
+     * * s - the input so far
+     */
+    pub fn partial_match(&self, s : &str) -> PartialResult {
+        let mut m = self.matcher(Input::Str(s));
+        m.furthest = Some(0);
+        if self.exec(&mut m, 0, &mut |end| end == s.len()).is_some() {
+            PartialResult::Full(m.match_result())
+        } else if m.furthest == Some(s.len()) {
+            PartialResult::Partial
+        } else {
+            PartialResult::None
+        }
+    }
+
+    /**
+     * Matches a str against a regex, like match_str, but when the whole str
+     * doesn't match, tells whether the regex matched the start of it.
+     *
+     * * s - a str to match
+     */
+    pub fn full_match(&self, s : &str) -> FullMatch {
+        if let Some(mr) = self.match_str(s) {
+            return FullMatch::Full(mr);
+        }
+        match self.match_prefix(s) {
+            Some(consumed) => FullMatch::PrefixOnly { consumed : consumed },
+            None => FullMatch::NoMatch
+        }
+    }
+
+    /**
+     * Matches a regex against the end of a str. The match must reach the end
+     * of the string, but can start anywhere; the leftmost start is taken.
+     *
+     * Returns the start position (in bytes) of the match.
+     *
+     * * s - a str to match the end of
+     */
+    pub fn match_suffix(&self, s : &str) -> Option<usize> {
+        let mut m = self.matcher(Input::Str(s));
+        let mut pos = 0;
+        loop {
+            if self.exec(&mut m, pos, &mut |end| end == s.len()).is_some() {
+                return Some(pos);
+            }
+            pos = m.next(pos)?.1;
+        }
+    }
+
+    /**
+     * Matches a str against a regex, returning the groups matched (including
+     * named groups).
+     *
+     * * s - a str to match
+     */
+    pub fn captures(&self, s : &str) -> Option<Captures> {
+        let mut m = self.matcher(Input::Str(s));
+        self.exec(&mut m, 0, &mut |end| end == s.len())?;
+        Some(m.captures(&self.names))
+    }
+
+    /**
+     * Searches a str for the leftmost match of a regex. Unlike match_str,
+     * the match needn't cover the whole string.
+     *
+     * * s - a str to search
+     */
+    pub fn find<'s>(&self, s : &'s str) -> Option<Match<'s>> {
+        self.find_at(s, 0).map(|(start, end)| Match {
+            text : s,
+            start : start,
+            end : end
+        })
+    }
+
+    /**
+     * Searches a str for the leftmost match of a regex that starts at or
+     * after a given position. Panics if start isn't on a char boundary.
+     *
+     * Returns the start and end positions (in bytes) of the match.
+     *
+     * * s     - a str to search
+     * * start - the position (in bytes) to start searching from
+     */
+    pub fn find_at(&self, s : &str, start : usize) -> Option<(usize, usize)> {
+        assert!(s.is_char_boundary(start),
+                "find_at: start position {} is not a char boundary", start);
+
+        let mut m = self.matcher(Input::Str(s));
+        m.search_start = start;
+        self.search(&mut m, start)
+    }
+
+    /**
+     * Returns whether a regex matches anywhere in a str, like
+     * find(s).is_some(), but without backtracking: each char of s is looked
+     * at once, so the time taken is linear in the length of s, whatever the
+     * pattern. This makes it safe to run untrusted patterns or inputs.
+     *
+     * Only regular constructs are supported. Returns
+     * MatchError::Unsupported for a regex using backreferences,
+     * conditionals, lookbehinds, word boundaries or possessive quantifiers,
+     * or built with a word char predicate or with '.' matching graphemes,
+     * or one with more than DFA_STATE_LIMIT states. Callers can fall back to
+     * find for those.
+     *
+     * * s - a str to search
+     */
+    pub fn is_match_dfa(&self, s : &str) -> Result<bool, MatchError> {
+        if self.is_word.is_some() {
+            return Err(MatchError::Unsupported { construct : "word char predicates" });
+        }
+        if self.graphemes {
+            return Err(MatchError::Unsupported { construct : "matching graphemes" });
+        }
+        Ok(Nfa::new(&self.ast())?.is_match(s))
+    }
+
+    /**
+     * Returns whether a regex matches a str starting exactly at a given
+     * position. The match needn't reach the end of the string. Panics if pos
+     * isn't on a char boundary.
+     *
+     * * s   - a str to match part of
+     * * pos - the position (in bytes) the match must start at
+     */
+    pub fn is_match_at(&self, s : &str, pos : usize) -> bool {
+        assert!(s.is_char_boundary(pos),
+                "is_match_at: position {} is not a char boundary", pos);
+
+        let mut m = self.matcher(Input::Str(s));
+        m.search_start = pos;
+        self.exec(&mut m, pos, &mut |_| true).is_some()
+    }
+
+    /**
+     * Searches a str for the leftmost match of a regex, like find, and
+     * returns the groups it matched along with where it is.
+     *
+     * Returns the range (in bytes) of the whole match, and the groups.
+     *
+     * * s - a str to search
+     */
+    pub fn find_with_captures(&self, s : &str) -> Option<(Range<usize>, MatchResult)> {
+        let mut m = self.matcher(Input::Str(s));
+        let (start, end) = self.search(&mut m, 0)?;
+        Some((start..end, m.match_result()))
+    }
+
+    /**
+     * Searches a str for the leftmost match of a regex, and returns where the
+     * shortest match from there ends, as found by treating every quantifier
+     * as lazy. This avoids the work of finding the longer matches greedy
+     * quantifiers would prefer.
+     *
+     * * s - a str to search
+     */
+    pub fn find_shortest(&self, s : &str) -> Option<usize> {
+        let mut m = self.matcher(Input::Str(s));
+        m.lazy = true;
+        self.search(&mut m, 0).map(|(_, end)| end)
+    }
+
+    /**
+     * Returns the spans of the non-overlapping matches of a regex in a str,
+     * from left to right, as find_iter finds them.
+     *
+     * * s - a str to search
+     */
+    pub fn find_ranges(&self, s : &str) -> Vec<Range<usize>> {
+        self.find_iter(s).map(|(start, end)| start..end).collect()
+    }
+
+    /**
+     * Returns an iterator over the non-overlapping matches of a regex in a
+     * str, from left to right.
+     *
+     * After an empty match, the search resumes one char further on, so an
+     * empty match can directly follow a non-empty one (e.g. "a*" finds "aa"
+     * then "" in "aa").
+     *
+     * * s - a str to search
+     */
+    pub fn find_iter<'r, 's>(&'r self, s : &'s str) -> Matches<'r, 's> {
+        Matches {
+            regex : self,
+            s : s,
+            pos : Some(0),
+            last_end : 0
+        }
+    }
+
+    /**
+     * Splits a str after each of the non-overlapping matches of a regex, as
+     * find_iter finds them, keeping each match at the end of the piece before
+     * it, like str::split_inclusive. So splitting "a\nb\n" on "\n" gives
+     * "a\n" and "b\n". The text after the last match is the last piece, if
+     * there is any; empty pieces (from empty matches) are skipped.
+     *
+     * * s - a str to split
+     */
+    pub fn split_inclusive<'a>(&'a self, s : &'a str) -> impl Iterator<Item=&'a str> + 'a {
+        let mut matches = self.find_iter(s);
+        let mut start = 0;
+        iter::from_fn(move || {
+            while start < s.len() {
+                let end = matches.next().map_or(s.len(), |(_, end)| end);
+                let piece = &s[start..end];
+                start = end;
+                if !piece.is_empty() {
+                    return Some(piece);
+                }
+            }
+            None
+        })
+    }
+
+    /**
+     * Splits a str at the non-overlapping matches of a regex, as find_iter
+     * finds them, yielding both the text between matches and the matches
+     * themselves. The pieces alternate, starting and ending with text, so
+     * there is a Piece::Text (which may be empty) before, between and after
+     * the Piece::Delim of each match.
+     *
+     * * s - a str to split
+     */
+    pub fn split_captures<'a>(&'a self, s : &'a str) -> impl Iterator<Item=Piece<'a>> + 'a {
+        let mut pos = Some(0);
+        let mut last_end = 0;
+        let mut start = Some(0);
+        let mut delim = None;
+        iter::from_fn(move || {
+            if let Some(mr) = delim.take() {
+                return Some(Piece::Delim(mr));
+            }
+            let text_start = start?;
+            match self.next_match(s, &mut pos, &mut last_end) {
+                Some((m, match_start, end)) => {
+                    delim = Some(m.match_result());
+                    start = Some(end);
+                    Some(Piece::Text(&s[text_start..match_start]))
+                }
+                None => {
+                    start = None;
+                    Some(Piece::Text(&s[text_start..]))
+                }
+            }
+        })
+    }
+
+    /**
+     * Returns an iterator over the groups matched by each of the
+     * non-overlapping matches of a regex in a str, from left to right. The
+     * matches are the same ones find_iter finds.
+     *
+     * * s - a str to search
+     */
+    pub fn captures_iter<'r, 's>(&'r self, s : &'s str) -> CaptureMatches<'r, 's> {
+        CaptureMatches {
+            regex : self,
+            s : s,
+            pos : Some(0),
+            last_end : 0
+        }
+    }
+
+    /**
+     * Returns a scanner over the groups matched by each of the
+     * non-overlapping matches of a regex in a str, like captures_iter, but
+     * reusing a single MatchResult between matches to avoid allocating one
+     * per match. Each match's groups are only valid until the next one is
+     * found.
+     *
+     * * s - a str to search
+     */
+    pub fn captures_scan<'r, 's>(&'r self, s : &'s str) -> CaptureScanner<'r, 's> {
+        CaptureScanner {
+            regex : self,
+            s : s,
+            pos : Some(0),
+            last_end : 0,
+            groups : MatchResult::new()
+        }
+    }
+
+    /**
+     * Replaces every non-overlapping match of a regex in a str with a
+     * template filled in with the match's groups (see Captures::expand).
+     *
+     * Returns s itself, borrowed, if there are no matches, and a new String
+     * otherwise.
+     *
+     * * s - a str to search
+     * * template - what to replace each match with
+     */
+    pub fn replace_all<'s>(&self, s : &'s str, template : &str) -> Cow<'s, str> {
+        let mut pos = Some(0);
+        let mut last_end = 0;
+        let mut out = String::new();
+        let mut copied = 0;
+        let mut matched = false;
+
+        while let Some((m, start, end)) = self.next_match(s, &mut pos, &mut last_end) {
+            matched = true;
+            out.push_str(&s[copied..start]);
+            out.push_str(&m.captures(&self.names).expand(template));
+            copied = end;
+        }
+
+        if !matched {
+            return Cow::Borrowed(s);
+        }
+        out.push_str(&s[copied..]);
+        return Cow::Owned(out);
+    }
+
+    /**
+     * Finds all matches of a regex in a str, including overlapping ones.
+     * Unlike find_iter, after each match the search resumes one char after
+     * where the match started, rather than where it ended. So there is at
+     * most one match starting at each position.
+     *
+     * Returns the start and end position (in bytes) of each match.
+     *
+     * * s - a str to search
+     */
+    pub fn find_overlapping(&self, s : &str) -> Vec<(usize, usize)> {
+        let mut found = Vec::new();
+        let mut pos = 0;
+
+        while let Some((start, end)) = self.find_at(s, pos) {
+            found.push((start, end));
+            match s[start..].chars().next() {
+                Some(c) => { pos = start + c.len_utf8(); }
+                None => { break; }
+            }
+        }
+
+        return found;
+    }
+
+    /**
+     * Returns the number of non-overlapping matches of a regex in a str, as
+     * found by find_iter.
+     *
+     * * s - a str to search
+     */
+    pub fn count(&self, s : &str) -> usize {
+        self.find_iter(s).count()
+    }
+
+    /**
+     * Matches a char iterator against a regex.
+     *
+     * * regex - the regular expression
+     * * itr   - an iterator to match
+     */
+     pub fn match_chars(&self, itr : &mut Chars) -> Option<MatchResult> {
+        let s = itr.as_str();
+        let res = self.match_str(s);
+
+        // The whole string was matched, so consume it.
+        if res.is_some() {
+            *itr = s[s.len()..].chars();
+        }
+        res
+     }
+
+    /**
+     * Matches the contents of a reader against a regex.
+     *
+     * The reader is read to the end into a buffer before matching, since the
+     * whole input must be matched. Fails if reading fails or the input isn't
+     * valid UTF-8.
+     *
+     * * r - a reader supplying the string to match
+     */
+    pub fn match_read<R : Read>(&self, mut r : R) -> io::Result<Option<MatchResult>> {
+        let mut s = String::new();
+        r.read_to_string(&mut s)?;
+        Ok(self.match_str(&s))
+    }
+
+    /**
+     * Returns a matcher that takes the input to match in chunks, for input
+     * that arrives a piece at a time. The chunks are matched as one string
+     * once they've all been fed in.
+     */
+    pub fn incremental<'r>(&'r self) -> IncrementalMatcher<'r> {
+        IncrementalMatcher {
+            regex : self,
+            buffer : String::new()
+        }
+    }
+
+    /**
+     * Searches each line of a reader for a match of a regex, as find does.
+     * Lines are split on "\n" (or "\r\n"), and the line ending isn't part
+     * of the line searched, so it is never included in a match.
+     *
+     * Returns the line number (starting from 1) and the groups matched for
+     * each line containing a match. Fails if reading fails or a line isn't
+     * valid UTF-8.
+     *
+     * * reader - a reader supplying the lines to search
+     */
+    pub fn match_lines(&self, reader : impl BufRead) -> io::Result<Vec<(usize, MatchResult)>> {
+        let mut found = Vec::new();
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let mut m = self.matcher(Input::Str(&line));
+            if self.search(&mut m, 0).is_some() {
+                found.push((i + 1, m.match_result()));
+            }
+        }
+        Ok(found)
+    }
+}
+
+impl Regex {
+    /**
+     * Returns a matcher for running the regex against some input.
+     */
+    fn matcher<'a>(&self, input : Input<'a>) -> Matcher<'a> {
+        let mut m = Matcher::new(input, self.names.len());
+        if self.capture_history {
+            m.history = Some(vec!(Vec::new(); self.names.len()));
+        }
+        m.is_word = self.is_word.clone();
+        m.ascii = self.ascii;
+        m.longest_alt = self.longest_alt;
+        m.capture_only = self.capture_only.clone();
+        m
+    }
+
+    /**
+     * Matches the regex against the matcher's input starting at start,
+     * taking the first match (in order of preference) whose end position
+     * accept approves of. The groups it matched are left in the matcher.
+     *
+     * Returns the end position of the match.
+     */
+    fn exec(&self, m : &mut Matcher, start : usize, accept : &mut dyn FnMut(usize) -> bool)
+            -> Option<usize> {
+        for span in &mut m.groups {
+            *span = None;
+        }
+        if let Some(ref mut history) = m.history {
+            for spans in history {
+                spans.clear();
+            }
+        }
+
+        let mut end = None;
+        self.root.match_chars(m, start, &mut |_, pos| {
+            if accept(pos) {
+                end = Some(pos);
+                true
+            } else {
+                false
+            }
+        });
+        end
+    }
+
+    /**
+     * Finds the next match for an iterator over the matches in s, searching
+     * from pos, and updates pos and last_end for the search after.
+     *
+     * Returns the matcher holding the groups matched, and the start and end
+     * positions of the match.
+     */
+    fn next_match<'s>(&self, s : &'s str, pos : &mut Option<usize>, last_end : &mut usize)
+                      -> Option<(Matcher<'s>, usize, usize)> {
+        let mut m = self.matcher(Input::Str(s));
+        m.search_start = *last_end;
+        let (start, end) = match self.search(&mut m, (*pos)?) {
+            Some(span) => span,
+            None => {
+                *pos = None;
+                return None;
+            }
+        };
+
+        // Resume after the match. If it was empty, skip a char so the same
+        // empty match isn't found again.
+        *pos = if end > start {
+            Some(end)
+        } else {
+            s[end..].chars().next().map(|c| end + c.len_utf8())
+        };
+        *last_end = end;
+
+        Some((m, start, end))
+    }
+
+    /**
+     * Finds the leftmost match at or after start in the matcher's input. The
+     * groups it matched are left in the matcher.
+     *
+     * Returns the start and end positions of the match.
+     */
+    fn search(&self, m : &mut Matcher, start : usize) -> Option<(usize, usize)> {
+        let mut pos = start;
+        loop {
+            // Try to match starting at the current position.
+            if let Some(end) = self.exec(m, pos, &mut |_| true) {
+                return Some((pos, end));
+            }
+
+            // An anchored regex can only match at the start, so there's no
+            // use trying further on.
+            if self.anchored_start {
+                return None;
+            }
+
+            // Move on to the next position, giving up at the end.
+            pos = m.next(pos)?.1;
+        }
+    }
+}
+
+/**
+ * Which chars are special in a pattern, for RegexBuilder::syntax. By default
+ * the syntax is the standard one that Regex::new uses.
+ *
+ * ```
+ * use regex::{RegexBuilder, RegexSyntax};
+ *
+ * let regex = RegexBuilder::new("a*%d")
+ *     .syntax(RegexSyntax::new().literal('*').escape('%'))
+ *     .build().unwrap();
+ * assert!(regex.match_str("a*1").is_some());
+ * ```
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegexSyntax {
+    /// The char that starts an escape.
+    escape : char,
+    /// The metachars to take as literal chars.
+    literals : BTreeSet<char>
+}
+
+impl RegexSyntax {
+    /**
+     * Creates the standard syntax.
+     */
+    pub fn new() -> RegexSyntax {
+        RegexSyntax {
+            escape : '\\',
+            literals : BTreeSet::new()
+        }
+    }
+
+    /**
+     * Sets the char that starts an escape, in place of '\\', which becomes a
+     * literal char. Panics if c isn't ASCII.
+     *
+     * * c - the escape char
+     */
+    pub fn escape(&mut self, c : char) -> &mut RegexSyntax {
+        assert!(c.is_ascii(), "escape: {:?} is not an ASCII char", c);
+        self.escape = c;
+        self
+    }
+
+    /**
+     * Makes a metachar, like '*' or '(', a literal char. It keeps its usual
+     * meaning inside a char class, and within a construct like "(?:" or
+     * "{2,3}". The escape char can't be made literal.
+     *
+     * * c - the metachar to take literally
+     */
+    pub fn literal(&mut self, c : char) -> &mut RegexSyntax {
+        self.literals.insert(c);
+        self
+    }
+
+    /**
+     * Returns the metachar c acts as, or None if c is a literal char.
+     */
+    fn meta(&self, c : char) -> Option<char> {
+        if c == self.escape {
+            Some('\\')
+        } else if c == '\\' || self.literals.contains(&c) {
+            None
+        } else {
+            Some(c)
+        }
+    }
+}
+
+impl Default for RegexSyntax {
+    fn default() -> RegexSyntax {
+        RegexSyntax::new()
+    }
+}
+
+/**
+ * Builds a regex with options beyond those Regex::new uses.
+ */
+pub struct RegexBuilder {
+    pattern : String,
+    size_limit : Option<usize>,
+    capture_history : bool,
+    case_insensitive : bool,
+    dot_matches_newline : bool,
+    dot_matches_grapheme : bool,
+    dollar_before_newline : bool,
+    nesting_limit : usize,
+    capture_groups : bool,
+    is_word : Option<Arc<WordFn>>,
+    syntax : RegexSyntax,
+    ascii : bool,
+    longest_alt : bool,
+    capture_only : Option<Arc<BTreeSet<usize>>>
+}
+
+impl RegexBuilder {
+    /**
+     * Creates a builder for the given pattern, with default options.
+     */
+    pub fn new(pattern : &str) -> RegexBuilder {
+        RegexBuilder {
+            pattern : pattern.to_string(),
+            size_limit : None,
+            capture_history : false,
+            case_insensitive : false,
+            dot_matches_newline : false,
+            dot_matches_grapheme : false,
+            dollar_before_newline : false,
+            nesting_limit : MAX_NESTING,
+            capture_groups : true,
+            is_word : None,
+            syntax : RegexSyntax::new(),
+            ascii : false,
+            longest_alt : false,
+            capture_only : None
+        }
+    }
+
+    /**
+     * Limits the size of the compiled regex, roughly the number of nodes it
+     * would take with each bounded repeat written out in full (so "a{1000}"
+     * counts as a thousand 'a's). Patterns over the limit fail to build with
+     * ParseError::PatternTooLarge. By default there is no limit.
+     */
+    pub fn size_limit(&mut self, limit : usize) -> &mut RegexBuilder {
+        self.size_limit = Some(limit);
+        self
+    }
+
+    /**
+     * Limits how deeply groups, char classes and quantifiers can be nested.
+     * Patterns nested deeper fail to build with ParseError::NestingTooDeep.
+     * The limit can't be raised above MAX_NESTING, which is the default.
+     */
+    pub fn nesting_limit(&mut self, limit : usize) -> &mut RegexBuilder {
+        self.nesting_limit = cmp::min(limit, MAX_NESTING);
+        self
+    }
+
+    /**
+     * Sets whether to record every match of each group, rather than just the
+     * last, for groups that match more than once (in a repeat). The matches
+     * are available from Captures::get_all. Off by default, since it costs
+     * time and memory.
+     */
+    pub fn capture_history(&mut self, yes : bool) -> &mut RegexBuilder {
+        self.capture_history = yes;
+        self
+    }
+
+    /**
+     * Limits the groups reported in match results to the ones listed, so
+     * no strings are made for the rest. Group 0, the whole match, is always
+     * reported. The other groups are still matched as before, so
+     * backreferences and conditionals work, but match results and Captures
+     * leave them out as if they hadn't participated. By default every group
+     * is reported. Regexes derived from this one, as by Regex::concat,
+     * report the same groups, renumbered along with them.
+     *
+     * * groups - the numbers of the groups to report
+     */
+    pub fn capture_only(&mut self, groups : &[usize]) -> &mut RegexBuilder {
+        self.capture_only = Some(Arc::new(groups.iter().cloned().collect()));
+        self
+    }
+
+    /**
+     * Sets whether groups capture. If not, every group, named or not, is
+     * treated as non-capturing, like "(?:...)", so only the whole match is
+     * recorded, saving the work of tracking groups. Backreferences and
+     * conditionals then have no groups to refer to, so fail to parse. On by
+     * default.
+     */
+    pub fn capture_groups(&mut self, yes : bool) -> &mut RegexBuilder {
+        self.capture_groups = yes;
+        self
+    }
+
+    /**
+     * Sets whether letters match regardless of case, as if the pattern began
+     * with "(?i)". Off by default.
+     */
+    pub fn case_insensitive(&mut self, yes : bool) -> &mut RegexBuilder {
+        self.case_insensitive = yes;
+        self
+    }
+
+    /**
+     * Sets whether '.' also matches '\n', as if the pattern began with
+     * "(?s)". A "(?-s)" in the pattern still turns it off. Off by default.
+     */
+    pub fn dot_matches_newline(&mut self, yes : bool) -> &mut RegexBuilder {
+        self.dot_matches_newline = yes;
+        self
+    }
+
+    /**
+     * Sets whether '.' matches a whole grapheme cluster, so that a char and
+     * the combining marks after it are matched together, rather than a
+     * single char. Off by default.
+     *
+     * Clusters are found by a simple approximation of the Unicode rules: a
+     * char, then any combining diacritical marks, variation selectors, emoji
+     * modifiers and tags, and chars joined on by a zero-width joiner. A pair
+     * of regional indicators (a flag) is one cluster. The mode isn't part of
+     * the tree (see Regex::ast), so a regex parsed from the printed pattern
+     * doesn't keep it, but regexes derived by methods like Regex::concat do.
+     */
+    pub fn dot_matches_grapheme(&mut self, yes : bool) -> &mut RegexBuilder {
+        self.dot_matches_grapheme = yes;
+        self
+    }
+
+    /**
+     * Sets whether '$' also matches just before a '\n' at the end of the
+     * input, as well as at the very end, so "abc$" matches "abc\n". Off by
+     * default, when '$' only matches at the end. Either way, "\\Z" matches
+     * before a final '\n' and "\\z" doesn't.
+     */
+    pub fn dollar_before_newline(&mut self, yes : bool) -> &mut RegexBuilder {
+        self.dollar_before_newline = yes;
+        self
+    }
+
+    /**
+     * Sets whether to match only ASCII chars, even with the unicode feature:
+     * "\\d", "\\w", "\\s" and "\\b" use ASCII digits, word chars and spaces,
+     * '.' matches any ASCII char but '\n', and the i flag only folds the case
+     * of ASCII letters. Negations like "\\W" and "[^a]" still match non-ASCII
+     * chars. Off by default.
+     */
+    pub fn ascii(&mut self, yes : bool) -> &mut RegexBuilder {
+        self.ascii = yes;
+        self
+    }
+
+    /**
+     * Sets whether each alternation tries first the alternative that matches
+     * the most input on its own, rather than the first that matches, so
+     * "a|ab" matches all of "ab". Only the alternation's own match is
+     * compared, not the rest of the regex after it, so this is cheaper than
+     * leftmost-longest matching but doesn't always find the longest match.
+     * Ties go to the earlier alternative, and if the preferred one leads to
+     * a failure the others are still tried. Off by default.
+     */
+    pub fn longest_alternative(&mut self, yes : bool) -> &mut RegexBuilder {
+        self.longest_alt = yes;
+        self
+    }
+
+    /**
+     * Sets which chars are special in the pattern, such as a different
+     * escape char, or metachars to take literally. The regex still prints
+     * (see Regex::to_string) in the standard syntax.
+     *
+     * * syntax - the syntax to parse the pattern with
+     */
+    pub fn syntax(&mut self, syntax : &RegexSyntax) -> &mut RegexBuilder {
+        self.syntax = syntax.clone();
+        self
+    }
+
+    /**
+     * Sets which chars are word chars, for "\\b", "\\B", "\\w" and "\\W",
+     * in place of the default class (ASCII letters, digits and '_', or their
+     * Unicode equivalents with the unicode feature). So with '-' as a word
+     * char, "\\bfoo\\b" doesn't match in "foo-bar".
+     *
+     * The predicate isn't part of the tree (see Regex::ast), where "\\w" is
+     * still the default class, so a regex parsed from the printed pattern
+     * doesn't keep it. Regexes derived by methods like Regex::concat do, and
+     * take a class of exactly the default word chars in the tree as "\\w".
+     * Inside a char class, as in "[\\w-]", "\\w" is always the default class.
+     *
+     * * is_word - returns whether a char is a word char
+     */
+    pub fn word_chars<F>(&mut self, is_word : F) -> &mut RegexBuilder
+        where F : Fn(char) -> bool + Send + Sync + 'static {
+        self.is_word = Some(Arc::new(is_word));
+        self
+    }
+
+    /**
+     * Builds the regex. Returns an error if the pattern is not well-formed
+     * or breaks one of the limits set.
+     */
+    pub fn build(&self) -> Result<Regex, ParseError> {
+        let mut st = ParseState::new(&self.pattern);
+        st.case_insensitive = self.case_insensitive;
+        st.dot_matches_newline = self.dot_matches_newline;
+        st.graphemes = self.dot_matches_grapheme;
+        st.dollar_before_newline = self.dollar_before_newline;
+        st.nesting_limit = self.nesting_limit;
+        st.capture_groups = self.capture_groups;
+        st.word_chars = self.is_word.is_some();
+        st.syntax = self.syntax.clone();
+        st.ascii = self.ascii;
+        let mut regex = Regex::parse(&mut self.pattern.chars(), st)?;
+        regex.capture_history = self.capture_history;
+        regex.is_word = self.is_word.clone();
+        regex.graphemes = self.dot_matches_grapheme;
+        regex.ascii = self.ascii;
+        regex.longest_alt = self.longest_alt;
+        regex.capture_only = self.capture_only.clone();
+        if let Some(limit) = self.size_limit {
+            if regex.ast().size() > limit {
+                return Err(ParseError::PatternTooLarge {
+                    limit : limit,
+                    position : 0
+                });
+            }
+        }
+        Ok(regex)
+    }
+}
+
+/**
+ * Prints the regex in regex syntax.
+ */
+impl fmt::Display for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.ast())
+    }
+}
+
+/**
+ * Regexes are equal if they have the same structure (as returned by ast) and
+ * options, so they match the same way.
+ */
+impl PartialEq for Regex {
+    fn eq(&self, other : &Regex) -> bool {
+        self.ast() == other.ast() && self.capture_history == other.capture_history &&
+            self.graphemes == other.graphemes && self.ascii == other.ascii &&
+            self.longest_alt == other.longest_alt && self.capture_only == other.capture_only &&
+            // Word char predicates can only be told apart by identity.
+            match (self.is_word.as_ref(), other.is_word.as_ref()) {
+                (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+                (a, b) => a.is_none() && b.is_none()
+            }
+    }
+}
+
+impl Eq for Regex {}
+
+impl Hash for Regex {
+    fn hash<H : Hasher>(&self, state : &mut H) {
+        self.ast().hash(state);
+        self.capture_history.hash(state);
+        self.graphemes.hash(state);
+        self.ascii.hash(state);
+        self.longest_alt.hash(state);
+        self.is_word.is_some().hash(state);
+        self.capture_only.hash(state);
+    }
+}
+
+impl fmt::Debug for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Regex {}", self.root.debug())
+    }
+}
+
+/// Input to match against. Positions in a str are byte offsets, and
+/// positions in a slice of chars are indices.
+#[derive(Clone, Copy)]
+enum Input<'a> {
+    Str(&'a str),
+    Chars(&'a [char])
+}
+
+impl<'a> Input<'a> {
+    /**
+     * Returns the char at pos and the position after it, or None at the end
+     * of the input.
+     */
+    fn next(&self, pos : usize) -> Option<(char, usize)> {
+        match *self {
+            Input::Str(s) => s[pos..].chars().next().map(|c| (c, pos + c.len_utf8())),
+            Input::Chars(chars) => chars.get(pos).map(|&c| (c, pos + 1))
+        }
+    }
+
+    /**
+     * Returns the char before pos and the position of it, or None at the
+     * start of the input.
+     */
+    fn prev(&self, pos : usize) -> Option<(char, usize)> {
+        match *self {
+            Input::Str(s) => s[..pos].chars().next_back().map(|c| (c, pos - c.len_utf8())),
+            Input::Chars(chars) => {
+                if pos == 0 { None } else { Some((chars[pos - 1], pos - 1)) }
+            }
+        }
+    }
+
+    /**
+     * Returns the position after lit, if the input has it at pos.
+     */
+    fn starts_with(&self, pos : usize, lit : &str) -> Option<usize> {
+        match *self {
+            Input::Str(s) => {
+                if s[pos..].starts_with(lit) { Some(pos + lit.len()) } else { None }
+            }
+            Input::Chars(chars) => {
+                let mut end = pos;
+                for c in lit.chars() {
+                    if chars.get(end) != Some(&c) {
+                        return None;
+                    }
+                    end += 1;
+                }
+                Some(end)
+            }
+        }
+    }
+
+    /// Returns the string between two positions.
+    fn slice(&self, start : usize, end : usize) -> String {
+        match *self {
+            Input::Str(s) => s[start..end].to_string(),
+            Input::Chars(chars) => chars[start..end].iter().collect()
+        }
+    }
+}
+
+/**
+ * The most states the automaton built by Regex::is_match_dfa can have. A
+ * pattern needing more, like one with large nested repeats, is rejected.
+ */
+pub const DFA_STATE_LIMIT : usize = 10000;
+
+/// A state of the automaton built by Regex::is_match_dfa.
+enum NfaState {
+    /// Takes a char in (or, if negated, not in) the ranges, then goes to next.
+    Step { ranges : Vec<(char, char)>, negated : bool, next : usize },
+    /// Goes to each of the states without taking a char.
+    Split(Vec<usize>),
+    /// Goes to next without taking a char, if the anchor holds.
+    Assert { anchor : Anchor, next : usize },
+    /// The match is complete.
+    Match
+}
+
+/**
+ * A nondeterministic automaton for a regex, built from its tree. It's run
+ * by tracking every state it could be in at once, taking each char of the
+ * input once, so it never backtracks.
+ */
+struct Nfa {
+    states : Vec<NfaState>,
+    /// The state a match starts in.
+    start : usize
+}
+
+/// Where in the input a set of automaton states is, for checking anchors.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct NfaContext {
+    /// Whether this is the start of the input.
+    at_start : bool,
+    /// Whether this is the end of the input.
+    at_end : bool,
+    /// Whether only a '\n' is left in the input.
+    before_final_newline : bool
+}
+
+impl Nfa {
+    /**
+     * Builds the automaton for a tree. Returns an error naming what isn't
+     * supported if the tree uses something that needs backtracking or
+     * lookaround, or it would need more than DFA_STATE_LIMIT states.
+     */
+    fn new(ast : &Ast) -> Result<Nfa, MatchError> {
+        // The match is state 0, so it sorts first in a set of states.
+        let mut nfa = Nfa {
+            states : vec!(NfaState::Match),
+            start : 0
+        };
+        nfa.start = nfa.compile(ast, 0)?;
+        Ok(nfa)
+    }
+
+    /// Adds a state, returning its index.
+    fn push(&mut self, state : NfaState) -> Result<usize, MatchError> {
+        if self.states.len() >= DFA_STATE_LIMIT {
+            return Err(MatchError::Unsupported { construct : "a pattern this large" });
+        }
+        self.states.push(state);
+        Ok(self.states.len() - 1)
+    }
+
+    /**
+     * Adds the states matching a tree, and returns the first of them.
+     *
+     * * ast  - the tree to match
+     * * next - the state to go to once the tree has matched
+     */
+    fn compile(&mut self, ast : &Ast, next : usize) -> Result<usize, MatchError> {
+        let unsupported = |construct| Err(MatchError::Unsupported { construct : construct });
+        match *ast {
+            Ast::Char(c) => self.push(NfaState::Step {
+                ranges : vec!((c, c)),
+                negated : false,
+                next : next
+            }),
+            Ast::Any => self.push(NfaState::Step {
+                ranges : vec!(('\n', '\n')),
+                negated : true,
+                next : next
+            }),
+            Ast::Class { ref ranges, negated } => self.push(NfaState::Step {
+                ranges : ranges.clone(),
+                negated : negated,
+                next : next
+            }),
+            Ast::Anchor(Anchor::WordBoundary) | Ast::Anchor(Anchor::NotWordBoundary) => {
+                unsupported("word boundaries")
+            }
+            Ast::Anchor(anchor) => self.push(NfaState::Assert {
+                anchor : anchor,
+                next : next
+            }),
+            Ast::Concat(ref asts) => {
+                let mut next = next;
+                for ast in asts.iter().rev() {
+                    next = self.compile(ast, next)?;
+                }
+                Ok(next)
+            }
+            Ast::Alternation(ref asts) => {
+                let alts = asts.iter().map(|a| self.compile(a, next)).collect::<Result<_, _>>()?;
+                self.push(NfaState::Split(alts))
+            }
+            Ast::Group { ref ast, .. } => self.compile(ast, next),
+            Ast::Repeat { possessive : true, .. } => unsupported("possessive quantifiers"),
+            Ast::Repeat { min, max, ref ast, .. } => {
+                // The optional repetitions after the first min, built from
+                // the last one back.
+                let mut rest = match max {
+                    Some(max) => {
+                        let mut rest = next;
+                        for _ in min..max {
+                            let body = self.compile(ast, rest)?;
+                            rest = self.push(NfaState::Split(vec!(body, next)))?;
+                        }
+                        rest
+                    }
+                    None => {
+                        let split = self.push(NfaState::Split(Vec::new()))?;
+                        let body = self.compile(ast, split)?;
+                        self.states[split] = NfaState::Split(vec!(body, next));
+                        split
+                    }
+                };
+                for _ in 0..min {
+                    rest = self.compile(ast, rest)?;
+                }
+                Ok(rest)
+            }
+            Ast::Backref { .. } => unsupported("backreferences"),
+            Ast::Conditional { .. } => unsupported("conditionals"),
+            Ast::Lookbehind { .. } => unsupported("lookbehinds")
+        }
+    }
+
+    /**
+     * Adds a state, and every state reachable from it without taking a char,
+     * to set. Only states that take a char, or are the match, are kept.
+     */
+    fn add_closure(&self, state : usize, cx : NfaContext,
+                   seen : &mut Vec<bool>, set : &mut Vec<usize>) {
+        if seen[state] {
+            return;
+        }
+        seen[state] = true;
+
+        match self.states[state] {
+            NfaState::Step { .. } | NfaState::Match => { set.push(state); }
+            NfaState::Split(ref nexts) => {
+                for &next in nexts {
+                    self.add_closure(next, cx, seen, set);
+                }
+            }
+            NfaState::Assert { anchor, next } => {
+                let holds = match anchor {
+                    Anchor::Start | Anchor::TextStart | Anchor::SearchStart => cx.at_start,
+                    Anchor::End | Anchor::TextEnd => cx.at_end,
+                    Anchor::EndBeforeNewline => cx.at_end || cx.before_final_newline,
+                    Anchor::WordBoundary | Anchor::NotWordBoundary => false
+                };
+                if holds {
+                    self.add_closure(next, cx, seen, set);
+                }
+            }
+        }
+    }
+
+    /**
+     * Returns the set of states after taking c from the states in set, with
+     * a new attempt started, since a match can begin anywhere.
+     */
+    fn step(&self, set : &[usize], c : Option<char>, cx : NfaContext) -> Vec<usize> {
+        let mut seen = vec!(false; self.states.len());
+        let mut out = Vec::new();
+        if let Some(c) = c {
+            for &state in set {
+                if let NfaState::Step { ref ranges, negated, next } = self.states[state] {
+                    if ranges_contain(ranges, c) != negated {
+                        self.add_closure(next, cx, &mut seen, &mut out);
+                    }
+                }
+            }
+        }
+        self.add_closure(self.start, cx, &mut seen, &mut out);
+        out.sort();
+        out
+    }
+
+    /**
+     * Returns the states reachable from those in raw without taking a char
+     * (see add_closure), sorted.
+     */
+    fn closure(&self, raw : &[usize], cx : NfaContext) -> Vec<usize> {
+        let mut seen = vec!(false; self.states.len());
+        let mut set = Vec::new();
+        for &state in raw {
+            self.add_closure(state, cx, &mut seen, &mut set);
+        }
+        set.sort();
+        set
+    }
+
+    /**
+     * Returns the states taking c leads to from those in set, before their
+     * closure is taken. Unlike step, no new attempt is started.
+     */
+    fn take(&self, set : &[usize], c : char) -> Vec<usize> {
+        let mut raw = Vec::new();
+        for &state in set {
+            if let NfaState::Step { ref ranges, negated, next } = self.states[state] {
+                if ranges_contain(ranges, c) != negated {
+                    raw.push(next);
+                }
+            }
+        }
+        raw.sort();
+        raw.dedup();
+        raw
+    }
+
+    /**
+     * Returns whether some str is matched as a whole by both this automaton
+     * and other, or None if finding out takes more than DFA_STATE_LIMIT
+     * pairs of state sets.
+     *
+     * Every input is explored at once: from each pair of state sets, the
+     * chars are split into runs that every state treats alike, and one char
+     * of each run is taken. The anchors are checked with their context as
+     * the input ending there, or ending with a '\n' there, or going on.
+     */
+    fn intersects(&self, other : &Nfa) -> Option<bool> {
+        let both_match = |mine : &[usize], theirs : &[usize]| {
+            mine.first() == Some(&0) && theirs.first() == Some(&0)
+        };
+        let plain = NfaContext { at_start : false, at_end : false, before_final_newline : false };
+        let end = NfaContext { at_end : true, ..plain };
+
+        let start = (vec!(self.start), vec!(other.start), true);
+        let mut seen = HashSet::new();
+        seen.insert(start.clone());
+        let mut queue = vec!(start);
+        while let Some((mine, theirs, at_start)) = queue.pop() {
+            // The input could end here...
+            let cx = NfaContext { at_start : at_start, ..end };
+            if both_match(&self.closure(&mine, cx), &other.closure(&theirs, cx)) {
+                return Some(true);
+            }
+
+            // ... or with just a '\n' after here...
+            let cx = NfaContext { at_start : at_start, before_final_newline : true, ..plain };
+            let (my_set, their_set) = (self.closure(&mine, cx), other.closure(&theirs, cx));
+            if both_match(&self.closure(&self.take(&my_set, '\n'), end),
+                          &other.closure(&other.take(&their_set, '\n'), end)) {
+                return Some(true);
+            }
+
+            // ... or go on, with any char.
+            let cx = NfaContext { at_start : at_start, ..plain };
+            let (my_set, their_set) = (self.closure(&mine, cx), other.closure(&theirs, cx));
+            let mut firsts = BTreeSet::new();
+            firsts.insert('\0');
+            let states = my_set.iter().map(|&state| &self.states[state])
+                .chain(their_set.iter().map(|&state| &other.states[state]));
+            for state in states {
+                if let NfaState::Step { ref ranges, .. } = *state {
+                    for &(lo, hi) in ranges {
+                        firsts.insert(lo);
+                        firsts.extend(next_char(hi));
+                    }
+                }
+            }
+
+            for c in firsts {
+                let next = (self.take(&my_set, c), other.take(&their_set, c), false);
+                if next.0.is_empty() || next.1.is_empty() || seen.contains(&next) {
+                    continue;
+                }
+                if seen.len() >= DFA_STATE_LIMIT {
+                    return None;
+                }
+                seen.insert(next.clone());
+                queue.push(next);
+            }
+        }
+
+        Some(false)
+    }
+
+    /**
+     * Returns whether the automaton matches anywhere in s. Each set of
+     * states seen is numbered, and the transitions between them are cached,
+     * so each char of s takes constant time once the sets it can lead to
+     * have been seen.
+     */
+    fn is_match(&self, s : &str) -> bool {
+        let context = |pos : usize| NfaContext {
+            at_start : pos == 0,
+            at_end : pos == s.len(),
+            before_final_newline : &s[pos..] == "\n"
+        };
+        let plain = NfaContext { at_start : false, at_end : false, before_final_newline : false };
+
+        let mut ids : HashMap<Vec<usize>, usize> = HashMap::new();
+        let mut sets : Vec<Vec<usize>> = Vec::new();
+        let mut transitions : HashMap<(usize, char), usize> = HashMap::new();
+        let mut intern = |set : Vec<usize>, sets : &mut Vec<Vec<usize>>| {
+            *ids.entry(set.clone()).or_insert_with(|| {
+                sets.push(set);
+                sets.len() - 1
+            })
+        };
+
+        let mut current = intern(self.step(&[], None, context(0)), &mut sets);
+        for (pos, c) in s.char_indices() {
+            if sets[current].first() == Some(&0) {
+                return true;
+            }
+            let next_pos = pos + c.len_utf8();
+            let cx = context(next_pos);
+            current = if cx == plain {
+                match transitions.get(&(current, c)) {
+                    Some(&next) => next,
+                    None => {
+                        let next = intern(self.step(&sets[current], Some(c), cx), &mut sets);
+                        transitions.insert((current, c), next);
+                        next
+                    }
+                }
+            } else {
+                intern(self.step(&sets[current], Some(c), cx), &mut sets)
+            };
+        }
+        sets[current].first() == Some(&0)
+    }
+}
+
+/// The state of a match in progress.
+struct Matcher<'a> {
+    /// The input being matched.
+    input : Input<'a>,
+    /// The span (start and end position) of each group matched so far,
+    /// indexed by group number.
+    groups : Vec<Option<(usize, usize)>>,
+    /// Whether to treat every quantifier as lazy.
+    lazy : bool,
+    /// If capture history is on, the span of every match of each group so
+    /// far, indexed by group number.
+    history : Option<Vec<Vec<(usize, usize)>>>,
+    /// The time to give up by, if any.
+    deadline : Option<Instant>,
+    /// The number of steps taken, counted to know when to check the clock.
+    steps : usize,
+    /// The most repetitions the quantifiers may attempt, if there's a limit.
+    repeat_limit : Option<usize>,
+    /// The number of repetitions attempted.
+    repeats : usize,
+    /// Why the match is being abandoned, if it is: the deadline passed or
+    /// the repeat limit was reached.
+    abandoned : Option<MatchError>,
+    /// The position \\G matches at: where the search started, or where the
+    /// previous match ended when iterating over matches.
+    search_start : usize,
+    /// The furthest position the match has reached, if that's being tracked
+    /// (for Regex::longest_prefix_match).
+    furthest : Option<usize>,
+    /// The predicate for word chars, if the regex was built with one.
+    is_word : Option<Arc<WordFn>>,
+    /// Whether the default word chars are only the ASCII ones.
+    ascii : bool,
+    /// Whether alternations try the alternative that gets furthest first.
+    longest_alt : bool,
+    /// The only groups (besides group 0) to report, if not all of them.
+    capture_only : Option<Arc<BTreeSet<usize>>>
+}
+
+/// A predicate for which chars are word chars, for \\b and \\w.
+type WordFn = dyn Fn(char) -> bool + Send + Sync;
+
+/// The number of steps to take between checks of the clock.
+const STEPS_PER_CLOCK_CHECK : usize = 1024;
+
+/**
+ * The groups matched so far, saved to be put back after a failed attempt.
+ * Matching only adds to the capture history (and takes back what it added
+ * when it fails), so the length of each group's history is enough to undo
+ * what's been added since.
+ */
+type SavedGroups = (Vec<Option<(usize, usize)>>, Option<Vec<usize>>);
+
+/// The groups as some match left them, and what it added to the capture
+/// history, to redo the match's effect after it's been undone.
+type GroupChanges = (Vec<Option<(usize, usize)>>, Option<Vec<Vec<(usize, usize)>>>);
+
+/// A continuation. Called with the position after a node's match to match
+/// the rest of the regex, and returns whether that succeeded.
+type Cont<'a, 'k> = dyn FnMut(&mut Matcher<'a>, usize) -> bool + 'k;
+
+impl<'a> Matcher<'a> {
+    fn new(input : Input<'a>, num_groups : usize) -> Self {
+        Matcher {
+            input : input,
+            groups : vec!(None; num_groups),
+            lazy : false,
+            history : None,
+            deadline : None,
+            steps : 0,
+            repeat_limit : None,
+            repeats : 0,
+            abandoned : None,
+            search_start : 0,
+            furthest : None,
+            is_word : None,
+            ascii : false,
+            longest_alt : false,
+            capture_only : None
+        }
+    }
+
+    /// Returns whether group num is reported in match results.
+    fn reports(&self, num : usize) -> bool {
+        num == 0 || self.capture_only.as_ref().is_none_or(|only| only.contains(&num))
+    }
+
+    /// Returns the span of each group, or None for groups that didn't
+    /// participate or aren't reported.
+    fn reported_spans(&self) -> impl Iterator<Item=Option<(usize, usize)>> + '_ {
+        self.groups.iter().enumerate().map(move |(num, &span)| span.filter(|_| self.reports(num)))
+    }
+
+    /// Returns whether c is a word char.
+    fn is_word_char(&self, c : char) -> bool {
+        match self.is_word {
+            Some(ref is_word) => is_word(c),
+            None if self.ascii => ranges_contain(WORD_RANGES, c),
+            None => ranges_contain(word_ranges(), c)
+        }
+    }
+
+    /// Returns whether pos is between a word char and a non-word char, or
+    /// the start or end of the input.
+    fn at_word_boundary(&self, pos : usize) -> bool {
+        let before = self.input.prev(pos).is_some_and(|(c, _)| self.is_word_char(c));
+        let after = self.next(pos).is_some_and(|(c, _)| self.is_word_char(c));
+        before != after
+    }
+
+    /// Notes that the match has reached pos, if progress is being tracked.
+    fn reached(&mut self, pos : usize) {
+        if let Some(ref mut furthest) = self.furthest {
+            *furthest = cmp::max(*furthest, pos);
+        }
+    }
+
+    /**
+     * Counts a step of the match, and returns whether to carry on, which is
+     * false once the deadline (if any) has passed or the match has been
+     * abandoned for another reason. Nodes that can backtrack call this
+     * before each attempt, so that an abandoned match fails fast.
+     */
+    fn tick(&mut self) -> bool {
+        if self.abandoned.is_some() {
+            return false;
+        }
+        if let Some(deadline) = self.deadline {
+            self.steps += 1;
+            if self.steps.is_multiple_of(STEPS_PER_CLOCK_CHECK) && Instant::now() >= deadline {
+                self.abandoned = Some(MatchError::Timeout);
+                return false;
+            }
+        }
+        true
+    }
+
+    /**
+     * Counts an attempt at a repetition, and returns whether to carry on,
+     * which is false once the repeat limit (if any) is passed.
+     */
+    fn repeat(&mut self) -> bool {
+        self.repeats += 1;
+        if let Some(limit) = self.repeat_limit {
+            if self.repeats > limit {
+                self.abandoned = Some(MatchError::RepeatLimitExceeded { limit : limit });
+            }
+        }
+        self.abandoned.is_none()
+    }
+
+    /// Saves the groups matched so far, to be put back by restore_groups.
+    fn save_groups(&self) -> SavedGroups {
+        let lens = self.history.as_ref().map(|history| history.iter().map(Vec::len).collect());
+        (self.groups.clone(), lens)
+    }
+
+    /// Puts back groups saved by save_groups, dropping the capture history
+    /// added since.
+    fn restore_groups(&mut self, saved : SavedGroups) {
+        self.groups = saved.0;
+        if let (Some(history), Some(lens)) = (self.history.as_mut(), saved.1) {
+            for (spans, len) in history.iter_mut().zip(lens) {
+                spans.truncate(len);
+            }
+        }
+    }
+
+    /// Returns how the groups have changed since they were saved.
+    fn changes_since(&self, saved : &SavedGroups) -> GroupChanges {
+        let added = match (self.history.as_ref(), saved.1.as_ref()) {
+            (Some(history), Some(lens)) => Some(history.iter().zip(lens).map(|(spans, &len)| {
+                spans[len..].to_vec()
+            }).collect()),
+            _ => None
+        };
+        (self.groups.clone(), added)
+    }
+
+    /// Puts back groups saved by save_groups, then makes the changes found
+    /// by changes_since from them.
+    fn redo_changes(&mut self, saved : &SavedGroups, changes : &GroupChanges) {
+        self.restore_groups(saved.clone());
+        self.groups.clone_from(&changes.0);
+        if let (Some(history), Some(added)) = (self.history.as_mut(), changes.1.as_ref()) {
+            for (spans, added) in history.iter_mut().zip(added) {
+                spans.extend_from_slice(added);
+            }
+        }
+    }
+
+    /**
+     * Returns the char at pos and the position after it, or None at the end
+     * of the input.
+     */
+    fn next(&self, pos : usize) -> Option<(char, usize)> {
+        self.input.next(pos)
+    }
+
+    /**
+     * Returns the groups matched, with the given group names.
+     */
+    fn captures(&self, names : &[Option<String>]) -> Captures {
+        // Without capture history, each group's history is just its last
+        // match, if any.
+        let slice = |&(start, end) : &(usize, usize)| self.input.slice(start, end);
+        let spans : Vec<Option<(usize, usize)>> = self.reported_spans().collect();
+        let history = match self.history {
+            Some(ref history) => history.iter().enumerate().map(|(num, spans)| {
+                if self.reports(num) {
+                    spans.iter().map(slice).collect()
+                } else {
+                    Vec::new()
+                }
+            }).collect(),
+            None => spans.iter().map(|span| span.iter().map(slice).collect()).collect()
+        };
+
+        Captures {
+            groups : self.match_result(),
+            names : names.to_vec(),
+            history : history,
+            spans : spans
+        }
+    }
+
+    /**
+     * Returns the string matched by each group that participated.
+     */
+    fn match_result(&self) -> MatchResult {
+        let mut mr = MatchResult::new();
+        for (num, span) in self.reported_spans().enumerate() {
+            if let Some((start, end)) = span {
+                mr.insert(num, self.input.slice(start, end));
+            }
+        }
+        mr
+    }
+
+    /**
+     * Overwrites mr with the groups matched, reusing the strings already in
+     * it where it can. Groups that didn't participate are removed.
+     */
+    fn fill_match_result(&self, mr : &mut MatchResult) {
+        for (num, span) in self.reported_spans().enumerate() {
+            match span {
+                Some((start, end)) => {
+                    let text = mr.entry(num).or_default();
+                    text.clear();
+                    match self.input {
+                        Input::Str(s) => text.push_str(&s[start..end]),
+                        Input::Chars(chars) => text.extend(&chars[start..end])
+                    }
+                }
+                None => {
+                    mr.remove(&num);
+                }
+            }
+        }
+    }
+}
+
+/// Interface for regex tree nodes. Nodes are shared between threads along
+/// with the regexes they make up.
+trait Node : Send + Sync {
+    /**
+     * Matches this node against (part of) the input, starting at pos. For
+     * each way the node can match, in order of preference, calls k with the
+     * position after the match, stopping once k succeeds. Trying the next
+     * way when k fails is how the matcher backtracks.
+     *
+     * Returns whether k succeeded.
+     *
+     * * m   - the input and the groups matched so far
+     * * pos - current position in the input
+     * * k   - matches the rest of the regex
+     */
+    fn match_chars<'a>(&self, m : &mut Matcher<'a>, pos : usize, k : &mut Cont<'a, '_>) -> bool;
+
+    /**
+     * Returns whether this node always matches exactly one char, in at most
+     * one way, without recording groups. Repeats of such nodes are matched
+     * without recursing for each repetition.
+     */
+    fn is_simple(&self) -> bool {
+        false
+    }
+
+    /**
+     * Returns the text this node matches, if it only matches literal chars.
+     */
+    fn as_literal(&self) -> Option<String> {
+        None
+    }
+
+    /**
+     * Returns the nodes of this node, if it's a sequence.
+     */
+    fn seq_nodes(&self) -> Option<&[Arc<dyn Node>]> {
+        None
+    }
+
+    /**
+     * Prints this node in normal regex syntax.
+     */
+    fn debug(&self) -> String;
+
+    /**
+     * Returns a copy of the structure of this node.
+     */
+    fn ast(&self) -> Ast;
+}
+
+/// Represents an alternation.
+struct AltNode {
+    /// A vec of alternative sequences.
+    alts : Vec<SeqNode>
+}
+
+/// Represents a backreference.
+struct BackrefNode {
+    /// The number of the group whose text is matched.
+    num : usize,
+    /// The name the group was referred to by, if any.
+    name : Option<String>,
+    /// Whether the text may be matched in any case, as under the i flag.
+    case_insensitive : bool
+}
+
+/// Represents an anchor, which matches no chars, but only at some positions.
+struct AnchorNode {
+    /// The position this anchor matches at.
+    anchor : Anchor
+}
+
+/// Represents a '.', which matches any char but '\n'.
+struct AnyNode;
+
+/// Represents a '.' in grapheme mode, which matches a grapheme cluster not
+/// starting with '\n' (unless the s flag is on).
+struct GraphemeNode {
+    /// Whether a cluster can be a '\n', as with the s flag.
+    newline : bool
+}
+
+/// Represents a "\\w" or "\\W" in a regex built with a word char
+/// predicate, which matches a char the predicate accepts (or rejects).
+struct WordNode {
+    /// Whether this is "\\W", matching the chars that aren't word chars.
+    negated : bool
+}
+
+/// Represents a char literal.
+struct CharNode {
+    /// The char literal this node represents.
+    c : char
+}
+
+/// A set of chars, stored as sorted ranges that neither overlap nor touch.
+#[derive(Clone)]
+struct CharSet {
+    /// Inclusive ranges of chars in the set.
+    ranges : Vec<(char, char)>
+}
+
+/// Represents a character class.
+struct CharClassNode {
+    /// Elements matched by this class.
+    set : CharSet,
+    /// Whether the class is negated.
+    negated : bool
+}
+
+/// Represents a conditional, which matches one of two sequences depending on
+/// whether a group has participated in the match so far.
+struct CondNode {
+    /// The number of the group to test.
+    num : usize,
+    /// The sequence matched if the group participated.
+    yes : SeqNode,
+    /// The sequence matched if it didn't.
+    no : SeqNode
+}
+
+/// Represents a group.
+struct GrpNode {
+    /// The number of this group.
+    num : usize,
+    /// The name of this group, if it has one.
+    name : Option<String>,
+    /// The list of alternative sequences.
+    alt : AltNode
+}
+
+/// Represents a lookbehind.
+struct LookbehindNode {
+    /// The alternatives that must match just before the current position.
+    alt : AltNode,
+    /// The number of chars every alternative matches.
+    len : usize,
+    /// Whether the alternatives must not match, rather than match.
+    negated : bool
+}
+
+/// Represents a run of char literals, compared against the input all at once
+/// rather than char by char.
+struct LiteralNode {
+    /// The literal text, at least two chars.
+    s : String
+}
+
+/// Represents a ?.
+struct OptNode {
+    /// The node that may be skipped.
+    node : Arc<dyn Node>,
+    /// Whether to prefer matching the node over skipping it.
+    greedy : bool
+}
+
+/// Represents a possessive quantifier, which matches its repeat greedily
+/// and never gives back what it matched.
+struct PossessiveNode {
+    /// The repeat (an OptNode or RptNode) to match possessively.
+    node : Arc<dyn Node>
+}
+
+/// Represents a *, + or {n,m}.
+struct RptNode {
+    /// The node to be repeated.
+    node : Arc<dyn Node>,
+    /// The fewest times the node must match (0 for *, 1 for +).
+    min : usize,
+    /// The most times the node may match, or None for no limit.
+    max : Option<usize>,
+    /// Whether to prefer more repetitions over fewer.
+    greedy : bool
+}
+
+/// A repetition in progress of a RptNode, kept while matching the rest.
+struct RptFrame {
+    /// Where the repetitions so far ended.
+    pos : usize,
+    /// The groups matched by the repetitions so far.
+    groups : SavedGroups,
+    /// The ways the next repetition can end that are yet to be tried, last
+    /// first, with how each changes the groups.
+    ends : Vec<(usize, GroupChanges)>,
+    /// Whether to match the rest of the regex from pos once they fail.
+    then_k : bool
+}
+
+/// Represents a sequence.
+struct SeqNode {
+    /// Nodes that together form a sequence.
+    nodes : Vec<Arc<dyn Node>>
+}
+
+impl Node for AltNode {
+    fn match_chars<'a>(&self, m : &mut Matcher<'a>, pos : usize, k : &mut Cont<'a, '_>) -> bool {
+        if m.longest_alt && self.alts.len() > 1 {
+            return self.match_longest(m, pos, k);
+        }
+
+        // Try each alternative, in order. If the rest of the regex fails
+        // after one, backtrack and try the next.
+        for alt in &self.alts {
+            if alt.match_chars(m, pos, k) {
+                return true;
+            }
+        }
+
+        return false;
+    }
+
+    fn debug(&self) -> String {
+        let mut s = String::new();
+
+        for alt in &self.alts {
+            s = s + &alt.debug();
+            s.push('|');
+        }
+        s.pop();
+
+        return s;
+    }
+
+    fn ast(&self) -> Ast {
+        if self.alts.len() == 1 {
+            self.alts[0].ast()
+        } else {
+            Ast::Alternation(self.alts.iter().map(|alt| alt.ast()).collect())
+        }
+    }
+}
+
+impl Node for BackrefNode {
+    fn match_chars<'a>(&self, m : &mut Matcher<'a>, pos : usize, k : &mut Cont<'a, '_>) -> bool {
+        // A group that hasn't participated matches nothing, so neither does
+        // a reference to it.
+        let (start, end) = match m.groups.get(self.num) {
+            Some(&Some(span)) => span,
+            _ => { return false; }
+        };
+
+        // Compare the group's text with the input, char by char.
+        let mut i = start;
+        let mut j = pos;
+        while i < end {
+            match (m.next(i), m.next(j)) {
+                (Some((a, next_i)), Some((b, next_j))) if self.same_char(m, a, b) => {
+                    i = next_i;
+                    j = next_j;
+                }
+                _ => { return false; }
+            }
+        }
+
+        k(m, j)
+    }
+
+    fn debug(&self) -> String {
+        match self.name {
+            Some(ref name) => format!("\\k<{}>", name),
+            None => format!("\\{}", self.num)
+        }
+    }
+
+    fn ast(&self) -> Ast {
+        Ast::Backref {
+            num : self.num,
+            name : self.name.clone(),
+            case_insensitive : self.case_insensitive
+        }
+    }
+}
+
+impl BackrefNode {
+    /**
+     * Returns whether the char b in the input matches the char a in the
+     * group's text. Under the i flag, other cases match too, found the same
+     * way as for chars in the pattern (only ASCII ones in ASCII mode).
+     */
+    fn same_char(&self, m : &Matcher, a : char, b : char) -> bool {
+        if a == b {
+            return true;
+        }
+        if !self.case_insensitive {
+            return false;
+        }
+        if m.ascii {
+            return a.eq_ignore_ascii_case(&b);
+        }
+        CharSet { ranges : vec!((a, a)) }.case_fold().contains(b)
+    }
+}
+
+impl Node for AnchorNode {
+    fn match_chars<'a>(&self, m : &mut Matcher<'a>, pos : usize, k : &mut Cont<'a, '_>) -> bool {
+        let at = match self.anchor {
+            Anchor::Start | Anchor::TextStart => pos == 0,
+            Anchor::End | Anchor::TextEnd => m.next(pos).is_none(),
+            Anchor::EndBeforeNewline => match m.next(pos) {
+                Some(('\n', next)) => m.next(next).is_none(),
+                Some(_) => false,
+                None => true
+            },
+            Anchor::SearchStart => pos == m.search_start,
+            Anchor::WordBoundary => m.at_word_boundary(pos),
+            Anchor::NotWordBoundary => !m.at_word_boundary(pos)
+        };
+        at && k(m, pos)
+    }
+
+    fn debug(&self) -> String {
+        self.ast().to_string()
+    }
+
+    fn ast(&self) -> Ast {
+        Ast::Anchor(self.anchor)
+    }
+}
+
+impl Node for AnyNode {
+    fn match_chars<'a>(&self, m : &mut Matcher<'a>, pos : usize, k : &mut Cont<'a, '_>) -> bool {
+        match m.next(pos) {
+            Some((c, next)) if c != '\n' => { k(m, next) }
+            _ => { false }
+        }
+    }
+
+    fn is_simple(&self) -> bool {
+        true
+    }
+
+    fn debug(&self) -> String {
+        ".".to_string()
+    }
+
+    fn ast(&self) -> Ast {
+        Ast::Any
+    }
+}
+
+impl Node for GraphemeNode {
+    fn match_chars<'a>(&self, m : &mut Matcher<'a>, pos : usize, k : &mut Cont<'a, '_>) -> bool {
+        let (c, mut end) = match m.next(pos) {
+            Some((c, next)) if self.newline || c != '\n' => (c, next),
+            _ => { return false; }
+        };
+
+        // A flag is made of two regional indicators.
+        if is_regional_indicator(c) {
+            if let Some((c, next)) = m.next(end) {
+                if is_regional_indicator(c) {
+                    end = next;
+                }
+            }
+        }
+
+        // Take the marks extending the cluster, and anything joined onto it.
+        while let Some((c, next)) = m.next(end) {
+            if c == '\u{200D}' {
+                end = m.next(next).map_or(next, |(_, after)| after);
+            } else if is_grapheme_extend(c) {
+                end = next;
+            } else {
+                break;
+            }
+        }
+
+        k(m, end)
+    }
+
+    fn debug(&self) -> String {
+        ".".to_string()
+    }
+
+    fn ast(&self) -> Ast {
+        if self.newline {
+            // Any char at all, as the s flag's '.' is.
+            Ast::Class { ranges : Vec::new(), negated : true }
+        } else {
+            Ast::Any
+        }
+    }
+}
+
+impl Node for WordNode {
+    fn match_chars<'a>(&self, m : &mut Matcher<'a>, pos : usize, k : &mut Cont<'a, '_>) -> bool {
+        match m.next(pos) {
+            Some((c, next)) if m.is_word_char(c) != self.negated => { k(m, next) }
+            _ => { false }
+        }
+    }
+
+    fn is_simple(&self) -> bool {
+        true
+    }
+
+    fn debug(&self) -> String {
+        if self.negated { "\\W".to_string() } else { "\\w".to_string() }
+    }
+
+    fn ast(&self) -> Ast {
+        Ast::Class {
+            ranges : word_ranges().to_vec(),
+            negated : self.negated
+        }
+    }
+}
+
+/// Returns whether c is a regional indicator, half of a flag.
+fn is_regional_indicator(c : char) -> bool {
+    ('\u{1F1E6}'..='\u{1F1FF}').contains(&c)
+}
+
+/// Returns whether c extends the grapheme cluster before it, rather than
+/// starting a new one.
+fn is_grapheme_extend(c : char) -> bool {
+    const EXTEND_RANGES : &[(char, char)] = &[
+        ('\u{300}', '\u{36F}'),     // Combining diacritical marks
+        ('\u{1AB0}', '\u{1AFF}'),   // Combining diacritical marks extended
+        ('\u{1DC0}', '\u{1DFF}'),   // Combining diacritical marks supplement
+        ('\u{20D0}', '\u{20FF}'),   // Combining diacritical marks for symbols
+        ('\u{FE00}', '\u{FE0F}'),   // Variation selectors
+        ('\u{FE20}', '\u{FE2F}'),   // Combining half marks
+        ('\u{1F3FB}', '\u{1F3FF}'), // Emoji skin tone modifiers
+        ('\u{E0020}', '\u{E007F}'), // Tags
+        ('\u{E0100}', '\u{E01EF}')  // Variation selectors supplement
+    ];
+    EXTEND_RANGES.iter().any(|&(lo, hi)| lo <= c && c <= hi)
+}
+
+impl Node for CharNode {
+    fn match_chars<'a>(&self, m : &mut Matcher<'a>, pos : usize, k : &mut Cont<'a, '_>) -> bool {
+        match m.next(pos) {
+            Some((c, next)) if c == self.c => { k(m, next) }
+            _ => { false }
+        }
+    }
+
+    fn is_simple(&self) -> bool {
+        true
+    }
+
+    fn as_literal(&self) -> Option<String> {
+        Some(self.c.to_string())
+    }
+
+    fn debug(&self) -> String {
+        let mut s = String::new();
+        s.push_str("Char{");
+        s.push(self.c);
+        s.push('}');
+        return s;
+    }
+
+    fn ast(&self) -> Ast {
+        Ast::Char(self.c)
+    }
+}
+
+impl Node for CharClassNode {
+    fn match_chars<'a>(&self, m : &mut Matcher<'a>, pos : usize, k : &mut Cont<'a, '_>) -> bool {
+        match m.next(pos) {
+            Some((c, next)) if self.set.contains(c) != self.negated => { k(m, next) }
+            _ => { false }
+        }
+    }
+
+    fn is_simple(&self) -> bool {
+        true
+    }
+
+    fn debug(&self) -> String {
+        let mut s = String::new();
+        s.push('[');
+
+        if self.negated {
+            s.push('^');
+        }
+
+        for &(lo, hi) in &self.set.ranges {
+            s.push(lo);
+            if hi != lo {
+                s.push('-');
+                s.push(hi);
+            }
+        }
+
+        s.push(']');
+        s
+    }
+
+    fn ast(&self) -> Ast {
+        Ast::Class {
+            ranges : self.set.ranges.clone(),
+            negated : self.negated
+        }
+    }
+}
+
+impl Node for CondNode {
+    fn match_chars<'a>(&self, m : &mut Matcher<'a>, pos : usize, k : &mut Cont<'a, '_>) -> bool {
+        let participated = m.groups.get(self.num).is_some_and(|span| span.is_some());
+        if participated {
+            self.yes.match_chars(m, pos, k)
+        } else {
+            self.no.match_chars(m, pos, k)
+        }
+    }
+
+    fn debug(&self) -> String {
+        format!("(?({}){}|{})", self.num, self.yes.debug(), self.no.debug())
+    }
+
+    fn ast(&self) -> Ast {
+        Ast::Conditional {
+            num : self.num,
+            yes : Box::new(self.yes.ast()),
+            no : Box::new(self.no.ast())
+        }
+    }
+}
+
+impl Node for GrpNode {
+    fn match_chars<'a>(&self, m : &mut Matcher<'a>, pos : usize, k : &mut Cont<'a, '_>) -> bool {
+        let num = self.num;
+        self.alt.match_chars(m, pos, &mut |m, end| {
+            // Record the group, but put back what was there before if the
+            // rest of the regex fails.
+            let prev = m.groups[num];
+            m.groups[num] = Some((pos, end));
+            if let Some(ref mut history) = m.history {
+                history[num].push((pos, end));
+            }
+
+            if k(m, end) {
+                true
+            } else {
+                m.groups[num] = prev;
+                if let Some(ref mut history) = m.history {
+                    history[num].pop();
+                }
+                false
+            }
+        })
+    }
+
+    fn debug(&self) -> String {
+        let mut s = String::new();
+
+        // Dont print parens around the entire regex, they are implicit.
+        if self.num == 0 {
+            s = self.alt.debug();
+        } else {
+            s.push('(');
+            if let Some(ref name) = self.name {
+                s = s + "?<" + name + ">";
+            }
+            s = s + &self.alt.debug();
+            s.push(')');
+        }
+        return s;
+    }
+
+    fn ast(&self) -> Ast {
+        Ast::Group {
+            num : self.num,
+            name : self.name.clone(),
+            ast : Box::new(self.alt.ast())
+        }
+    }
+}
+
+impl Node for LookbehindNode {
+    fn match_chars<'a>(&self, m : &mut Matcher<'a>, pos : usize, k : &mut Cont<'a, '_>) -> bool {
+        // Step back to where the body would have to start, then match it
+        // there, requiring that it end where we are now.
+        let mut start = Some(pos);
+        for _ in 0..self.len {
+            start = start.and_then(|start| m.input.prev(start)).map(|(_, prev)| prev);
+        }
+
+        if !self.negated {
+            return match start {
+                Some(start) => {
+                    self.alt.match_chars(m, start, &mut |m, end| end == pos && k(m, pos))
+                }
+                None => false
+            };
+        }
+
+        // A negative lookbehind only checks whether the body matches, so
+        // it leaves no groups behind.
+        let matched = match start {
+            Some(start) => {
+                let groups = m.save_groups();
+                let matched = self.alt.match_chars(m, start, &mut |_, end| end == pos);
+                m.restore_groups(groups);
+                matched
+            }
+            None => false
+        };
+
+        !matched && k(m, pos)
+    }
+
+    fn debug(&self) -> String {
+        format!("(?<{}{})", if self.negated { '!' } else { '=' }, self.alt.debug())
+    }
+
+    fn ast(&self) -> Ast {
+        Ast::Lookbehind {
+            negated : self.negated,
+            ast : Box::new(self.alt.ast())
+        }
+    }
+}
+
+impl Node for LiteralNode {
+    fn match_chars<'a>(&self, m : &mut Matcher<'a>, pos : usize, k : &mut Cont<'a, '_>) -> bool {
+        match m.input.starts_with(pos, &self.s) {
+            Some(end) => k(m, end),
+            None => {
+                if m.furthest.is_some() {
+                    // Note how much of the literal did match.
+                    let mut end = pos;
+                    for c in self.s.chars() {
+                        match m.next(end) {
+                            Some((d, next)) if d == c => { end = next; }
+                            _ => { break; }
+                        }
+                    }
+                    m.reached(end);
+                }
+                false
+            }
+        }
+    }
+
+    fn as_literal(&self) -> Option<String> {
+        Some(self.s.clone())
+    }
+
+    fn debug(&self) -> String {
+        format!("Lit{{{}}}", self.s)
+    }
+
+    fn ast(&self) -> Ast {
+        Ast::Concat(self.s.chars().map(Ast::Char).collect())
+    }
+}
+
+impl Node for OptNode {
+    fn match_chars<'a>(&self, m : &mut Matcher<'a>, pos : usize, k : &mut Cont<'a, '_>) -> bool {
+        // Try matching the node and skipping it, in order of preference.
+        let greedy = self.greedy && !m.lazy;
+        if greedy && self.node.match_chars(m, pos, k) {
+            return true;
+        }
+        if k(m, pos) {
+            return true;
+        }
+        return !greedy && self.node.match_chars(m, pos, k);
+    }
+
+    fn debug(&self) -> String {
+        return self.node.debug() + "?" + lazy_suffix(self.greedy);
+    }
+
+    fn ast(&self) -> Ast {
+        Ast::Repeat {
+            min : 0,
+            max : Some(1),
+            greedy : self.greedy,
+            possessive : false,
+            ast : Box::new(self.node.ast())
+        }
+    }
+}
+
+impl Node for PossessiveNode {
+    fn match_chars<'a>(&self, m : &mut Matcher<'a>, pos : usize, k : &mut Cont<'a, '_>) -> bool {
+        // Take the first way the node matches, and don't backtrack into it
+        // if the rest of the regex fails.
+        let groups = m.save_groups();
+        let mut end = None;
+        self.node.match_chars(m, pos, &mut |_, next| {
+            end = Some(next);
+            true
+        });
+
+        match end {
+            Some(end) if k(m, end) => true,
+            _ => {
+                m.restore_groups(groups);
+                false
+            }
+        }
+    }
+
+    fn debug(&self) -> String {
+        return self.node.debug() + "+";
+    }
+
+    fn ast(&self) -> Ast {
+        match self.node.ast() {
+            Ast::Repeat { min, max, ast, .. } => Ast::Repeat {
+                min : min,
+                max : max,
+                greedy : true,
+                possessive : true,
+                ast : ast
+            },
+            ast => ast
+        }
+    }
+}
+
+impl Node for RptNode {
+    fn match_chars<'a>(&self, m : &mut Matcher<'a>, pos : usize, k : &mut Cont<'a, '_>) -> bool {
+        if self.node.is_simple() {
+            self.match_simple(m, pos, k)
+        } else {
+            self.match_from(m, pos, k)
+        }
+    }
+
+    fn debug(&self) -> String {
+        return self.node.debug() + &quantifier(self.min, self.max) + lazy_suffix(self.greedy);
+    }
+
+    fn ast(&self) -> Ast {
+        Ast::Repeat {
+            min : self.min,
+            max : self.max,
+            greedy : self.greedy,
+            possessive : false,
+            ast : Box::new(self.node.ast())
+        }
+    }
+}
+
+impl Node for SeqNode {
+    fn match_chars<'a>(&self, m : &mut Matcher<'a>, pos : usize, k : &mut Cont<'a, '_>) -> bool {
+        self.match_from(0, m, pos, k)
+    }
+
+    fn debug(&self) -> String {
+        let mut s = String::new();
+
+        for n in &self.nodes {
+            s = s + &n.debug();
+        }
+
+        return s;
+    }
+
+    fn seq_nodes(&self) -> Option<&[Arc<dyn Node>]> {
+        Some(&self.nodes)
+    }
+
+    fn ast(&self) -> Ast {
+        // Literal runs are shown as the chars they're made of.
+        let mut asts = Vec::new();
+        for n in &self.nodes {
+            match n.as_literal() {
+                Some(s) => asts.extend(s.chars().map(Ast::Char)),
+                None => asts.push(n.ast())
+            }
+        }
+
+        if asts.len() == 1 {
+            asts.pop().expect("")
+        } else {
+            Ast::Concat(asts)
+        }
+    }
+}
+
+impl RptNode {
+    /**
+     * Matches repetitions of a node that may match more than one way. The
+     * ways each repetition can end are tried depth first, as recursing per
+     * repetition would, but the repetitions in progress are kept on the
+     * heap so that long repeats can't overflow the stack.
+     */
+    fn match_from<'a>(&self, m : &mut Matcher<'a>, pos : usize, k : &mut Cont<'a, '_>) -> bool {
+        let entry = m.save_groups();
+        let mut frames : Vec<RptFrame> = Vec::new();
+        let mut next = Some(pos);
+
+        loop {
+            // Start another repetition, or finish the last one tried.
+            if let Some(pos) = next.take() {
+                match self.enter(m, pos, frames.len(), k) {
+                    Ok(frame) => { frames.push(frame); }
+                    Err(true) => { return true; }
+                    Err(false) if m.abandoned.is_some() => { break; }
+                    Err(false) => {}
+                }
+                continue;
+            }
+
+            let frame = match frames.last_mut() {
+                Some(frame) => frame,
+                None => { break; }
+            };
+            if let Some((end, changes)) = frame.ends.pop() {
+                m.redo_changes(&frame.groups, &changes);
+                next = Some(end);
+                continue;
+            }
+
+            // Every way to repeat again failed, so stop at this repetition.
+            let frame = frames.pop().expect("");
+            m.restore_groups(frame.groups);
+            if frame.then_k && k(m, frame.pos) {
+                return true;
+            }
+        }
+
+        m.restore_groups(entry);
+        false
+    }
+
+    /**
+     * Starts the repetition after count have matched, ending at pos.
+     * Returns the frame to backtrack over the ways the next repetition can
+     * end, or Err with whether the rest of the regex matched if there's
+     * nothing to backtrack over.
+     */
+    fn enter<'a>(&self, m : &mut Matcher<'a>, pos : usize, count : usize,
+                 k : &mut Cont<'a, '_>) -> Result<RptFrame, bool> {
+        if !m.tick() {
+            return Err(false);
+        }
+        let groups = m.save_groups();
+
+        let done = count >= self.min;
+        let greedy = self.greedy && !m.lazy;
+        if self.max == Some(count) {
+            return Err(k(m, pos));
+        }
+        if done && !greedy && k(m, pos) {
+            return Err(true);
+        }
+
+        // Find how another repetition can end. Once the minimum is met, one
+        // that matches nothing can't lead anywhere new, so leave it out
+        // rather than loop forever.
+        if !m.repeat() {
+            return Err(false);
+        }
+        let mut ends = Vec::new();
+        self.node.match_chars(m, pos, &mut |m, next| {
+            if next != pos || !done {
+                ends.push((next, m.changes_since(&groups)));
+            }
+            false
+        });
+        ends.reverse();
+
+        Ok(RptFrame { pos : pos, groups : groups, ends : ends, then_k : done && greedy })
+    }
+
+    /**
+     * Matches repetitions of a simple node. Since each repetition can only
+     * match one way, the repetitions are found up front and only the
+     * number of them is backtracked over.
+     */
+    fn match_simple<'a>(&self, m : &mut Matcher<'a>, pos : usize, k : &mut Cont<'a, '_>) -> bool {
+        if self.greedy && !m.lazy {
+            // Match as many as possible, then give them back one at a time.
+            let mut ends = vec!(pos);
+            while self.max.is_none_or(|max| ends.len() <= max) {
+                match self.match_once(m, *ends.last().expect("")) {
+                    Some(next) => { ends.push(next); }
+                    None => { break; }
+                }
+            }
+            m.reached(*ends.last().expect(""));
+
+            for (count, &end) in ends.iter().enumerate().rev() {
+                if count < self.min || !m.tick() {
+                    break;
+                }
+                if k(m, end) {
+                    return true;
+                }
+            }
+            return false;
+        } else {
+            // Match as few as possible, adding one at a time.
+            let mut end = pos;
+            let mut count = 0;
+            loop {
+                if !m.tick() {
+                    return false;
+                }
+                if count >= self.min && k(m, end) {
+                    return true;
+                }
+                if self.max == Some(count) {
+                    return false;
+                }
+                match self.match_once(m, end) {
+                    Some(next) => {
+                        end = next;
+                        count += 1;
+                        m.reached(end);
+                    }
+                    None => { return false; }
+                }
+            }
+        }
+    }
+
+    /**
+     * Matches a single repetition of a simple node, returning the position
+     * after it.
+     */
+    fn match_once(&self, m : &mut Matcher, pos : usize) -> Option<usize> {
+        if !m.repeat() {
+            return None;
+        }
+        let mut end = None;
+        self.node.match_chars(m, pos, &mut |_, next| {
+            end = Some(next);
+            true
+        });
+        end
+    }
+}
+
+impl SeqNode {
+    /**
+     * Simplifies the sequence without changing what it matches. Nested
+     * sequences are merged into it (so empty ones disappear), and runs of
+     * char literals become single literal nodes.
+     */
+    fn optimize(&mut self) {
+        let mut nodes : Vec<Arc<dyn Node>> = Vec::new();
+        let mut run = String::new();
+
+        // Take nodes off the front, putting back the nodes of any nested
+        // sequence in its place.
+        let mut todo : Vec<Arc<dyn Node>> = self.nodes.drain(..).rev().collect();
+        while let Some(n) = todo.pop() {
+            if let Some(nested) = n.seq_nodes() {
+                todo.extend(nested.iter().rev().cloned());
+                continue;
+            }
+            if let Some(s) = n.as_literal() {
+                run.push_str(&s);
+                continue;
+            }
+
+            SeqNode::push_literal(&mut nodes, &mut run);
+            nodes.push(n);
+        }
+        SeqNode::push_literal(&mut nodes, &mut run);
+
+        self.nodes = nodes;
+    }
+
+    /// Returns the literal chars the sequence starts with, if any.
+    fn leading_literal(&self) -> Option<String> {
+        self.nodes.first().and_then(|n| n.as_literal())
+    }
+
+    /// Returns a copy of the sequence without the first len bytes of its
+    /// leading literal.
+    fn without_prefix(&self, len : usize) -> SeqNode {
+        let mut nodes = Vec::new();
+        let mut run = self.leading_literal().unwrap_or_default().split_off(len);
+        SeqNode::push_literal(&mut nodes, &mut run);
+        nodes.extend(self.nodes.iter().skip(1).cloned());
+        SeqNode { nodes : nodes }
+    }
+
+    /// Pushes a node matching a run of literal chars, if there are any, and
+    /// empties the run.
+    fn push_literal(nodes : &mut Vec<Arc<dyn Node>>, run : &mut String) {
+        let mut chars = run.chars();
+        match (chars.next(), chars.next()) {
+            (None, _) => { return; }
+            (Some(c), None) => { nodes.push(Arc::new(CharNode { c : c })); }
+            _ => { nodes.push(Arc::new(LiteralNode { s : run.clone() })); }
+        }
+        run.clear();
+    }
+
+    /**
+     * Matches the nodes of the sequence from the ith on.
+     */
+    fn match_from<'a>(&self, i : usize, m : &mut Matcher<'a>, pos : usize,
+                      k : &mut Cont<'a, '_>) -> bool {
+        if !m.tick() {
+            return false;
+        }
+        m.reached(pos);
+
+        match self.nodes.get(i) {
+            Some(n) => n.match_chars(m, pos, &mut |m, next| self.match_from(i + 1, m, next, k)),
+            None => k(m, pos)
+        }
+    }
+}
+
+/// Returns the quantifier for repeating between min and max times.
+fn quantifier(min : usize, max : Option<usize>) -> String {
+    match (min, max) {
+        (0, None) => "*".to_string(),
+        (1, None) => "+".to_string(),
+        (0, Some(1)) => "?".to_string(),
+        (min, None) => format!("{{{},}}", min),
+        (min, Some(max)) if min == max => format!("{{{}}}", min),
+        (min, Some(max)) => format!("{{{},{}}}", min, max)
+    }
+}
+
+/// Returns the suffix that marks a quantifier as lazy, if it is.
+fn lazy_suffix(greedy : bool) -> &'static str {
+    if greedy { "" } else { "?" }
+}
+
+/**
+ * The deepest that groups, char classes and quantifiers can be nested in a
+ * pattern. Parsing and matching recurse once per level, so deeper patterns
+ * could overflow the stack.
+ */
+pub const MAX_NESTING : usize = 100;
+
+/// State kept while parsing a regex.
+struct ParseState {
+    /// The length in bytes of the pattern, used to compute error positions.
+    len : usize,
+    /// The number of the most recently opened group.
+    num : usize,
+    /// The name of each group opened so far, indexed by group number.
+    names : Vec<Option<String>>,
+    /// Whether the i flag (case-insensitive matching) is on.
+    case_insensitive : bool,
+    /// Whether the s flag ('.' matching '\n') is on.
+    dot_matches_newline : bool,
+    /// The char that ends the pattern, if it doesn't run to the end of the
+    /// string.
+    terminator : Option<char>,
+    /// The number of groups and char classes enclosing the current position.
+    depth : usize,
+    /// Whether '.' matches a grapheme cluster rather than a char.
+    graphemes : bool,
+    /// Whether '$' also matches before a '\n' ending the input.
+    dollar_before_newline : bool,
+    /// How deeply groups, char classes and quantifiers can be nested.
+    nesting_limit : usize,
+    /// Whether groups capture, or are all treated as non-capturing.
+    capture_groups : bool,
+    /// Whether \\w and \\W use the regex's word char predicate, rather than
+    /// a fixed class.
+    word_chars : bool,
+    /// Which chars are special.
+    syntax : RegexSyntax,
+    /// Whether classes, '.' and case folding only match ASCII chars.
+    ascii : bool
+}
+
+impl ParseState {
+    fn new(s : &str) -> Self {
+        ParseState {
+            len : s.len(),
+            num : 0,
+            names : vec!(None),
+            case_insensitive : false,
+            dot_matches_newline : false,
+            terminator : None,
+            depth : 0,
+            graphemes : false,
+            dollar_before_newline : false,
+            nesting_limit : MAX_NESTING,
+            capture_groups : true,
+            word_chars : false,
+            syntax : RegexSyntax::new(),
+            ascii : false
+        }
+    }
+
+    /**
+     * Returns a set with the other cases of its chars added, as the i flag
+     * needs. In ASCII mode only ASCII letters are folded.
+     */
+    fn fold_case(&self, set : &CharSet) -> CharSet {
+        if !self.ascii {
+            return set.case_fold();
+        }
+        let mut folded = set.clone();
+        for c in ('A'..='Z').filter(|&c| set.contains(c)) {
+            folded.add(c.to_ascii_lowercase(), c.to_ascii_lowercase());
+        }
+        for c in ('a'..='z').filter(|&c| set.contains(c)) {
+            folded.add(c.to_ascii_uppercase(), c.to_ascii_uppercase());
+        }
+        folded
+    }
+
+    /**
+     * Returns the position in the pattern of the next char to be taken from
+     * itr.
+     */
+    fn pos(&self, itr : &Chars) -> usize {
+        self.len - itr.as_str().len()
+    }
+
+    /**
+     * Notes that a group or char class was opened at position, returning an
+     * error if that nests it too deeply. Each call should be followed by one
+     * to leave once it's closed.
+     */
+    fn enter(&mut self, position : usize) -> Result<(), ParseError> {
+        self.depth += 1;
+        if self.depth > self.nesting_limit {
+            return Err(self.too_deep(position));
+        }
+        Ok(())
+    }
+
+    /// Returns the error for nesting past the limit at position.
+    fn too_deep(&self, position : usize) -> ParseError {
+        ParseError::NestingTooDeep {
+            limit : self.nesting_limit,
+            position : position
+        }
+    }
+
+    /// Notes that the innermost group or char class was closed.
+    fn leave(&mut self) {
+        self.depth -= 1;
+    }
+}
+
+impl CharSet {
+    fn new() -> Self {
+        CharSet {
+            ranges : Vec::new()
+        }
+    }
+
+    fn contains(&self, c : char) -> bool {
+        ranges_contain(&self.ranges, c)
+    }
+
+    /// Adds the chars from lo to hi (inclusive).
+    fn add(&mut self, lo : char, hi : char) {
+        self.ranges.push((lo, hi));
+        self.normalize();
+    }
+
+    /// Adds the chars of another set.
+    fn union(&mut self, other : &CharSet) {
+        self.ranges.extend(other.ranges.iter().cloned());
+        self.normalize();
+    }
+
+    /// Returns the chars in both this set and another.
+    fn intersect(&self, other : &CharSet) -> CharSet {
+        let mut ranges = Vec::new();
+        let (mut i, mut j) = (0, 0);
+
+        while i < self.ranges.len() && j < other.ranges.len() {
+            let (a_lo, a_hi) = self.ranges[i];
+            let (b_lo, b_hi) = other.ranges[j];
+
+            let lo = cmp::max(a_lo, b_lo);
+            let hi = cmp::min(a_hi, b_hi);
+            if lo <= hi {
+                ranges.push((lo, hi));
+            }
+
+            // Move past whichever range ends first.
+            if a_hi < b_hi {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+
+        CharSet {
+            ranges : ranges
+        }
+    }
+
+    /// Returns the chars not in this set.
+    fn complement(&self) -> CharSet {
+        let mut ranges = Vec::new();
+        let mut next = Some('\0');
+
+        for &(lo, hi) in &self.ranges {
+            if let Some(n) = next {
+                if n < lo {
+                    ranges.push((n, prev_char(lo).expect("")));
+                }
+            }
+            next = next_char(hi);
+        }
+
+        if let Some(n) = next {
+            ranges.push((n, std::char::MAX));
+        }
+
+        CharSet {
+            ranges : ranges
+        }
+    }
+
+    /**
+     * Returns this set with every char's other cases added, repeatedly, until
+     * there are none left to add. Only one-to-one case mappings are used, so
+     * e.g. 'ß' isn't matched to "SS".
+     */
+    fn case_fold(&self) -> CharSet {
+        let mut folded = self.clone();
+        loop {
+            let mut next = folded.clone();
+            for &(lo, hi) in &folded.ranges {
+                for c in lo..=hi {
+                    for other in case_variants(c) {
+                        next.ranges.push((other, other));
+                    }
+                }
+            }
+
+            // Chars like the final sigma 'ς' map to a char ('Σ') that doesn't
+            // map back, so they must be found from the other side.
+            for &(c, other) in one_way_cases() {
+                if folded.contains(other) {
+                    next.ranges.push((c, c));
+                }
+            }
+            next.normalize();
+
+            if next.ranges == folded.ranges {
+                return folded;
+            }
+            folded = next;
+        }
+    }
+
+    /// Drops empty ranges, then sorts the rest and merges those that overlap
+    /// or touch.
+    fn normalize(&mut self) {
+        self.ranges.retain(|&(lo, hi)| lo <= hi);
+        self.ranges.sort();
+
+        let mut merged : Vec<(char, char)> = Vec::new();
+        for &(lo, hi) in &self.ranges {
+            if let Some(last) = merged.last_mut() {
+                if next_char(last.1).is_none_or(|n| lo <= n) {
+                    last.1 = cmp::max(last.1, hi);
+                    continue;
+                }
+            }
+            merged.push((lo, hi));
+        }
+
+        self.ranges = merged;
+    }
+}
+
+/// Returns the other cases of c, as given by its one-to-one case mappings.
+fn case_variants(c : char) -> Vec<char> {
+    [single_char(c.to_lowercase()), single_char(c.to_uppercase())].iter()
+        .filter_map(|&other| other)
+        .filter(|&other| other != c)
+        .collect()
+}
+
+/**
+ * Returns the chars with a case mapping that doesn't map back to them, each
+ * paired with the char it maps to. Found (once) by checking every char.
+ */
+fn one_way_cases() -> &'static [(char, char)] {
+    static CASES : OnceLock<Vec<(char, char)>> = OnceLock::new();
+    CASES.get_or_init(|| {
+        let mut cases = Vec::new();
+        for c in '\0'..=std::char::MAX {
+            for other in case_variants(c) {
+                if !case_variants(other).contains(&c) {
+                    cases.push((c, other));
+                }
+            }
+        }
+        cases
+    })
+}
+
+/// Returns the only char an iterator yields, or None if it yields more.
+fn single_char<I : Iterator<Item=char>>(mut itr : I) -> Option<char> {
+    let c = itr.next();
+    if itr.next().is_none() { c } else { None }
+}
+
+/// Returns the char after c, skipping the surrogate range.
+fn next_char(c : char) -> Option<char> {
+    match c as u32 {
+        0xD7FF => Some('\u{E000}'),
+        n => std::char::from_u32(n + 1)
+    }
+}
+
+/// Returns the char before c, skipping the surrogate range.
+fn prev_char(c : char) -> Option<char> {
+    match c as u32 {
+        0 => None,
+        0xE000 => Some('\u{D7FF}'),
+        n => std::char::from_u32(n - 1)
+    }
+}
+
+impl CharClassNode {
+    /**
+     * Parses a char class. The iterator should be positioned just after the
+     * '[' that opens it.
+     *
+     * A class is a list of members, each a char, a range of chars ("a-z"), a
+     * class escape ("\\d") or a nested class. The class matches the union of its members, except
+     * that lists of members can be joined by "&&" to take their intersection
+     * instead.
+     *
+     * A '-' makes a range when it comes between two chars (either of which
+     * may be escaped, or a '-' itself). It is a literal '-' when it is the
+     * first member (including just after '^' or "&&") or the last. A '-'
+     * right after a range, class escape or nested class is an error unless it
+     * is the last member, since it can't make a range and was likely meant
+     * to.
+     *
+     * A ']' always closes the class, even as the first member, so "[]" and
+     * "[^]" are empty (and so errors). A literal ']' must be escaped.
+     *
+     * * itr - pointer to current position in regex string
+     * * st  - parse state
+     */
+    fn parse(itr : &mut Chars, st : &mut ParseState) -> Result<Self, ParseError> {
+        // Position of the opening '['.
+        let start = st.pos(itr) - 1;
+        let mut negated = false;
+
+        let mut peek = itr.clone();
+        if peek.next() == Some('^') {
+            negated = true;
+            itr.next();
+        }
+
+        // Don't let the '^' be mistaken for a member.
+        if itr.as_str().starts_with(']') {
+            return Err(ParseError::EmptyCharClass { position : start });
+        }
+
+        // The intersection of the completed lists, and the current list.
+        let mut set : Option<CharSet> = None;
+        let mut members = CharSet::new();
+        let mut has_members = false;
+        // Whether the last member was a range or nested class, so can't be
+        // the start of a range.
+        let mut after_range = false;
+
+        loop {
+            let pos = st.pos(itr);
+            let c = match itr.next() {
+                Some(']') => { break; }
+                Some(c) => c,
+                None => { return Err(ParseError::UnterminatedCharClass { position : start }); }
+            };
+
+            let lo = match c {
+                c if c == st.syntax.escape => {
+                    match itr.clone().next().and_then(|c| class_escape(c, st.ascii)) {
+                        Some((ranges, negated)) => {
+                            itr.next();
+                            let escape = CharClassNode::from_ranges(ranges, negated);
+                            members.union(&escape.effective_set());
+                            has_members = true;
+                            after_range = true;
+                            continue;
+                        }
+                        None => parse_escape_char(itr, st)?
+                    }
+                }
+                '[' => {
+                    st.enter(pos)?;
+                    let nested = CharClassNode::parse(itr, st)?;
+                    st.leave();
+                    members.union(&nested.effective_set());
+                    has_members = true;
+                    after_range = true;
+                    continue;
+                }
+                '-' if after_range && !itr.as_str().starts_with(']') => {
+                    return Err(ParseError::InvalidRange { position : pos });
+                }
+                '&' if itr.as_str().starts_with('&') => {
+                    itr.next();
+                    if !has_members {
+                        return Err(ParseError::EmptyCharClass { position : start });
+                    }
+                    set = Some(match set {
+                        Some(set) => set.intersect(&members),
+                        None => members
+                    });
+                    members = CharSet::new();
+                    has_members = false;
+                    after_range = false;
+                    continue;
+                }
+                c => c
+            };
+
+            // A '-' between two chars makes a range. Anywhere else it's just
+            // a '-'.
+            let mut peek = itr.clone();
+            after_range = false;
+            let hi = match (peek.next(), peek.next()) {
+                (Some('-'), Some(c)) if c != ']' => {
+                    itr.next();
+                    after_range = true;
+                    let hi = match itr.next().expect("") {
+                        c if c == st.syntax.escape => {
+                            let next = itr.clone().next();
+                            if next.and_then(|c| class_escape(c, st.ascii)).is_some() {
+                                return Err(ParseError::InvalidRange { position : pos });
+                            }
+                            parse_escape_char(itr, st)?
+                        }
+                        '[' => { return Err(ParseError::InvalidRange { position : pos }); }
+                        c => c
+                    };
+                    if hi < lo {
+                        return Err(ParseError::InvalidRange { position : pos });
+                    }
+                    hi
+                }
+                _ => lo
+            };
+
+            members.add(lo, hi);
+            has_members = true;
+        }
+
+        if !has_members {
+            return Err(ParseError::EmptyCharClass { position : start });
+        }
+
+        let mut set = match set {
+            Some(set) => set.intersect(&members),
+            None => members
+        };
+        if st.case_insensitive {
+            set = st.fold_case(&set);
+        }
+
+        Ok(CharClassNode {
+            set : set,
+            negated : negated
+        })
+    }
+
+    fn from_ranges(ranges : &[(char, char)], negated : bool) -> CharClassNode {
+        CharClassNode {
+            set : CharSet {
+                ranges : ranges.to_vec()
+            },
+            negated : negated
+        }
+    }
+
+    /// Returns the set of chars this class matches, accounting for negation.
+    fn effective_set(&self) -> CharSet {
+        if self.negated {
+            self.set.complement()
+        } else {
+            self.set.clone()
+        }
+    }
+}
+
+impl AltNode {
+    /**
+     * Does the actual parsing of a regex or group body. The type hierarchy
+     * goes:
+     * group > alternation > sequence > (group or repeat or char).
+     *
+     * Returns the alternation making up the body, stopping at the ')' that
+     * closes it (or, for the whole regex, the end of the string).
+     *
+     * * itr  - pointer to current position in regex string
+     * * st   - parse state (used to keep track of group numbers)
+     * * open - position of the '(' that opened the body (None for the root)
+     */
+    fn parse(itr : &mut Chars, st : &mut ParseState, open : Option<usize>)
+             -> Result<Self, ParseError> {
+        let mut alt = AltNode::parse_unfactored(itr, st, open)?;
+        alt.factor();
+        Ok(alt)
+    }
+
+    /**
+     * Parses a body like parse, but without factoring out the prefixes its
+     * alternatives share, for bodies whose alternatives mean more than a
+     * choice, like a conditional's "yes|no".
+     */
+    fn parse_unfactored(itr : &mut Chars, st : &mut ParseState, open : Option<usize>)
+                        -> Result<Self, ParseError> {
+        // Flags set in the body only last until its end.
+        let case_insensitive = st.case_insensitive;
+        let dot_matches_newline = st.dot_matches_newline;
+        if let Some(open) = open {
+            st.enter(open)?;
+        }
+        let mut alt = AltNode {
+            alts : vec!(SeqNode {
+                nodes : Vec::new()
+            })
+        };
+
+        loop {
+            let pos = st.pos(itr);
+            let c = match itr.next() {
+                Some(c) => c,
+                None => match open {
+                    // The group was never closed.
+                    Some(open) => { return Err(ParseError::UnbalancedParen { position : open }); }
+                    None if st.terminator.is_some() => {
+                        return Err(ParseError::MissingTerminator { position : pos });
+                    }
+                    None => break
+                }
+            };
+
+            if open.is_none() && st.terminator == Some(c) {
+                break;
+            }
+
+            let c = match st.syntax.meta(c) {
+                Some(c) => c,
+                None => {
+                    alt.get_seq().push_char(c, st);
+                    continue;
+                }
+            };
+
+            match c {
+                '(' => {
+                    let mut peek = itr.clone();
+                    if peek.next() == Some('?') {
+                        // Extended group syntax, (?...).
+                        match peek.next() {
+                            Some('(') => {
+                                itr.clone_from(&peek);
+                                let cond = CondNode::parse(itr, st, pos)?;
+                                alt.get_seq().push(Arc::new(cond));
+                            }
+                            Some('<') if peek.as_str().starts_with(['=', '!']) => {
+                                let negated = peek.next() == Some('!');
+                                itr.clone_from(&peek);
+                                let lb = LookbehindNode::parse(itr, st, negated, pos)?;
+                                alt.get_seq().push(Arc::new(lb));
+                            }
+                            Some('<') => {
+                                // Parse this nested, named group.
+                                itr.clone_from(&peek);
+                                let err = ParseError::InvalidGroup { position : pos };
+                                let name = parse_name(itr, '>', err)?;
+                                if !st.capture_groups {
+                                    let body = AltNode::parse(itr, st, Some(pos))?;
+                                    alt.get_seq().push_body(body);
+                                    continue;
+                                }
+                                if st.names.contains(&Some(name.clone())) {
+                                    return Err(ParseError::DuplicateGroupName {
+                                        name : name,
+                                        position : pos
+                                    });
+                                }
+                                st.num += 1;
+                                st.names.push(Some(name.clone()));
+                                let grp = GrpNode::parse(itr, st, Some(name), Some(pos))?;
+                                alt.get_seq().push_grp(grp);
+                            }
+                            Some('=') | Some('!') => {
+                                return Err(ParseError::UnsupportedConstruct {
+                                    construct : "lookahead",
+                                    position : pos
+                                });
+                            }
+                            Some('>') => {
+                                return Err(ParseError::UnsupportedConstruct {
+                                    construct : "atomic groups",
+                                    position : pos
+                                });
+                            }
+                            Some(':') => {
+                                // A non-capturing group.
+                                itr.clone_from(&peek);
+                                let body = AltNode::parse(itr, st, Some(pos))?;
+                                alt.get_seq().push_body(body);
+                            }
+                            Some('i') | Some('s') | Some('-') => {
+                                // Skip just the '?', leaving the flags. If
+                                // they're scoped to a group, they're only
+                                // set until its end.
+                                itr.next();
+                                let case_insensitive = st.case_insensitive;
+                                let dot_matches_newline = st.dot_matches_newline;
+                                if parse_flags(itr, st, pos)? {
+                                    let body = AltNode::parse(itr, st, Some(pos))?;
+                                    alt.get_seq().push_body(body);
+                                    st.case_insensitive = case_insensitive;
+                                    st.dot_matches_newline = dot_matches_newline;
+                                }
+                            }
+                            _ => {
+                                return Err(ParseError::InvalidGroup { position : pos });
+                            }
+                        }
+                    } else if !st.capture_groups {
+                        let body = AltNode::parse(itr, st, Some(pos))?;
+                        alt.get_seq().push_body(body);
+                    } else {
+                        // Parse this nested group.
+                        st.num += 1;
+                        st.names.push(None);
+                        let grp = GrpNode::parse(itr, st, None, Some(pos))?;
+                        alt.get_seq().push_grp(grp);
+                    }
+                }
+                '|' => {
+                    // Create a new alternative sequence.
+                    alt.add_alt();
+                }
+                ')' => {
+                    // lparens should always be removed by the
+                    // subgroup parse. So this must be an error.
+                    if open.is_none() {
+                        return Err(ParseError::UnbalancedParen { position : pos });
+                    } else {
+                        break;
+                    }
+                }
+                '*' => {
+                    alt.get_seq().push_repeat(itr, st, 0, None, pos)?;
+                }
+                '+' => {
+                    alt.get_seq().push_repeat(itr, st, 1, None, pos)?;
+                }
+                '?' => {
+                    alt.get_seq().push_repeat(itr, st, 0, Some(1), pos)?;
+                }
+                '{' => {
+                    // A '{' that doesn't start a valid "{n}", "{n,}" or
+                    // "{n,m}" is just a char.
+                    match parse_bounds(itr, pos)? {
+                        Some((min, max)) => { alt.get_seq().push_repeat(itr, st, min, max, pos)?; }
+                        None => { alt.get_seq().push_char(c, st); }
+                    }
+                }
+                '[' => {
+                    let n = Arc::new(CharClassNode::parse(itr, st)?);
+                    alt.get_seq().push(n);
+                }
+                '\\' => {
+                    let node = parse_escape(itr, st)?;
+                    alt.get_seq().push(node);
+                }
+                '.' if st.ascii => {
+                    let ranges = if st.dot_matches_newline {
+                        ASCII_RANGES
+                    } else {
+                        ASCII_DOT_RANGES
+                    };
+                    alt.get_seq().push(Arc::new(CharClassNode::from_ranges(ranges, false)));
+                }
+                '.' if st.graphemes => {
+                    let newline = st.dot_matches_newline;
+                    alt.get_seq().push(Arc::new(GraphemeNode { newline : newline }));
+                }
+                '.' if st.dot_matches_newline => {
+                    // Any char at all, as an empty negated class.
+                    alt.get_seq().push(Arc::new(CharClassNode::from_ranges(&[], true)));
+                }
+                '.' => {
+                    alt.get_seq().push(Arc::new(AnyNode));
+                }
+                '^' => {
+                    alt.get_seq().push(Arc::new(AnchorNode { anchor : Anchor::Start }));
+                }
+                '$' => {
+                    let anchor = if st.dollar_before_newline {
+                        Anchor::EndBeforeNewline
+                    } else {
+                        Anchor::End
+                    };
+                    alt.get_seq().push(Arc::new(AnchorNode { anchor : anchor }));
+                }
+                c => {
+                    // Char literal. Just push it on the
+                    // current senquence.
+                    alt.get_seq().push_char(c, st);
+                }
+            }
+        }
+
+        st.case_insensitive = case_insensitive;
+        st.dot_matches_newline = dot_matches_newline;
+        if open.is_some() {
+            st.leave();
+        }
+        for seq in &mut alt.alts {
+            seq.optimize();
+        }
+        Ok(alt)
+    }
+
+    /**
+     * Factors the literal prefix shared by adjacent alternatives out of
+     * them, so "abc|abd" becomes "ab(?:c|d)" and the prefix is only matched
+     * once. Only adjacent alternatives are merged, so they're still tried in
+     * the same order. The alternatives should already be optimized.
+     */
+    fn factor(&mut self) {
+        let old = std::mem::take(&mut self.alts);
+        let mut i = 0;
+
+        while i < old.len() {
+            // Find the run of alternatives starting with the same char, and
+            // the prefix they all share.
+            let mut prefix = match old[i].leading_literal() {
+                Some(s) => s,
+                None => {
+                    self.alts.push(SeqNode { nodes : old[i].nodes.clone() });
+                    i += 1;
+                    continue;
+                }
+            };
+            let mut j = i + 1;
+            while let Some(s) = old.get(j).and_then(|seq| seq.leading_literal()) {
+                let len = prefix.char_indices()
+                    .zip(s.chars())
+                    .take_while(|&((_, a), b)| a == b)
+                    .last()
+                    .map_or(0, |((k, a), _)| k + a.len_utf8());
+                if len == 0 {
+                    break;
+                }
+                prefix.truncate(len);
+                j += 1;
+            }
+
+            if j - i < 2 {
+                self.alts.push(SeqNode { nodes : old[i].nodes.clone() });
+                i += 1;
+                continue;
+            }
+
+            // Match the prefix, then what's left of each alternative.
+            let mut rest = AltNode {
+                alts : old[i..j].iter().map(|seq| seq.without_prefix(prefix.len())).collect()
+            };
+            rest.factor();
+            let mut nodes = Vec::new();
+            SeqNode::push_literal(&mut nodes, &mut prefix);
+            nodes.push(Arc::new(rest));
+            self.alts.push(SeqNode { nodes : nodes });
+            i = j;
+        }
+    }
+
+    fn add_alt(&mut self) {
+        self.alts.push(SeqNode {
+            nodes : Vec::new()
+        });
+    }
+
+    fn get_seq(&mut self) -> &mut SeqNode {
+        let len = self.alts.len();
+        return self.alts.get_mut(len - 1).expect("");
+    }
+}
+
+impl CondNode {
+    /**
+     * Parses a conditional. The iterator should be positioned just after the
+     * "(?(" that opens it.
+     *
+     * * itr   - pointer to current position in regex string
+     * * st    - parse state (used to keep track of group numbers)
+     * * start - position of the conditional in the regex string
+     */
+    fn parse(itr : &mut Chars, st : &mut ParseState, start : usize) -> Result<Self, ParseError> {
+        let err = ParseError::InvalidConditional { position : start };
+
+        let mut digits = String::new();
+        loop {
+            match itr.next() {
+                Some(')') => { break; }
+                Some(c) if c.is_ascii_digit() => { digits.push(c); }
+                _ => { return Err(err); }
+            }
+        }
+
+        let cond_num = digits.parse().map_err(|_| err.clone())?;
+
+        // The body is parsed like a group, but only gets two alternatives.
+        let mut alt = AltNode::parse_unfactored(itr, st, Some(start))?;
+        if alt.alts.len() > 2 {
+            return Err(err);
+        }
+
+        let no = if alt.alts.len() == 2 {
+            alt.alts.pop().expect("")
+        } else {
+            SeqNode {
+                nodes : Vec::new()
+            }
+        };
+        let yes = alt.alts.pop().expect("");
+
+        // Like a backreference, the conditional can only refer to a group
+        // opened before it ends. Group 0 is the whole match, which hasn't
+        // been recorded while matching, so it can't be tested either.
+        if cond_num == 0 || cond_num > st.num {
+            return Err(err);
+        }
+
+        Ok(CondNode {
+            num : cond_num,
+            yes : yes,
+            no : no
+        })
+    }
+}
+
+impl LookbehindNode {
+    /**
+     * Parses a lookbehind. The iterator should be positioned just after the
+     * "(?<=" or "(?<!" that opens it. The body must match a fixed number of
+     * chars.
+     *
+     * * itr     - pointer to current position in regex string
+     * * st      - parse state (used to keep track of group numbers)
+     * * negated - whether this is a negative lookbehind
+     * * start   - position of the lookbehind in the regex string
+     */
+    fn parse(itr : &mut Chars, st : &mut ParseState, negated : bool, start : usize)
+             -> Result<Self, ParseError> {
+        let alt = AltNode::parse(itr, st, Some(start))?;
+        match alt.ast().fixed_len() {
+            Some(len) => Ok(LookbehindNode {
+                alt : alt,
+                len : len,
+                negated : negated
+            }),
+            None => Err(ParseError::VariableLengthLookbehind { position : start })
+        }
+    }
+}
+
+impl GrpNode {
+    /**
+     * Helper function for Regex constructors. Parses a group, whose body is
+     * an alternation.
+     *
+     * Returns the root node, a group, of the string passed.
+     *
+     * * itr  - pointer to current position in regex string
+     * * st   - parse state (used to keep track of group numbers)
+     * * name - the name of the group, if it has one
+     * * open - position of the '(' that opened the group (None for the root)
+     */
+    fn parse(itr : &mut Chars, st : &mut ParseState, name : Option<String>, open : Option<usize>)
+             -> Result<Self, ParseError> {
+        let num = st.num;
+        Ok(GrpNode {
+            num : num,
+            name : name,
+            alt : AltNode::parse(itr, st, open)?
+        })
+    }
+}
+
+/**
+ * Parses the bounds of a "{n}", "{n,}" or "{n,m}" quantifier. The iterator
+ * should be positioned just after the '{', and is only advanced if it starts
+ * a quantifier.
+ *
+ * Returns the fewest and most repetitions allowed, or None if the '{' doesn't
+ * start a quantifier.
+ *
+ * * itr   - pointer to current position in regex string
+ * * start - position of the quantifier in the regex string
+ */
+fn parse_bounds(itr : &mut Chars, start : usize)
+                -> Result<Option<(usize, Option<usize>)>, ParseError> {
+    let body = match itr.as_str().find('}') {
+        Some(end) => &itr.as_str()[..end],
+        None => { return Ok(None); }
+    };
+
+    let (min, max) = match body.find(',') {
+        Some(comma) => (&body[..comma], Some(&body[comma + 1..])),
+        None => (body, None)
+    };
+    let is_number = |s : &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+    if !is_number(min) || !max.is_none_or(|max| max.is_empty() || is_number(max)) {
+        return Ok(None);
+    }
+
+    let err = ParseError::InvalidRepeat { position : start };
+    let min = min.parse().map_err(|_| err.clone())?;
+    let max = match max {
+        None => Some(min),
+        Some("") => None,
+        Some(max) => Some(max.parse().map_err(|_| err.clone())?)
+    };
+    if max.is_some_and(|max| max < min) {
+        return Err(err);
+    }
+
+    // Skip past the closing '}'.
+    for _ in 0..body.len() + 1 {
+        itr.next();
+    }
+    Ok(Some((min, max)))
+}
+
+/**
+ * Parses a flag group, like "(?i)" or "(?-i)", and sets the flags it turns on
+ * or (after a '-') off. They apply until the end of the enclosing group. The
+ * iterator should be positioned just after the "(?" that opens it. The
+ * flags are 'i', for case-insensitive matching, and 's', for '.' matching
+ * '\n'.
+ *
+ * The flags can instead be followed by a ':' and a body, like "(?i:ab)", to
+ * apply them only to the body; then the iterator is left at the start of
+ * the body, and the flags must be reset after it.
+ *
+ * Returns whether the flags are followed by a body.
+ *
+ * * itr   - pointer to current position in regex string
+ * * st    - parse state (holding the flags)
+ * * start - position of the flag group in the regex string
+ */
+fn parse_flags(itr : &mut Chars, st : &mut ParseState, start : usize) -> Result<bool, ParseError> {
+    let mut on = true;
+    loop {
+        match itr.next() {
+            Some(')') => { return Ok(false); }
+            Some(':') => { return Ok(true); }
+            Some('-') if on => { on = false; }
+            Some('i') => { st.case_insensitive = on; }
+            Some('s') => { st.dot_matches_newline = on; }
+            _ => { return Err(ParseError::InvalidGroup { position : start }); }
+        }
+    }
+}
+
+/**
+ * Parses the '?' that may follow a quantifier to make it lazy. Returns whether
+ * the quantifier is greedy.
+ */
+fn parse_greedy(itr : &mut Chars) -> bool {
+    if itr.as_str().starts_with('?') {
+        itr.next();
+        false
+    } else {
+        true
+    }
+}
+
+/**
+ * Parses a group name, as given to a named group or a backreference. The
+ * iterator should be positioned just after the char that opens the name, and
+ * is left just after the closing char.
+ *
+ * Names are made up of alphanumerics and '_', and can't start with a digit.
+ *
+ * * itr   - pointer to current position in regex string
+ * * close - the char that ends the name
+ * * err   - the error to return if the name is malformed
+ */
+fn parse_name(itr : &mut Chars, close : char, err : ParseError) -> Result<String, ParseError> {
+    let mut name = String::new();
+    loop {
+        match itr.next() {
+            Some(c) if c == close => { break; }
+            Some(c) if c.is_alphanumeric() || c == '_' => { name.push(c); }
+            _ => { return Err(err); }
+        }
+    }
+
+    match name.chars().next() {
+        Some(c) if !c.is_numeric() => Ok(name),
+        _ => Err(err)
+    }
+}
+
+/**
+ * Parses the char(s) following an escape, but restricts matches to those which
+ * map directly to a another char (rather than, e.g., full nodes like a char
+ * class). The iterator should be positioned just after the '\\'.
+ */
+fn parse_escape_char(itr : &mut Chars, st : &ParseState) -> Result<char, ParseError> {
+    // Position of the '\\'.
+    let start = st.pos(itr) - 1;
+
+    match itr.next() {
+        Some(c) if Some(c) == st.terminator || c == st.syntax.escape => Ok(c),
+        Some(c) => match c {
+            '\\'|'('|')'|'['|']'|'*'|'+'|'?'|'^'|'-'|'&'|'.'|'|'|'{'|'}'|'$' => Ok(c),
+            't' => Ok('\t'),
+            'n' => Ok('\n'),
+            'r' => Ok('\r'),
+            'u' => parse_code_point(itr, start),
+            'x' if itr.as_str().starts_with('{') => parse_code_point(itr, start),
+            'x' => parse_hex_byte(itr, start),
+            'p' | 'P' => Err(ParseError::UnsupportedConstruct {
+                construct : "Unicode properties",
+                position : start
+            }),
+            _   => Err(ParseError::InvalidEscape { position : start })
+        },
+        None => Err(ParseError::UnterminatedEscape { position : start })
+    }
+}
+
+/**
+ * Parses the two hex digits following a \\x escape into the char with that
+ * code point.
+ *
+ * * itr   - pointer to current position in regex string
+ * * start - position of the escape in the regex string
+ */
+fn parse_hex_byte(itr : &mut Chars, start : usize) -> Result<char, ParseError> {
+    let err = ParseError::InvalidEscape { position : start };
+
+    let digits : String = itr.by_ref().take(2).collect();
+    if digits.len() != 2 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(err);
+    }
+    let value = u8::from_str_radix(&digits, 16).map_err(|_| err)?;
+    Ok(value as char)
+}
+
+/**
+ * Parses the "{...}" following a \\u or \\x escape into the char with that
+ * (hexadecimal) code point.
+ *
+ * * itr   - pointer to current position in regex string
+ * * start - position of the escape in the regex string
+ */
+fn parse_code_point(itr : &mut Chars, start : usize) -> Result<char, ParseError> {
+    let err = ParseError::InvalidEscape { position : start };
+
+    if itr.next() != Some('{') {
+        return Err(err);
+    }
+
+    let mut digits = String::new();
+    loop {
+        match itr.next() {
+            Some('}') => { break; }
+            Some(c) if c.is_ascii_hexdigit() => { digits.push(c); }
+            _ => { return Err(err); }
+        }
+    }
+
+    let value = u32::from_str_radix(&digits, 16).map_err(|_| err)?;
+    std::char::from_u32(value).ok_or(ParseError::InvalidCodePoint {
+        value : value,
+        position : start
+    })
+}
+
+/**
+ * Parses the char(s) following an escape ('\\'), allowing any result. (This
+ * is used outside of character classes.)
+ */
+fn parse_escape(itr : &mut Chars, st : &ParseState) -> Result<Arc<dyn Node>, ParseError> {
+    let mut peek = itr.clone();
+    match peek.next() {
+        Some('w') if st.word_chars => {
+            itr.next();
+            Ok(Arc::new(WordNode { negated : false }))
+        }
+        Some('W') if st.word_chars => {
+            itr.next();
+            Ok(Arc::new(WordNode { negated : true }))
+        }
+        Some(c) if class_escape(c, st.ascii).is_some() => {
+            itr.next();
+            let (ranges, negated) = class_escape(c, st.ascii).expect("");
+            Ok(Arc::new(CharClassNode::from_ranges(ranges, negated)))
+        }
+        Some('b') => {
+            itr.next();
+            Ok(Arc::new(AnchorNode { anchor : Anchor::WordBoundary }))
+        }
+        Some('B') => {
+            itr.next();
+            Ok(Arc::new(AnchorNode { anchor : Anchor::NotWordBoundary }))
+        }
+        Some('k') => {
+            itr.next();
+            parse_named_backref(itr, st)
+        }
+        Some('G') => {
+            itr.next();
+            Ok(Arc::new(AnchorNode { anchor : Anchor::SearchStart }))
+        }
+        Some('A') => {
+            itr.next();
+            Ok(Arc::new(AnchorNode { anchor : Anchor::TextStart }))
+        }
+        Some('z') => {
+            itr.next();
+            Ok(Arc::new(AnchorNode { anchor : Anchor::TextEnd }))
+        }
+        Some('Z') => {
+            itr.next();
+            Ok(Arc::new(AnchorNode { anchor : Anchor::EndBeforeNewline }))
+        }
+        Some(c) if c.is_ascii_digit() && c != '0' => {
+            parse_backref(itr, st)
+        }
+        _ => {
+            let c = parse_escape_char(itr, st)?;
+            Ok(char_node(c, st))
+        }
+    }
+}
+
+/**
+ * Returns the chars matched by a class escape like "\\d", given the char
+ * after the '\\', and whether the escape is negated (as "\\D" is). Returns
+ * None if the char doesn't make a class escape.
+ *
+ * * c     - the char after the '\\'
+ * * ascii - whether to match only ASCII chars, even with the unicode feature
+ */
+fn class_escape(c : char, ascii : bool) -> Option<(&'static [(char, char)], bool)> {
+    let (digits, words, spaces) = if ascii {
+        (DIGIT_RANGES, WORD_RANGES, SPACE_RANGES)
+    } else {
+        (digit_ranges(), word_ranges(), space_ranges())
+    };
+    match c {
+        'd' => Some((digits, false)),
+        'D' => Some((digits, true)),
+        'w' => Some((words, false)),
+        'W' => Some((words, true)),
+        's' => Some((spaces, false)),
+        'S' => Some((spaces, true)),
+        'h' => Some((HSPACE_RANGES, false)),
+        'H' => Some((HSPACE_RANGES, true)),
+        'v' => Some((VSPACE_RANGES, false)),
+        'V' => Some((VSPACE_RANGES, true)),
+        _ => None
+    }
+}
+
+/// The chars matched by '.' in ASCII mode: ASCII chars other than '\n'.
+const ASCII_DOT_RANGES : &[(char, char)] = &[('\0', '\t'), ('\u{b}', '\u{7f}')];
+
+/// The ASCII chars, which '.' matches in ASCII mode with the s flag.
+const ASCII_RANGES : &[(char, char)] = &[('\0', '\u{7f}')];
+
+/// The chars matched by \\h: horizontal whitespace, ' ' and '\t'.
+const HSPACE_RANGES : &[(char, char)] = &[('\t', '\t'), (' ', ' ')];
+
+/// The chars matched by \\v: vertical whitespace, '\n', vertical tab, form
+/// feed and '\r'.
+const VSPACE_RANGES : &[(char, char)] = &[('\n', '\r')];
+
+/// Returns whether c is in one of the sorted, non-overlapping ranges.
+fn ranges_contain(ranges : &[(char, char)], c : char) -> bool {
+    ranges.binary_search_by(|&(lo, hi)| {
+        if hi < c {
+            Ordering::Less
+        } else if lo > c {
+            Ordering::Greater
+        } else {
+            Ordering::Equal
+        }
+    }).is_ok()
+}
+
+/// The chars matched by \\d without the unicode feature, or in ASCII mode.
+const DIGIT_RANGES : &[(char, char)] = &[('0', '9')];
+
+/// The chars matched by \\w without the unicode feature, or in ASCII mode.
+const WORD_RANGES : &[(char, char)] = &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')];
+
+/// The chars matched by \\s without the unicode feature, or in ASCII mode.
+const SPACE_RANGES : &[(char, char)] = &[('\t', '\t'), (' ', ' ')];
+
+/// The first char of each run of ten Unicode decimal digits (category Nd).
+#[cfg(feature = "unicode")]
+const DIGIT_ZEROS : &[u32] = &[
+    0x0030, 0x0660, 0x06F0, 0x07C0, 0x0966, 0x09E6, 0x0A66, 0x0AE6, 0x0B66, 0x0BE6,
+    0x0C66, 0x0CE6, 0x0D66, 0x0DE6, 0x0E50, 0x0ED0, 0x0F20, 0x1040, 0x1090, 0x17E0,
+    0x1810, 0x1946, 0x19D0, 0x1A80, 0x1A90, 0x1B50, 0x1BB0, 0x1C40, 0x1C50, 0xA620,
+    0xA8D0, 0xA900, 0xA9D0, 0xA9F0, 0xAA50, 0xABF0, 0xFF10, 0x104A0, 0x10D30, 0x11066,
+    0x110F0, 0x11136, 0x111D0, 0x112F0, 0x11450, 0x114D0, 0x11650, 0x116C0, 0x11730,
+    0x118E0, 0x11950, 0x11C50, 0x11D50, 0x11DA0, 0x11F50, 0x16A60, 0x16AC0, 0x16B50,
+    0x1D7CE, 0x1D7D8, 0x1D7E2, 0x1D7EC, 0x1D7F6, 0x1E140, 0x1E2F0, 0x1E4F0, 0x1E950,
+    0x1FBF0
+];
+
+/// Returns the chars matched by \\d: ASCII digits.
+#[cfg(not(feature = "unicode"))]
+fn digit_ranges() -> &'static [(char, char)] {
+    DIGIT_RANGES
+}
+
+/// Returns the chars matched by \\d: Unicode decimal digits.
+#[cfg(feature = "unicode")]
+fn digit_ranges() -> &'static [(char, char)] {
+    static RANGES : OnceLock<Vec<(char, char)>> = OnceLock::new();
+    RANGES.get_or_init(|| {
+        // Some runs are adjacent, so merge them as a parsed class would.
+        let mut set = CharSet {
+            ranges : DIGIT_ZEROS.iter().filter_map(|&zero| {
+                Some((std::char::from_u32(zero)?, std::char::from_u32(zero + 9)?))
+            }).collect()
+        };
+        set.normalize();
+        set.ranges
+    })
+}
+
+/// Returns the chars matched by \\w: ASCII letters, digits and '_'.
+#[cfg(not(feature = "unicode"))]
+fn word_ranges() -> &'static [(char, char)] {
+    WORD_RANGES
+}
+
+/// Returns the chars matched by \\w: Unicode letters and numbers, and '_'.
+#[cfg(feature = "unicode")]
+fn word_ranges() -> &'static [(char, char)] {
+    static RANGES : OnceLock<Vec<(char, char)>> = OnceLock::new();
+    RANGES.get_or_init(|| ranges_where(|c| c.is_alphanumeric() || c == '_'))
+}
+
+/// Returns the chars matched by \\s: ' ' and '\\t'.
+#[cfg(not(feature = "unicode"))]
+fn space_ranges() -> &'static [(char, char)] {
+    SPACE_RANGES
+}
+
+/// Returns the chars matched by \\s: Unicode whitespace.
+#[cfg(feature = "unicode")]
+fn space_ranges() -> &'static [(char, char)] {
+    static RANGES : OnceLock<Vec<(char, char)>> = OnceLock::new();
+    RANGES.get_or_init(|| ranges_where(char::is_whitespace))
+}
+
+/// Returns the ranges of chars f is true for, found by checking every char.
+#[cfg(feature = "unicode")]
+fn ranges_where<F : Fn(char) -> bool>(f : F) -> Vec<(char, char)> {
+    let mut ranges : Vec<(char, char)> = Vec::new();
+    for c in ('\0'..=std::char::MAX).filter(|&c| f(c)) {
+        match ranges.last_mut() {
+            Some(&mut (_, ref mut hi)) if next_char(*hi) == Some(c) => { *hi = c; }
+            _ => { ranges.push((c, c)); }
+        }
+    }
+    ranges
+}
+
+/**
+ * Parses a numeric backreference, like "\\1". The iterator should be
+ * positioned just after the '\\'. Only groups opened earlier in the pattern
+ * can be referred to.
+ */
+fn parse_backref(itr : &mut Chars, st : &ParseState) -> Result<Arc<dyn Node>, ParseError> {
+    // Position of the '\\'.
+    let start = st.pos(itr) - 1;
+
+    let mut digits = String::new();
+    while let Some(c) = itr.as_str().chars().next().filter(|c| c.is_ascii_digit()) {
+        digits.push(c);
+        itr.next();
+    }
+
+    match digits.parse() {
+        Ok(num) if num <= st.num => Ok(Arc::new(BackrefNode {
+            num : num,
+            name : None,
+            case_insensitive : st.case_insensitive
+        })),
+        _ => Err(ParseError::InvalidBackreference { position : start })
+    }
+}
+
+/**
+ * Parses a backreference by name, "\\k<name>" or "\\k{name}", or a relative
+ * one, like "\\k<-1>", which refers to the group opened that many groups
+ * back from this point, "-1" being the most recent. Relative references are
+ * resolved to the group's number. The iterator should be positioned just
+ * after the 'k'.
+ */
+fn parse_named_backref(itr : &mut Chars, st : &ParseState) -> Result<Arc<dyn Node>, ParseError> {
+    // Position of the '\\'.
+    let start = st.pos(itr) - 2;
+    let err = ParseError::InvalidBackreference { position : start };
+
+    let close = match itr.next() {
+        Some('<') => '>',
+        Some('{') => '}',
+        _ => { return Err(err); }
+    };
+
+    if itr.as_str().starts_with('-') {
+        itr.next();
+        let mut digits = String::new();
+        loop {
+            match itr.next() {
+                Some(c) if c == close => { break; }
+                Some(c) if c.is_ascii_digit() => { digits.push(c); }
+                _ => { return Err(err); }
+            }
+        }
+        return match digits.parse::<usize>() {
+            Ok(back) if back > 0 && back <= st.num => Ok(Arc::new(BackrefNode {
+                num : st.num + 1 - back,
+                name : None,
+                case_insensitive : st.case_insensitive
+            })),
+            _ => Err(err)
+        };
+    }
+
+    let name = parse_name(itr, close, err)?;
+
+    match st.names.iter().position(|n| n.as_ref() == Some(&name)) {
+        Some(num) => Ok(Arc::new(BackrefNode {
+            num : num,
+            name : Some(name),
+            case_insensitive : st.case_insensitive
+        })),
+        None => Err(ParseError::UnknownGroupName {
+            name : name,
+            position : start
+        })
+    }
+}
+
+impl AltNode {
+    /**
+     * Matches the alternatives in order of how far each gets on its own,
+     * furthest first, for RegexBuilder::longest_alternative.
+     */
+    fn match_longest<'a>(&self, m : &mut Matcher<'a>, pos : usize, k : &mut Cont<'a, '_>)
+                         -> bool {
+        // Find where each alternative's preferred match ends, putting back
+        // the groups it set after each.
+        let saved = m.save_groups();
+        let mut ends = Vec::with_capacity(self.alts.len());
+        for (i, alt) in self.alts.iter().enumerate() {
+            let mut end = None;
+            alt.match_chars(m, pos, &mut |_, e| { end = Some(e); true });
+            m.restore_groups(saved.clone());
+            if let Some(end) = end {
+                ends.push((end, i));
+            }
+        }
+
+        // The sort is stable, so ties keep their order.
+        ends.sort_by_key(|&(end, _)| cmp::Reverse(end));
+        for (_, i) in ends {
+            if self.alts[i].match_chars(m, pos, k) {
+                return true;
+            }
+        }
+
+        return false;
+    }
+
+    /**
+     * Builds an alternation from a tree. Anything but an Ast::Alternation
+     * becomes a single alternative.
+     */
+    fn from_ast(ast : &Ast, options : NodeOptions) -> AltNode {
+        match *ast {
+            Ast::Alternation(ref asts) => {
+                let mut alt = AltNode {
+                    alts : asts.iter().map(|ast| SeqNode::from_ast(ast, options)).collect()
+                };
+                alt.factor();
+                alt
+            }
+            _ => AltNode {
+                alts : vec!(SeqNode::from_ast(ast, options))
+            }
+        }
+    }
+}
+
+impl SeqNode {
+    /**
+     * Builds a sequence from a tree. Anything but an Ast::Concat becomes a
+     * sequence of one node.
+     */
+    fn from_ast(ast : &Ast, options : NodeOptions) -> SeqNode {
+        let mut seq = match *ast {
+            Ast::Concat(ref asts) => SeqNode {
+                nodes : asts.iter().map(|ast| node_from_ast(ast, options)).collect()
+            },
+            _ => SeqNode {
+                nodes : vec!(node_from_ast(ast, options))
+            }
+        };
+        seq.optimize();
+        seq
+    }
+}
+
+/// The options that change which nodes a tree builds.
+#[derive(Clone, Copy, Default)]
+struct NodeOptions {
+    /// Whether '.' matches a grapheme cluster, so that the trees of its
+    /// GraphemeNodes (any char, or with the s flag any char at all) build
+    /// GraphemeNodes again.
+    graphemes : bool,
+    /// Whether the regex has a word char predicate, so that the default
+    /// class of word chars (the tree of a WordNode) builds a WordNode again.
+    word_chars : bool
+}
+
+/**
+ * Builds the node matching a tree.
+ */
+fn node_from_ast(ast : &Ast, options : NodeOptions) -> Arc<dyn Node> {
+    match *ast {
+        Ast::Char(c) => Arc::new(CharNode { c : c }),
+        Ast::Any if options.graphemes => Arc::new(GraphemeNode { newline : false }),
+        Ast::Any => Arc::new(AnyNode),
+        Ast::Anchor(anchor) => Arc::new(AnchorNode { anchor : anchor }),
+        Ast::Class { ref ranges, negated : true } if options.graphemes && ranges.is_empty() => {
+            Arc::new(GraphemeNode { newline : true })
+        }
+        Ast::Class { ref ranges, negated } if options.word_chars && ranges == word_ranges() => {
+            Arc::new(WordNode { negated : negated })
+        }
+        Ast::Class { ref ranges, negated } => {
+            let mut set = CharSet {
+                ranges : ranges.clone()
+            };
+            set.normalize();
+            Arc::new(CharClassNode {
+                set : set,
+                negated : negated
+            })
+        }
+        Ast::Concat(_) => Arc::new(SeqNode::from_ast(ast, options)),
+        Ast::Alternation(_) => Arc::new(AltNode::from_ast(ast, options)),
+        Ast::Group { num, ref name, ref ast } => Arc::new(GrpNode {
+            num : num,
+            name : name.clone(),
+            alt : AltNode::from_ast(ast, options)
+        }),
+        Ast::Repeat { min, max, greedy, possessive, ref ast } => {
+            repeat_node(node_from_ast(ast, options), min, max, greedy, possessive)
+        }
+        Ast::Conditional { num, ref yes, ref no } => Arc::new(CondNode {
+            num : num,
+            yes : SeqNode::from_ast(yes, options),
+            no : SeqNode::from_ast(no, options)
+        }),
+        Ast::Backref { num, ref name, case_insensitive } => Arc::new(BackrefNode {
+            num : num,
+            name : name.clone(),
+            case_insensitive : case_insensitive
+        }),
+        Ast::Lookbehind { negated, ref ast } => Arc::new(LookbehindNode {
+            alt : AltNode::from_ast(ast, options),
+            len : ast.fixed_len().expect("Lookbehind must have a fixed length."),
+            negated : negated
+        })
+    }
+}
+
+/**
+ * Builds the node matching a char literal. If the i flag is on and c has
+ * other cases, that's a class of all of them.
+ */
+fn char_node(c : char, st : &ParseState) -> Arc<dyn Node> {
+    if st.case_insensitive {
+        let mut set = CharSet::new();
+        set.add(c, c);
+        let set = st.fold_case(&set);
+        if set.ranges != [(c, c)] {
+            return Arc::new(CharClassNode {
+                set : set,
+                negated : false
+            });
+        }
+    }
+    Arc::new(CharNode { c : c })
+}
+
+/**
+ * Builds the node repeating another between min and max times.
+ */
+fn repeat_node(node : Arc<dyn Node>, min : usize, max : Option<usize>, greedy : bool,
+               possessive : bool) -> Arc<dyn Node> {
+    let rpt : Arc<dyn Node> = if min == 0 && max == Some(1) {
+        Arc::new(OptNode {
+            node : node,
+            greedy : greedy
+        })
+    } else {
+        Arc::new(RptNode {
+            node : node,
+            min : min,
+            max : max,
+            greedy : greedy
+        })
+    };
+
+    if possessive {
+        Arc::new(PossessiveNode { node : rpt })
+    } else {
+        rpt
+    }
+}
+
+impl SeqNode {
+    fn push_char(&mut self, c : char, st : &ParseState) {
+        self.nodes.push(char_node(c, st));
+    }
+
+    fn push_grp(&mut self, grp : GrpNode) {
+        self.nodes.push(Arc::new(grp));
+    }
+
+    fn push(&mut self, node : Arc<dyn Node>) {
+        self.nodes.push(node);
+    }
+
+    /// Pushes the body of a non-capturing group. A body with one alternative
+    /// is just a sequence.
+    fn push_body(&mut self, mut body : AltNode) {
+        if body.alts.len() == 1 {
+            let seq = body.alts.pop().expect("");
+            self.push(Arc::new(seq));
+        } else {
+            self.push(Arc::new(body));
+        }
+    }
+
+    fn pop(&mut self) -> Option<Arc<dyn Node>> {
+        self.nodes.pop()
+    }
+
+    /**
+     * Pops the previous node and nests it under a repeat, whose '?' (lazy)
+     * or '+' (possessive) suffix, if any, is parsed from itr.
+     *
+     * * itr - pointer to the position just after the quantifier
+     * * st  - parse state (holding the nesting limit)
+     * * min - the fewest repetitions allowed
+     * * max - the most repetitions allowed, or None for no limit
+     * * pos - position of the quantifier in the regex string
+     */
+    fn push_repeat(&mut self, itr : &mut Chars, st : &ParseState, min : usize, max : Option<usize>,
+                   pos : usize) -> Result<(), ParseError> {
+        let n = self.pop().ok_or(ParseError::QuantifierWithoutOperand { position : pos })?;
+        let possessive = itr.as_str().starts_with('+');
+        if possessive {
+            itr.next();
+        }
+
+        let rpt = repeat_node(n, min, max, possessive || parse_greedy(itr), possessive);
+        if rpt.ast().depth() > st.nesting_limit {
+            return Err(st.too_deep(pos));
+        }
+        self.push(rpt);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn test_match(r : &str, testcase : &str) {
+    let regex = Regex::from_str(r);
+    assert!(regex.match_str(testcase).is_some());
+}
+
+#[cfg(test)]
+fn test_no_match(r : &str, testcase : &str) {
+    let regex = Regex::from_str(r);
+    assert!(regex.match_str(testcase).is_none());
+}
+
+#[cfg(test)]
+fn test_result(r : &str, testcase : &str, mut mr : MatchResult) {
+    let regex = Regex::from_str(r);
+    mr.insert(0, testcase.to_string());
+    println!("Regex: {:?}", regex);
+
+    let res = regex.match_str(testcase);
+    println!("Expected result: {:?}", &mr);
+    println!("Actual result: {:?}", &res);
+
+    assert!(res == Some(mr));
+}
+
+#[test]
+fn test_escape_escape() {
+    test_match("\\\\", "\\");
+}
+
+#[test]
+fn test_hex_escapes() {
+    for c in ' '..='~' {
+        test_match("[\\x20-\\x7E]", &c.to_string());
+    }
+    test_no_match("[\\x20-\\x7E]", "\x7F");
+    test_no_match("[\\x20-\\x7E]", "\x1F");
+    test_match("[\\u{41}-\\u{5A}]+", "AZQ");
+    test_no_match("[\\u{41}-\\u{5A}]", "a");
+    test_match("[\\x00-\\x{10FFFF}]+", "\u{0}a\u{10FFFF}");
+    test_match("[\\t-\\r]+", "\t\n\r");
+    test_match("\\x41\\x{3bb}", "A\u{3bb}");
+    assert!(Regex::try_from_str("\\x4").err() == Some(ParseError::InvalidEscape { position : 0 }));
+    assert!(Regex::try_from_str("\\x+1").err() == Some(ParseError::InvalidEscape { position : 0 }));
+}
+
+#[test]
+fn test_any() {
+    test_match("a.c", "abc");
+    test_match("a.c", "a\u{e9}c");
+    test_no_match("a.c", "a\nc");
+    test_no_match("a.c", "ac");
+    test_match(".*", "xyz");
+}
+
+#[test]
+fn test_metachar_escapes() {
+    test_match("a\\.b", "a.b");
+    test_no_match("a\\.b", "axb");
+    test_match("[\\.]", ".");
+    test_no_match("[\\.]", "x");
+    test_match("a\\|b", "a|b");
+    test_no_match("a\\|b", "a");
+    test_match("a\\?", "a?");
+    test_match("a\\{2\\}", "a{2}");
+    test_match("[\\{\\}]+", "{}");
+
+    let s = Regex::from_str("\\.\\|\\{1\\}.").to_string();
+    assert!(s == "\\.\\|\\{1\\}.");
+    assert!(Regex::from_str(&s).match_str(".|{1}x").is_some());
+}
+
+#[test]
+fn test_tab() {
+    test_match("\t", "\t");
+    test_match("\\t", "\t");
+}
+
+#[test]
+fn test_char_class_escape() {
+    test_match("[\\t]", "\t");
+    test_match("[\\^]", "^");
+}
+
+#[test]
+fn test_star() {
+    let mut mr = MatchResult::new();
+    mr.insert(1, "aa".to_string());
+    test_result("(a*)bc", "aabc", mr);
+}
+
+#[test]
+fn test_plus() {
+    let mut mr = MatchResult::new();
+    mr.insert(1, "aaa".to_string());
+    test_result("(a+)b", "aaab", mr);
+}
+
+#[test]
+fn test_groups() {
+    let mut mr = MatchResult::new();
+    mr.insert(1, "ac".to_string());
+    mr.insert(2, "c".to_string());
+    mr.insert(3, "cdcdd".to_string());
+    mr.insert(4, "d".to_string());
+    test_result("(a(b|c))b((c|d)*)", "acbcdcdd", mr);
+}
+
+#[test]
+fn test_alts() {
+    let mut mr = MatchResult::new();
+    mr.insert(3, "cdcdd".to_string());
+    mr.insert(4, "d".to_string());
+    test_result("(a(b|c)*)|((c|d)*)", "cdcdd", mr);
+}
+
+#[test]
+fn test_char_class() {
+    let mut mr = MatchResult::new();
+    mr.insert(1, "a".to_string());
+    mr.insert(2, "z".to_string());
+    test_result("([abc])([xyz])", "az", mr);
+}
+
+#[test]
+fn test_char_class_negated() {
+    test_result("[^z]", "a", MatchResult::new());
+}
+
+#[test]
+fn test_optional() {
+    let mut mr = MatchResult::new();
+    mr.insert(1, "".to_string());
+    test_result("a(b?)c", "ac", mr);
+    test_match("ab?c", "abc");
+    test_no_match("ab?c", "abbc");
+}
+
+#[test]
+fn test_conditional() {
+    test_match("(a)?(?(1)b|c)", "ab");
+    test_match("(a)?(?(1)b|c)", "c");
+    test_no_match("(a)?(?(1)b|c)", "b");
+    test_no_match("(a)?(?(1)b|c)", "ac");
+    test_no_match("(x)?(?(1)ab|ac)", "xac");
+    test_match("(x)?(?(1)ab|ac)", "ac");
+    assert!(Regex::from_str("(x)?(?(1)ab|ac)").to_string() == "(x)?(?(1)ab|ac)");
+
+    // The group must exist, though it may be opened inside the conditional.
+    test_match("(?(1)(a)|b)", "b");
+    assert!(Regex::try_from_str("(a)(?(2)b|c)").err() ==
+            Some(ParseError::InvalidConditional { position : 3 }));
+    assert!(Regex::try_from_str("(?(1)a|b)(c)").err() ==
+            Some(ParseError::InvalidConditional { position : 0 }));
+    assert!(Regex::try_from_str("(a)(?(0)a|b)").err() ==
+            Some(ParseError::InvalidConditional { position : 3 }));
+}
+
+#[test]
+fn test_conditional_failed_group() {
+    // Group 2 matches before the sequence containing it fails, so it
+    // mustn't count as having participated.
+    test_match("((a)x)?(?(2)b|ac)", "ac");
+}
+
+#[test]
+fn test_match_read() {
+    use std::io::Cursor;
+
+    let regex = Regex::from_str("(a|b)c*");
+    let mut mr = MatchResult::new();
+    mr.insert(0, "bccc".to_string());
+    mr.insert(1, "b".to_string());
+    assert!(regex.match_read(Cursor::new("bccc")).unwrap() == Some(mr));
+    assert!(regex.match_read(Cursor::new("bcd")).unwrap().is_none());
+    assert!(regex.match_read(Cursor::new(vec!(0xff))).is_err());
+}
+
+#[test]
+fn test_match_lines() {
+    use std::io::Cursor;
+
+    let regex = Regex::from_str("error: (\\w+)");
+    let log = "ok\nerror: disk\nok\r\nerror: net\r\n\nerror: ";
+    let found = regex.match_lines(Cursor::new(log)).unwrap();
+
+    let mut disk = MatchResult::new();
+    disk.insert(0, "error: disk".to_string());
+    disk.insert(1, "disk".to_string());
+    let mut net = MatchResult::new();
+    net.insert(0, "error: net".to_string());
+    net.insert(1, "net".to_string());
+    assert!(found == vec!((2, disk), (4, net)));
+
+    // The line ending is never matched.
+    assert!(Regex::from_str("a\\s*").match_lines(Cursor::new("a \n")).unwrap()[0].1[&0] == "a ");
+    assert!(regex.match_lines(Cursor::new(vec!(0xff))).is_err());
+}
+
+#[test]
+fn test_code_point_escape() {
+    test_match("\\u{41}", "A");
+    test_match("[\\u{3bb}x]", "\u{3bb}");
+    test_match("a\\u{1F600}", "a\u{1F600}");
+}
+
+#[test]
+fn test_code_point_errors() {
+    assert!(Regex::try_from_str("\\u{D800}").err() ==
+            Some(ParseError::InvalidCodePoint { value : 0xD800, position : 0 }));
+    assert!(Regex::try_from_str("a\\u{110000}").err() ==
+            Some(ParseError::InvalidCodePoint { value : 0x110000, position : 1 }));
+    assert!(Regex::try_from_str("\\u{}").err() ==
+            Some(ParseError::InvalidEscape { position : 0 }));
+    assert!(Regex::try_from_str("\\u41").err() ==
+            Some(ParseError::InvalidEscape { position : 0 }));
+    assert!(Regex::try_from_str("\\u{FFFFFFFFF}").err() ==
+            Some(ParseError::InvalidEscape { position : 0 }));
+}
+
+#[test]
+fn test_quantifier_without_operand() {
+    for &(r, position) in &[("+abc", 0), ("*abc", 0), ("?abc", 0), ("(|*)", 2), ("a(+)", 2)] {
+        assert!(Regex::try_from_str(r).err() ==
+                Some(ParseError::QuantifierWithoutOperand { position : position }));
+    }
+}
+
+#[test]
+fn test_parse_errors() {
+    assert!(Regex::try_from_str("a)").err() ==
+            Some(ParseError::UnbalancedParen { position : 1 }));
+    assert!(Regex::try_from_str("a|*").err() ==
+            Some(ParseError::QuantifierWithoutOperand { position : 2 }));
+    assert!(Regex::try_from_str("ab[cd").err() ==
+            Some(ParseError::UnterminatedCharClass { position : 2 }));
+    assert!(Regex::try_from_str("a\\").err() ==
+            Some(ParseError::UnterminatedEscape { position : 1 }));
+    assert!(Regex::try_from_str("(?x)").err() ==
+            Some(ParseError::InvalidGroup { position : 0 }));
+}
+
+#[test]
+fn test_named_groups() {
+    let mut mr = MatchResult::new();
+    mr.insert(1, "x".to_string());
+    mr.insert(2, "y".to_string());
+    test_result("(?<first>x)(y)", "xy", mr);
+
+    assert!(Regex::try_from_str("(?<>x)").err() ==
+            Some(ParseError::InvalidGroup { position : 0 }));
+    assert!(Regex::try_from_str("a(?<1a>x)").err() ==
+            Some(ParseError::InvalidGroup { position : 1 }));
+    assert!(Regex::try_from_str("(?<a-b>x)").err() ==
+            Some(ParseError::InvalidGroup { position : 0 }));
+}
+
+#[test]
+fn test_capture_names() {
+    let regex = Regex::from_str("(?<a>x)(y)(?<b>z)");
+    let names : Vec<Option<&str>> = regex.capture_names().collect();
+    assert!(names == vec!(None, Some("a"), None, Some("b")));
+
+    let regex = Regex::from_str("((?<inner>a)b)");
+    let names : Vec<Option<&str>> = regex.capture_names().collect();
+    assert!(names == vec!(None, None, Some("inner")));
+}
+
+#[test]
+fn test_expand() {
+    let regex = Regex::from_str("(a*)-(?<second>b*)");
+    let caps = regex.captures("aa-bbb").unwrap();
+
+    assert!(caps.expand("$1:$2") == "aa:bbb");
+    assert!(caps.expand("$second/$0") == "bbb/aa-bbb");
+    assert!(caps.expand("${1}x${second}") == "aaxbbb");
+    assert!(caps.expand("$1x").is_empty());
+    assert!(caps.expand("$$1 costs $") == "$1 costs $");
+    assert!(caps.expand("$3${missing}${1") == "${1");
+}
+
+#[test]
+fn test_captures_get() {
+    let regex = Regex::from_str("(?<x>a)|(b)");
+    let caps = regex.captures("a").unwrap();
+
+    assert!(caps.get(0) == Some("a"));
+    assert!(caps.name("x") == Some("a"));
+    assert!(caps.get(2).is_none());
+    assert!(caps.name("y").is_none());
+    assert!(regex.captures("c").is_none());
+}
+
+#[test]
+fn test_duplicate_group_name() {
+    assert!(Regex::try_from_str("(?<x>a)(?<x>b)").err() ==
+            Some(ParseError::DuplicateGroupName { name : "x".to_string(), position : 7 }));
+    assert!(Regex::try_from_str("(?<x>(?<x>b))").err() ==
+            Some(ParseError::DuplicateGroupName { name : "x".to_string(), position : 5 }));
+    assert!(Regex::try_from_str("(?<x>a)(?<y>b)").is_ok());
+}
+
+#[test]
+fn test_find() {
+    let regex = Regex::from_str("a+");
+    assert!(regex.find("xaayaaa").map(|m| m.range()) == Some(1..3));
+    assert!(regex.find("xyz").is_none());
+
+    let regex = Regex::from_str("b*");
+    assert!(regex.find("").map(|m| m.range()) == Some(0..0));
+}
+
+#[test]
+fn test_find_at() {
+    let regex = Regex::from_str("a+");
+    assert!(regex.find_at("xaayaaa", 3) == Some((4, 7)));
+    assert!(regex.find_at("xaayaaa", 2) == Some((2, 3)));
+    assert!(regex.find_at("xaayaaa", 7).is_none());
+
+    let regex = Regex::from_str("\\u{3bb}");
+    assert!(regex.find_at("\u{3bb}x\u{3bb}", 2) == Some((3, 5)));
+}
+
+#[test]
+fn test_is_match_at() {
+    let regex = Regex::from_str("\\d+");
+    assert!(regex.is_match_at("abc123", 3));
+    assert!(regex.is_match_at("abc123", 5));
+    assert!(!regex.is_match_at("abc123", 2));
+    assert!(!regex.is_match_at("abc123", 6));
+    assert!(Regex::from_str("\\Gb").is_match_at("ab", 1));
+}
+
+#[test]
+#[should_panic(expected = "not a char boundary")]
+fn test_is_match_at_not_boundary() {
+    Regex::from_str("a").is_match_at("\u{3bb}a", 1);
+}
+
+#[test]
+#[should_panic(expected = "not a char boundary")]
+fn test_find_at_not_boundary() {
+    Regex::from_str("a").find_at("\u{3bb}a", 1);
+}
+
+#[test]
+fn test_char_class_range() {
+    test_match("[a-cx]", "b");
+    test_match("[a-cx]", "x");
+    test_no_match("[a-cx]", "d");
+    test_match("[-a]", "-");
+    test_match("[a-]", "-");
+    test_match("[\\u{3b1}-\\u{3c9}]", "\u{3bb}");
+    assert!(Regex::try_from_str("a[z-a]").err() ==
+            Some(ParseError::InvalidRange { position : 2 }));
+}
+
+#[test]
+fn test_char_class_intersection() {
+    test_match("[a-z&&[^aeiou]]", "b");
+    test_no_match("[a-z&&[^aeiou]]", "a");
+    test_no_match("[a-z&&[^aeiou]]", "B");
+    test_match("[ab[xy]]", "y");
+    test_match("[a-f&&c-z&&[^d]]", "e");
+    test_no_match("[a-f&&c-z&&[^d]]", "d");
+    test_match("[^a-z&&[^m]]", "m");
+    test_no_match("[^a-z&&[^m]]", "a");
+    assert!(Regex::try_from_str("[&&a]").err() ==
+            Some(ParseError::EmptyCharClass { position : 0 }));
+}
+
+#[test]
+fn test_empty_char_class() {
+    assert!(Regex::try_from_str("[]").err() ==
+            Some(ParseError::EmptyCharClass { position : 0 }));
+    assert!(Regex::try_from_str("a[^]").err() ==
+            Some(ParseError::EmptyCharClass { position : 1 }));
+    assert!(Regex::try_from_str("[a&&]").err() ==
+            Some(ParseError::EmptyCharClass { position : 0 }));
+
+    // A ']' can't be the first member unless it's escaped.
+    assert!(Regex::try_from_str("[]]").err() ==
+            Some(ParseError::EmptyCharClass { position : 0 }));
+    test_match("[\\]]", "]");
+    test_match("[^\\]]", "a");
+    test_no_match("[^\\]]", "]");
+    test_match("[^^]", "a");
+    test_no_match("[^^]", "^");
+}
+
+#[test]
+fn test_char_class_surrogate_gap() {
+    test_match("[^\\u{D7FF}]", "\u{E000}");
+    test_match("[\\u{D700}-\\u{E0FF}&&[^\\u{E000}]]", "\u{D7FF}");
+    test_no_match("[\\u{D700}-\\u{E0FF}&&[^\\u{E000}]]", "\u{E000}");
+}
+
+#[test]
+fn test_ast() {
+    let ast = Regex::from_str("(a|b)*").ast();
+    assert!(ast == Ast::Repeat {
+        min : 0,
+        max : None,
+        greedy : true,
+        possessive : false,
+        ast : Box::new(Ast::Group {
+            num : 1,
+            name : None,
+            ast : Box::new(Ast::Alternation(vec!(Ast::Char('a'), Ast::Char('b'))))
+        })
+    });
+
+    let ast = Regex::from_str("x+[^a-c]?").ast();
+    assert!(ast == Ast::Concat(vec!(
+        Ast::Repeat {
+            min : 1,
+            max : None,
+            greedy : true,
+            possessive : false,
+            ast : Box::new(Ast::Char('x'))
+        },
+        Ast::Repeat {
+            min : 0,
+            max : Some(1),
+            greedy : true,
+            possessive : false,
+            ast : Box::new(Ast::Class {
+                ranges : vec!(('a', 'c')),
+                negated : true
+            })
+        }
+    )));
+
+    assert!(Regex::from_str("").ast() == Ast::Concat(Vec::new()));
+    assert!(Regex::from_str("a|").ast() ==
+            Ast::Alternation(vec!(Ast::Char('a'), Ast::Concat(Vec::new()))));
+}
+
+#[test]
+fn test_repeat_empty_match() {
+    // A repeated node that can match nothing mustn't loop forever.
+    test_match("(a*)*b", "aab");
+    test_match("(a?)+", "");
+}
+
+#[test]
+fn test_backtracking() {
+    test_match("a*a", "aaa");
+    test_match("(a|ab)c", "abc");
+    test_match("(ab|a)*c", "abac");
+    test_match("[ab]+b", "abab");
+
+    let mut mr = MatchResult::new();
+    mr.insert(1, "aaa".to_string());
+    mr.insert(2, "a".to_string());
+    test_result("(a+)(a+)", "aaaa", mr);
+}
+
+#[test]
+fn test_backtracking_groups() {
+    // Groups from a branch that was backtracked out of aren't kept.
+    let mut mr = MatchResult::new();
+    mr.insert(1, "ab".to_string());
+    mr.insert(3, "ab".to_string());
+    test_result("((a)c|(ab))", "ab", mr);
+}
+
+#[test]
+fn test_lazy() {
+    let mut mr = MatchResult::new();
+    mr.insert(1, "a".to_string());
+    mr.insert(2, "aaa".to_string());
+    test_result("(a+?)(a+)", "aaaa", mr);
+
+    let mut mr = MatchResult::new();
+    mr.insert(1, "".to_string());
+    mr.insert(2, "ab".to_string());
+    test_result("(a??)(ab)", "ab", mr);
+
+    let regex = Regex::from_str("a*?");
+    assert!(regex.find("aaa").map(|m| m.range()) == Some(0..0));
+    let regex = Regex::from_str("b(aa)*?c");
+    assert!(regex.find("baaaac").map(|m| m.range()) == Some(0..6));
+}
+
+#[test]
+fn test_display() {
+    for r in &["(a|b)*c", "x+?y??", "[^\\-a-c]", "(?<n>\\(\\*)|", "(a)?(?(1)b|c)"] {
+        assert!(Regex::from_str(r).to_string() == *r);
+    }
+    assert!(Regex::from_str("[cba]").to_string() == "[a-c]");
+}
+
+#[test]
+fn test_factor_prefix() {
+    let regex = Regex::from_str("abc|abd|ae|b");
+    assert!(regex.ast() == Ast::Alternation(vec!(
+        Ast::Concat(vec!(
+            Ast::Char('a'),
+            Ast::Alternation(vec!(
+                Ast::Concat(vec!(
+                    Ast::Char('b'),
+                    Ast::Alternation(vec!(Ast::Char('c'), Ast::Char('d')))
+                )),
+                Ast::Char('e')
+            ))
+        )),
+        Ast::Char('b')
+    )));
+    assert!(regex.to_string() == "a(?:b(?:c|d)|e)|b");
+    for s in &["abc", "abd", "ae", "b"] {
+        assert!(regex.match_str(s).is_some());
+    }
+    assert!(regex.match_str("ab").is_none());
+
+    // Alternatives are still tried in order, and groups keep their numbers.
+    let regex = Regex::from_str("(ab|a)(b?)");
+    let caps = regex.captures("ab").unwrap();
+    assert!(caps.get(1) == Some("ab") && caps.get(2) == Some(""));
+    assert!(Regex::from_str("x(?:ab|ac)*").match_str("xabacab").is_some());
+}
+
+#[test]
+fn test_enumerate() {
+    assert!(Regex::from_str("(a|b)c").enumerate() == Some(vec!("ac".to_string(), "bc".to_string())));
+    assert!(Regex::from_str("x{1,2}[yz]?").enumerate().unwrap().len() == 6);
+    // Only strings the regex really matches are listed.
+    assert!(Regex::from_str("a|a|b$c").enumerate() == Some(vec!("a".to_string())));
+    assert!(Regex::from_str("ab*").enumerate().is_none());
+    assert!(Regex::from_str("(a)\\1").enumerate().is_none());
+}
+
+#[test]
+fn test_concat() {
+    let regex = Regex::from_str("(a)").concat(&Regex::from_str("(b)"));
+    let caps = regex.captures("ab").unwrap();
+    assert!(caps.get(1) == Some("a") && caps.get(2) == Some("b"));
+    assert!(regex.to_string() == "(a)(b)");
+
+    // Alternations and references to groups are kept intact.
+    let regex = Regex::from_str("(x)|y").concat(&Regex::from_str("(?<n>z)\\1|\\k<n>"));
+    assert!(regex.to_string() == "(?:(x)|y)(?:(?<n>z)\\2|\\k<n>)");
+    assert!(regex.captures("xzz").unwrap().name("n") == Some("z"));
+    assert!(regex.match_str("xz").is_none());
+}
+
+#[test]
+fn test_or() {
+    let regex = Regex::from_str("(a)").or(&Regex::from_str("(b)"));
+    let caps = regex.captures("a").unwrap();
+    assert!(caps.get(1) == Some("a") && caps.get(2).is_none());
+    let caps = regex.captures("b").unwrap();
+    assert!(caps.get(1).is_none() && caps.get(2) == Some("b"));
+    assert!(regex.to_string() == "(a)|(b)");
+
+    // Each side keeps its own precedence.
+    let regex = Regex::from_str("ab|c").or(&Regex::from_str("d*"));
+    for s in &["ab", "c", "", "ddd"] {
+        assert!(regex.match_str(s).is_some());
+    }
+    assert!(regex.match_str("ad").is_none());
+}
+
+#[test]
+fn test_repeat() {
+    let regex = Regex::from_str("(ab)").repeat(2, Some(3));
+    assert!(regex.match_str("ababab").is_some());
+    assert!(regex.match_str("abab").is_some());
+    assert!(regex.match_str("ab").is_none());
+    assert!(regex.match_str("abababab").is_none());
+    assert!(regex.captures("ababab").unwrap().get(1) == Some("ab"));
+
+    // The whole pattern is repeated, not just its last part.
+    let regex = Regex::from_str("ab|c").repeat(1, None);
+    assert!(regex.to_string() == "(?:ab|c)+");
+    assert!(regex.match_str("abcab").is_some());
+}
+
+#[test]
+#[should_panic(expected = "less than min")]
+fn test_repeat_bounds() {
+    Regex::from_str("a").repeat(2, Some(1));
+}
+
+#[test]
+fn test_first_bytes() {
+    let chars = |s : &str| Some(s.chars().collect::<BTreeSet<char>>());
+    assert!(Regex::from_str("(abc|def)").first_bytes() == chars("ad"));
+    assert!(Regex::from_str("^x?[yz]+").first_bytes() == chars("xyz"));
+    assert!(Regex::from_str("a|b*").first_bytes().is_none());
+    assert!(Regex::from_str("a|.").first_bytes().is_none());
+}
+
+#[test]
+fn test_cached() {
+    let first = Regex::cached("cached(a|b)+").unwrap();
+    let second = Regex::cached("cached(a|b)+").unwrap();
+    assert!(Arc::ptr_eq(&first, &second));
+    assert!(second.match_str("cachedab").is_some());
+    assert!(Regex::cached("cached(").is_err());
+
+    // Regexes can be shared between threads.
+    let handle = std::thread::spawn(|| Regex::cached("cached(a|b)+").unwrap());
+    assert!(Arc::ptr_eq(&first, &handle.join().unwrap()));
+}
+
+#[test]
+fn test_reverse() {
+    let reversed = Regex::from_str("abc").reverse().unwrap();
+    assert!(reversed.match_str("cba").is_some());
+    assert!(reversed.match_str("abc").is_none());
+
+    let reversed = Regex::from_str("^a(b|cd)+\\z").reverse().unwrap();
+    assert!(reversed.to_string() == "\\A(b|dc)+a$");
+    assert!(reversed.find("bdcba").map(|m| m.range()) == Some(0..5));
+    assert!(reversed.find("xdcba").is_none());
+    assert!(reversed.find("dcbax").is_none());
+    assert!(reversed.captures("dcba").unwrap().get(1) == Some("b"));
+
+    let unsupported = |pattern : &str| Regex::from_str(pattern).reverse().err();
+    assert!(unsupported("(a)\\1") == Some(ReverseError { construct : "backreferences" }));
+    assert!(unsupported("(a)?(?(1)b)") == Some(ReverseError { construct : "conditionals" }));
+    assert!(unsupported("(?<=a)b") == Some(ReverseError { construct : "lookbehinds" }));
+    assert!(unsupported("\\Ga") == Some(ReverseError { construct : "\\G" }));
+    assert!(unsupported("a\\Z") == Some(ReverseError { construct : "\\Z" }));
+    assert!(unsupported("a\\Z").unwrap().to_string() == "Can't reverse a regex using \\Z.");
+}
+
+#[test]
+fn test_equivalent() {
+    assert!(Regex::from_str("(ab|ac)").equivalent(&Regex::from_str("a(b|c)")) == Some(true));
+    assert!(Regex::from_str("a?b").equivalent(&Regex::from_str("b|ab")) == Some(true));
+    assert!(Regex::from_str("ab").equivalent(&Regex::from_str("ba")) == Some(false));
+    assert!(Regex::from_str("a*").equivalent(&Regex::from_str("a*")).is_none());
+}
+
+#[test]
+fn test_to_lazy() {
+    let lazy = Regex::from_str("a*b+").to_lazy();
+    assert!(lazy.to_string() == "a*?b+?");
+    assert!(Regex::try_from_str(&lazy.to_string()).is_ok());
+
+    let lazy = Regex::from_str("(a+)(b?)").to_lazy();
+    assert!(lazy.to_string() == "(a+?)(b??)");
+    assert!(lazy.captures("aab").unwrap().get(1) == Some("aa"));
+}
+
+#[test]
+fn test_match_char_slice() {
+    let regex = Regex::from_str("a(b|x)c");
+    let mut mr = MatchResult::new();
+    mr.insert(0, "abc".to_string());
+    mr.insert(1, "b".to_string());
+    assert!(regex.match_char_slice(&['a', 'b', 'c']) == Some(mr));
+    assert!(regex.match_char_slice(&['a', 'b']).is_none());
+
+    let chars : Vec<char> = "\u{3bb}\u{3bb}x".chars().collect();
+    assert!(Regex::from_str("[\\u{3bb}]*x").match_char_slice(&chars).is_some());
+}
+
+#[test]
+fn test_find_iter() {
+    let regex = Regex::from_str("a+");
+    let found : Vec<(usize, usize)> = regex.find_iter("aabaaa\u{3bb}a").collect();
+    assert!(found == vec!((0, 2), (3, 6), (8, 9)));
+
+    let regex = Regex::from_str("a*");
+    let found : Vec<(usize, usize)> = regex.find_iter("bab").collect();
+    assert!(found == vec!((0, 0), (1, 2), (2, 2), (3, 3)));
+}
+
+#[test]
+fn test_count() {
+    assert!(Regex::from_str("a").count("banana") == 3);
+    assert!(Regex::from_str("x").count("banana") == 0);
+
+    // The empty match after "aa" is counted too.
+    assert!(Regex::from_str("a*").count("aa") == 2);
+    assert!(Regex::from_str("a*").count("") == 1);
+}
+
+#[test]
+fn test_find_overlapping() {
+    let regex = Regex::from_str("aa");
+    assert!(regex.find_overlapping("aaaa") == vec!((0, 2), (1, 3), (2, 4)));
+    assert!(regex.find_iter("aaaa").count() == 2);
+
+    let regex = Regex::from_str("a+");
+    assert!(regex.find_overlapping("baab") == vec!((1, 3), (2, 3)));
+
+    let regex = Regex::from_str("x?");
+    assert!(regex.find_overlapping("\u{3bb}") == vec!((0, 0), (2, 2)));
+}
+
+#[test]
+fn test_find_shortest() {
+    assert!(Regex::from_str("a+").find_shortest("aaa") == Some(1));
+    assert!(Regex::from_str("a+").find_shortest("bbaaa") == Some(3));
+    assert!(Regex::from_str("(ab)*c").find_shortest("xababc") == Some(6));
+    assert!(Regex::from_str("a(b|bc)+").find_shortest("abcbc") == Some(2));
+    assert!(Regex::from_str("x").find_shortest("aaa").is_none());
+}
+
+#[test]
+fn test_char_class_dash() {
+    // First, last, and just after '^' or "&&", it's a literal.
+    test_match("[-a]", "-");
+    test_match("[a-]", "-");
+    test_match("[^-a]", "b");
+    test_no_match("[^-a]", "-");
+    test_match("[a-z&&-b]", "b");
+    test_no_match("[a-z&&-b]", "-");
+    test_match("[-]", "-");
+
+    // Between two chars, it makes a range.
+    test_match("[a-c]", "b");
+    test_no_match("[a-c]", "-");
+    test_match("[--/]", ".");
+    test_match("[!--]", ",");
+    test_match("[\\t-\\u{20}]", "\u{10}");
+
+    // After a range, it's only allowed as the last member.
+    test_match("[a-c-]", "-");
+    test_match("[a-c\\-z]", "z");
+    test_match("[a-c\\-z]", "-");
+    assert!(Regex::try_from_str("[a-d-z]").err() ==
+            Some(ParseError::InvalidRange { position : 4 }));
+    assert!(Regex::try_from_str("[a-a-z]").err() ==
+            Some(ParseError::InvalidRange { position : 4 }));
+    test_match("[a-a-]", "-");
+    assert!(Regex::try_from_str("[[ab]-z]").err() ==
+            Some(ParseError::InvalidRange { position : 5 }));
+    assert!(Regex::try_from_str("[a-[b]]").err() ==
+            Some(ParseError::InvalidRange { position : 1 }));
+}
+
+#[test]
+fn test_word_and_digit() {
+    test_match("\\w+", "a_Z9");
+    test_no_match("\\w", "-");
+    test_match("\\W", "-");
+    test_match("\\d+", "0123");
+    test_no_match("\\d", "a");
+    test_match("\\D", "a");
+}
+
+#[test]
+fn test_backref() {
+    test_match("(a|b)\\1", "aa");
+    test_no_match("(a|b)\\1", "ab");
+    test_match("(?<w>\\w+) \\k<w>", "go go");
+    test_no_match("(?<w>\\w+) \\k<w>", "go no");
+    test_match("(?<w>\\w+) \\k{w}", "go go");
+
+    // A reference to a group that didn't participate never matches.
+    test_no_match("(a)?\\1", "");
+
+    // Under the i flag, the text may be matched in another case.
+    test_match("(?i)(ab)\\1", "abAB");
+    test_match("(ab)(?i)\\1", "abAb");
+    test_no_match("(ab)\\1", "abAB");
+    test_no_match("(?i)(ab)(?-i)\\1", "abAB");
+    let regex = Regex::from_str("(a)(?i)\\1");
+    assert!(regex.to_string() == "(a)(?i:\\1)");
+    assert!(Regex::from_str(&regex.to_string()) == regex);
+    let pattern = "(?i)(\u{e9})\\1";
+    assert!(Regex::from_str(pattern).match_str("\u{e9}\u{c9}").is_some());
+    let ascii = RegexBuilder::new(pattern).ascii(true).build().unwrap();
+    assert!(ascii.match_str("\u{e9}\u{c9}").is_none());
+
+    assert!(Regex::try_from_str("(a)\\2").err() ==
+            Some(ParseError::InvalidBackreference { position : 3 }));
+    assert!(Regex::try_from_str("(?<a>x)\\k<b>").err() ==
+            Some(ParseError::UnknownGroupName { name : "b".to_string(), position : 7 }));
+    assert!(Regex::try_from_str("\\k<a").err() ==
+            Some(ParseError::InvalidBackreference { position : 0 }));
+}
+
+#[test]
+fn test_match_prefix() {
+    let regex = Regex::from_str("a+");
+    assert!(regex.match_prefix("aaab") == Some(3));
+    assert!(regex.match_prefix("baaa").is_none());
+    assert!(Regex::from_str("a*").match_prefix("b") == Some(0));
+    assert!(Regex::from_str("\u{e9}+").match_prefix("\u{e9}\u{e9}x") == Some(2));
+}
+
+#[test]
+fn test_lookbehind() {
+    let regex = Regex::from_str("(?<=@)\\w+");
+    assert!(regex.find("@user").map(|m| m.range()) == Some(1..5));
+    assert!(regex.find("user").is_none());
+    assert!(Regex::from_str("(?<=ab|cd)x").find("cdx").map(|m| m.range()) == Some(2..3));
+    assert!(Regex::from_str("a(?<=a)b").match_str("ab").is_some());
+    assert!(Regex::from_str("(?<=\u{e9})x").find("\u{e9}x").map(|m| m.range()) == Some(2..3));
+    assert!(Regex::from_str("(?<=a)").match_char_slice(&[]).is_none());
+
+    assert!(Regex::try_from_str("(?<=a+)b").err() ==
+            Some(ParseError::VariableLengthLookbehind { position : 0 }));
+    assert!(Regex::try_from_str("(?<=a|bc)d").err() ==
+            Some(ParseError::VariableLengthLookbehind { position : 0 }));
+    assert!(Regex::from_str("(?<=x|y)c").to_string() == "(?<=x|y)c");
+}
+
+#[test]
+fn test_negative_lookbehind() {
+    let regex = Regex::from_str("(?<!@)\\w+");
+    assert!(regex.find("bob").map(|m| m.range()) == Some(0..3));
+    // The run right after the '@' is rejected, but its tail isn't.
+    assert!(regex.find("@user").map(|m| m.range()) == Some(2..5));
+    assert!(regex.match_str("user").is_some());
+    assert!(Regex::from_str("a(?<!b)").match_str("a").is_some());
+    assert!(Regex::from_str("(?<!(a))b").captures("b").expect("").get(1).is_none());
+
+    assert!(Regex::try_from_str("(?<!a*)b").err() ==
+            Some(ParseError::VariableLengthLookbehind { position : 0 }));
+    assert!(Regex::from_str("(?<!x)c").to_string() == "(?<!x)c");
+}
+
+#[test]
+fn test_bounded_repeat() {
+    test_match("a{3}", "aaa");
+    test_no_match("a{3}", "aa");
+    test_no_match("a{3}", "aaaa");
+    test_match("a{2,}", "aaaaa");
+    test_no_match("a{2,}", "a");
+    test_match("(ab){1,2}c", "ababc");
+    test_no_match("(ab){1,2}c", "abababc");
+    test_match("a{0,2}a", "aaa");
+    assert!(Regex::from_str("a{1,3}?").find("aaa").map(|m| m.range()) == Some(0..1));
+
+    // A '{' that doesn't start a quantifier is a char.
+    test_match("a{", "a{");
+    test_match("a{x}", "a{x}");
+    test_match("a{,2}", "a{,2}");
+
+    assert!(Regex::try_from_str("a{3,1}").err() ==
+            Some(ParseError::InvalidRepeat { position : 1 }));
+    assert!(Regex::try_from_str("{2}").err() ==
+            Some(ParseError::QuantifierWithoutOperand { position : 0 }));
+    assert!(Regex::from_str("a{2}b{2,}c{0,3}?").to_string() == "a{2}b{2,}c{0,3}?");
+}
+
+#[test]
+fn test_possessive() {
+    // The possessive repeat won't give back the third 'a'.
+    test_no_match("a{1,3}+a", "aaa");
+    test_match("a{1,3}+a", "aaaa");
+    test_no_match("a*+a", "aaa");
+    test_no_match("a++a", "aaa");
+    test_no_match("a?+a", "a");
+    test_match("(ab|a)*+c", "ababc");
+    test_no_match("(ab|a)++b", "ab");
+    test_match("(a|ab)++b", "ab");
+    test_match("a++b", "aab");
+
+    // Groups from a possessive repeat that's abandoned don't linger.
+    assert!(Regex::from_str("(a)++b|a").captures("a").expect("").get(1).is_none());
+
+    assert!(Regex::from_str("a*+b{1,2}+c?+").to_string() == "a*+b{1,2}+c?+");
+    assert!(Regex::from_str("a*+b*").to_lazy().to_string() == "a*+b*?");
+}
+
+#[test]
+fn test_unbalanced_parens() {
+    assert!(Regex::try_from_str("(a").err() ==
+            Some(ParseError::UnbalancedParen { position : 0 }));
+    assert!(Regex::try_from_str("a)").err() ==
+            Some(ParseError::UnbalancedParen { position : 1 }));
+    assert!(Regex::try_from_str("(a(b)").err() ==
+            Some(ParseError::UnbalancedParen { position : 0 }));
+    assert!(Regex::try_from_str("a(?<n>b").err() ==
+            Some(ParseError::UnbalancedParen { position : 1 }));
+    assert!(Regex::try_from_str("(?(1)a").err() ==
+            Some(ParseError::UnbalancedParen { position : 0 }));
+    assert!(Regex::try_from_str("x(?<=a").err() ==
+            Some(ParseError::UnbalancedParen { position : 1 }));
+}
+
+#[test]
+fn test_nesting_limit() {
+    let deep = "(".repeat(1000) + "a" + &")".repeat(1000);
+    assert!(RegexBuilder::new(&deep).nesting_limit(64).build().err() ==
+            Some(ParseError::NestingTooDeep { limit : 64, position : 64 }));
+    let limit = "(".repeat(64) + "a" + &")".repeat(64);
+    assert!(RegexBuilder::new(&limit).nesting_limit(64).build().is_ok());
+    assert!(RegexBuilder::new("[[[a]]]").nesting_limit(1).build().is_err());
+    assert!(RegexBuilder::new("a**").nesting_limit(1).build().is_err());
+
+    // The limit can only be lowered.
+    assert!(RegexBuilder::new(&deep).nesting_limit(10000).build().err() ==
+            Some(ParseError::NestingTooDeep { limit : MAX_NESTING, position : MAX_NESTING }));
+}
+
+#[test]
+fn test_capture_groups() {
+    let regex = RegexBuilder::new("(a|b)+(?<n>c)").capture_groups(false).build().unwrap();
+    let mr = regex.match_str("abc").unwrap();
+    assert!(mr.len() == 1 && mr.get(&0).map(|s| s.as_str()) == Some("abc"));
+    assert!(regex.capture_names().count() == 1);
+    assert!(regex.match_str("ab").is_none());
+
+    let backref = "(a)\\1";
+    assert!(RegexBuilder::new(backref).capture_groups(false).build().is_err());
+}
+
+#[test]
+fn test_size_limit() {
+    let err = RegexBuilder::new("a{100000000}").size_limit(1000).build().err();
+    assert!(err == Some(ParseError::PatternTooLarge { limit : 1000, position : 0 }));
+    assert!(RegexBuilder::new("(ab){10}").size_limit(1000).build().is_ok());
+    assert!(RegexBuilder::new("(a{1000}){1000}").size_limit(1000000).build().is_err());
+    assert!(RegexBuilder::new("a{100000000}").build().is_ok());
+
+    // Syntax errors are still reported as such.
+    let unbalanced = "a)";
+    assert!(RegexBuilder::new(unbalanced).size_limit(1000).build().err() ==
+            Some(ParseError::UnbalancedParen { position : 1 }));
+}
+
+#[test]
+fn test_empty_regex() {
+    let regex = Regex::from_str("");
+    let mut mr = MatchResult::new();
+    mr.insert(0, "".to_string());
+    assert!(regex.match_str("") == Some(mr));
+    assert!(regex.match_str("a").is_none());
+    assert!(regex.find("abc").map(|m| m.range()) == Some(0..0));
+    assert!(regex.to_string() == "");
+}
+
+#[test]
+fn test_debug_tree() {
+    assert!(Regex::from_str("(a|b)*").debug_tree() ==
+            "Rpt *\n  Grp #1\n    Alt\n      Char 'a'\n      Char 'b'\n");
+    assert!(Regex::from_str("(?<x>[a-c]{2}?).\\1").debug_tree() ==
+            "Seq\n  Grp #1 <x>\n    Rpt {2}?\n      Class [a-c]\n  Any\n  Backref #1\n");
+}
+
+#[test]
+fn test_capture_history() {
+    let regex = RegexBuilder::new("((\\d),?)+").capture_history(true).build().unwrap();
+    let caps = regex.captures("1,2,3").expect("");
+    assert!(caps.get_all(2) == ["1", "2", "3"]);
+    assert!(caps.get_all(1) == ["1,", "2,", "3"]);
+    assert!(caps.get(2) == Some("3"));
+    assert!(caps.get_all(0) == ["1,2,3"]);
+    assert!(caps.get_all(3).is_empty());
+
+    // Only the repetitions on the path that matched are kept.
+    let regex = RegexBuilder::new("(a|ab)+c").capture_history(true).build().unwrap();
+    assert!(regex.captures("abac").expect("").get_all(1) == ["ab", "a"]);
+    let regex = RegexBuilder::new("(a)*+b|(a)*").capture_history(true).build().unwrap();
+    let caps = regex.captures("aa").expect("");
+    assert!(caps.get_all(1).is_empty());
+    assert!(caps.get_all(2) == ["a", "a"]);
+
+    // By default, only the last match is kept.
+    let caps = Regex::from_str("((\\d),?)+").captures("1,2,3").expect("");
+    assert!(caps.get_all(2) == ["3"]);
+    assert!(Regex::from_str("(a)?b").captures("b").expect("").get_all(1).is_empty());
+}
+
+#[test]
+fn test_capture_history_fields() {
+    use std::time::Duration;
+
+    let regex = RegexBuilder::new("((\\w+),?)+").capture_history(true).build().unwrap();
+    let record = regex.captures("name,age,city").expect("");
+    assert!(record.get_all(2) == ["name", "age", "city"]);
+    assert!(record.get(2) == Some("city"));
+
+    let fields : Vec<Vec<String>> = regex.captures_iter("a,b c")
+        .map(|caps| caps.get_all(2).to_vec())
+        .collect();
+    assert!(fields == vec!(vec!("a".to_string(), "b".to_string()), vec!("c".to_string())));
+
+    // Saving the groups at each repetition doesn't copy the history, so
+    // long records take linear time.
+    let record = "ab,".repeat(100000);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    assert!(regex.match_until(&record, deadline).unwrap().is_some());
+    let caps = regex.captures(&record).unwrap();
+    assert!(caps.get_all(2).len() == 100000);
+    assert!(caps.get_all(2).iter().all(|field| *field == "ab"));
+}
+
+#[test]
+fn test_match_until() {
+    use std::time::Duration;
+
+    // Each 'a' can be matched two ways, so failing takes 2^n tries.
+    let regex = Regex::from_str("(a|a)*b");
+    let s = "a".repeat(40);
+    let deadline = Instant::now() + Duration::from_millis(20);
+    assert!(regex.match_until(&s, deadline) == Err(MatchError::Timeout));
+
+    let deadline = Instant::now() + Duration::from_secs(60);
+    let mr = regex.match_until("aab", deadline).unwrap().expect("");
+    assert!(mr[&0] == "aab");
+    assert!(regex.match_until("aa", deadline) == Ok(None));
+}
+
+#[test]
+fn test_regex_eq_hash() {
+    use std::collections::HashMap;
+
+    let mut map = HashMap::new();
+    map.insert(Regex::from_str("(a|b)*c"), 1);
+    map.insert(Regex::from_str("(a|b)*c"), 2);
+    assert!(map.len() == 1);
+    assert!(map[&Regex::from_str("(a|b)*c")] == 2);
+
+    // Equality is structural, so different spellings of a pattern are equal.
+    assert!(Regex::from_str("[a-c]") == Regex::from_str("[cba]"));
+    assert!(Regex::from_str("a*") != Regex::from_str("a*?"));
+    assert!(Regex::from_str("(a)") != RegexBuilder::new("(a)").capture_history(true).build().unwrap());
+}
+
+#[test]
+fn test_anchors() {
+    assert!(Regex::from_str("^a").find("aa").map(|m| m.range()) == Some(0..1));
+    assert!(Regex::from_str("^a").find("ba").is_none());
+    assert!(Regex::from_str("a$").find("aa").map(|m| m.range()) == Some(1..2));
+    assert!(Regex::from_str("a$").find("ab").is_none());
+    test_match("^a*$", "aaa");
+    test_match("a\\^\\$", "a^$");
+    assert!(Regex::from_str("a|^").find_iter("ba").collect::<Vec<_>>() == vec!((0, 0), (1, 2)));
+    assert!(Regex::from_str("^a\\$\\^$").to_string() == "^a\\$\\^$");
+}
+
+#[test]
+fn test_match_suffix() {
+    assert!(Regex::from_str("\\d+$").match_suffix("abc123") == Some(3));
+    assert!(Regex::from_str("\\d+").match_suffix("abc123") == Some(3));
+    assert!(Regex::from_str("\\d+").match_suffix("123abc").is_none());
+    assert!(Regex::from_str("b*").match_suffix("ab") == Some(1));
+    assert!(Regex::from_str("").match_suffix("ab") == Some(2));
+}
+
+#[test]
+fn test_captures_iter() {
+    let regex = Regex::from_str("(\\w)(\\d)?");
+    let caps : Vec<Captures> = regex.captures_iter("a1 b c2").collect();
+    assert!(caps.len() == 3);
+    assert!(caps[0].get(0) == Some("a1") && caps[0].get(2) == Some("1"));
+    assert!(caps[1].get(1) == Some("b") && caps[1].get(2).is_none());
+    assert!(caps[2].get(0) == Some("c2"));
+}
+
+#[test]
+fn test_captures_scan() {
+    // The buffer is reused, so a group missing from a later match must not
+    // keep its value from an earlier one.
+    let regex = Regex::from_str("(\\w)(\\d)?");
+    let mut scanner = regex.captures_scan("a1 b c2");
+    let mut found = Vec::new();
+    while let Some(groups) = scanner.next() {
+        found.push(groups.clone());
+    }
+    let expected : Vec<MatchResult> = regex.captures_iter("a1 b c2")
+        .map(|caps| caps.match_result().clone())
+        .collect();
+    assert!(found == expected);
+    assert!(!found[1].contains_key(&2));
+    assert!(found[2].get(&0).map(|s| s.as_str()) == Some("c2"));
+}
+
+#[test]
+fn test_replace_all() {
+    let regex = Regex::from_str("(\\d)(\\w)");
+    let replaced = regex.replace_all("1a 2b c", "$2$1");
+    assert!(replaced == "a1 b2 c");
+    assert!(match replaced { Cow::Owned(_) => true, Cow::Borrowed(_) => false });
+
+    let unchanged = regex.replace_all("no digits", "$2$1");
+    assert!(unchanged == "no digits");
+    assert!(match unchanged { Cow::Borrowed(_) => true, Cow::Owned(_) => false });
+
+    // A match replaced with nothing still makes a new string.
+    let removed = regex.replace_all("1a", "");
+    assert!(removed.is_empty());
+    assert!(match removed { Cow::Owned(_) => true, Cow::Borrowed(_) => false });
+}
+
+#[test]
+fn test_find_with_captures() {
+    let (range, groups) = Regex::from_str("a(b+)c").find_with_captures("xxabbbcyy").unwrap();
+    assert!(range == (2..7));
+    assert!(groups.get(&0).map(|s| s.as_str()) == Some("abbbc"));
+    assert!(groups.get(&1).map(|s| s.as_str()) == Some("bbb"));
+    assert!(Regex::from_str("a(b+)c").find_with_captures("ac").is_none());
+}
+
+#[test]
+fn test_longest_prefix_match() {
+    assert!(Regex::from_str("abcd").longest_prefix_match("abx") == 2);
+    assert!(Regex::from_str("a(bc|bd)e").longest_prefix_match("abdx") == 3);
+    assert!(Regex::from_str("x{5}").longest_prefix_match("xxxy") == 3);
+    assert!(Regex::from_str("\u{3bb}+!").longest_prefix_match("\u{3bb}\u{3bb}") == 2);
+    assert!(Regex::from_str("ab").longest_prefix_match("abab") == 2);
+    assert!(Regex::from_str("b").longest_prefix_match("a") == 0);
+}
+
+#[test]
+fn test_parse_error_render() {
+    let err = Regex::try_from_str("a(b").err().unwrap();
+    assert!(err.render("a(b") == format!("{}\na(b\n ^", err));
+
+    // The caret is placed by chars, not bytes.
+    let err = Regex::try_from_str("\u{3bb}\u{3bb})").err().unwrap();
+    assert!(err.position() == 4);
+    assert!(err.render("\u{3bb}\u{3bb})").ends_with("\n  ^"));
+}
+
+#[test]
+fn test_iter_spans() {
+    let caps = Regex::from_str("(a)(b)?c").captures("ac").unwrap();
+    let spans : Vec<Option<Range<usize>>> = caps.iter_spans().collect();
+    assert!(spans == vec!(Some(0..2), Some(0..1), None));
+
+    let caps = Regex::from_str("([a-z])").captures_iter("\u{3bb}x").next().unwrap();
+    assert!(caps.iter_spans().next() == Some(Some(2..3)));
+}
+
+#[test]
+fn test_incremental() {
+    let regex = Regex::from_str("a(bc)");
+    let mut m = regex.incremental();
+    m.feed("ab");
+    m.feed("c");
+    let mr = m.finish().unwrap();
+    assert!(mr.get(&1).map(|s| s.as_str()) == Some("bc"));
+
+    let mut m = regex.incremental();
+    m.feed("abc");
+    m.feed("d");
+    assert!(m.finish().is_none());
+}
+
+#[test]
+fn test_full_match() {
+    let regex = Regex::from_str("abc");
+    let mut mr = MatchResult::new();
+    mr.insert(0, "abc".to_string());
+    assert!(regex.full_match("abc") == FullMatch::Full(mr));
+    assert!(regex.full_match("abcde") == FullMatch::PrefixOnly { consumed : 3 });
+    assert!(regex.full_match("ab") == FullMatch::NoMatch);
+    assert!(regex.full_match("xabc") == FullMatch::NoMatch);
+}
+
+#[test]
+fn test_search_start_anchor() {
+    // Each match must start where the last one ended.
+    let regex = Regex::from_str("\\G\\d,?");
+    let found : Vec<String> = regex.captures_iter("1,2,x3")
+        .map(|caps| caps.get(0).expect("").to_string())
+        .collect();
+    assert!(found == vec!("1,", "2,"));
+    assert!(regex.find_iter("1,2,x3").count() == 2);
+
+    assert!(regex.find_at("x3", 1) == Some((1, 2)));
+    assert!(regex.find("x3").is_none());
+    assert!(Regex::from_str("\\Ga").to_string() == "\\Ga");
+}
+
+#[test]
+fn test_is_anchored() {
+    let regex = Regex::from_str("^abc");
+    assert!(regex.is_anchored_start() && !regex.is_anchored_end());
+    let regex = Regex::from_str("abc$");
+    assert!(!regex.is_anchored_start() && regex.is_anchored_end());
+    let regex = Regex::from_str("abc");
+    assert!(!regex.is_anchored_start() && !regex.is_anchored_end());
+
+    assert!(Regex::from_str("\\Aa|(^b)").is_anchored_start());
+    assert!(!Regex::from_str("^a|b").is_anchored_start());
+    assert!(Regex::from_str("a\\z").is_anchored_end());
+    assert!(Regex::from_str("^a").to_lazy().is_anchored_start());
+
+    let regex = Regex::from_str("^abc$");
+    assert!(regex.is_fully_anchored());
+    assert!(regex.find("abc").map(|m| m.range()) == Some(0..3));
+    assert!(regex.find("abcabc").is_none());
+    assert!(regex.find("xabc").is_none());
+    assert!(regex.find_iter("abc").count() == 1);
+    assert!(Regex::from_str("^a$|\\Ab\\z").is_fully_anchored());
+    assert!(!Regex::from_str("^a|b$").is_fully_anchored());
+    assert!(!Regex::from_str("^abc").is_fully_anchored());
+
+    // Anchored searches still find the match at the start.
+    assert!(Regex::from_str("^a").find("ab").map(|m| m.range()) == Some(0..1));
+    assert!(Regex::from_str("\\Aa").find("ba").is_none());
+    assert!(Regex::from_str("^a").find_at("aa", 1).is_none());
+    assert!(Regex::from_str("\\Aa\\z").to_string() == "\\Aa\\z");
+}
+
+#[test]
+fn test_case_insensitive() {
+    test_match("(?i)abc", "AbC");
+    test_no_match("(?i)abc", "abd");
+    test_match("a(?i)b", "aB");
+    test_no_match("a(?i)b", "AB");
+    test_match("(?i)a(?-i)b", "Ab");
+    test_no_match("(?i)a(?-i)b", "AB");
+
+    // Flags last until the end of the enclosing group.
+    test_match("((?i)a)a", "Aa");
+    test_no_match("((?i)a)a", "AA");
+
+    // Classes and ranges are folded too.
+    test_match("(?i)[a-c]", "B");
+    test_no_match("(?i)[a-c]", "D");
+    test_no_match("(?i)[^a-c]", "B");
+    test_match("(?i)[^a-c]", "d");
+    test_match("(?i)\\u{e9}", "\u{c9}");
+    test_match("(?i)[\u{3b1}-\u{3c9}]+", "\u{391}\u{3a9}");
+    test_match("(?i)\u{3c3}", "\u{3c2}");
+    test_match("(?i)k", "\u{212a}");
+    test_match("(?i)[^a]", "\u{212a}");
+    test_no_match("(?i)[^k]", "\u{212a}");
+    test_match("(?i)1", "1");
+
+    let regex = RegexBuilder::new("[a-c]x").case_insensitive(true).build().unwrap();
+    assert!(regex.match_str("BX").is_some());
+
+    assert!(Regex::try_from_str("(?x)").err() ==
+            Some(ParseError::InvalidGroup { position : 0 }));
+}
+
+#[test]
+fn test_scoped_flags() {
+    test_match("(?i:ab)c", "ABc");
+    test_no_match("(?i:ab)c", "ABC");
+    test_match("(?i)a(?-i:b)c", "AbC");
+    test_no_match("(?i)a(?-i:b)c", "ABC");
+    test_match("(?i:a|b)+c", "aBbc");
+
+    // Scoped flag groups don't capture.
+    let caps = Regex::from_str("(?i:(a))(b)").captures("Ab").unwrap();
+    assert!(caps.get(1) == Some("A") && caps.get(2) == Some("b"));
+    assert!(Regex::try_from_str("(?i:a").is_err());
+}
+
+#[test]
+fn test_from_chars() {
+    let mut itr = "a+b/rest".chars();
+    let regex = Regex::from_chars(&mut itr, '/').unwrap();
+    assert!(itr.as_str() == "rest");
+    assert!(regex.match_str("aab").is_some());
+
+    // The terminator can be escaped, and doesn't count in a group or class.
+    let mut itr = "a\\/b([/])/x".chars();
+    let regex = Regex::from_chars(&mut itr, '/').unwrap();
+    assert!(itr.as_str() == "x");
+    assert!(regex.match_str("a/b/").is_some());
+
+    let mut itr = "xy(a/b)".chars();
+    itr.next();
+    assert!(Regex::from_chars(&mut itr, '/').err() ==
+            Some(ParseError::MissingTerminator { position : 6 }));
+    let mut itr = "a)/".chars();
+    assert!(Regex::from_chars(&mut itr, '/').err() ==
+            Some(ParseError::UnbalancedParen { position : 1 }));
+}
+
+#[test]
+fn test_optimize() {
+    // Runs of chars become one literal node, but look the same from outside.
+    let regex = Regex::from_str("ab(cd)*ef|g");
+    assert!(format!("{:?}", regex) == "Regex Lit{ab}(Lit{cd})*Lit{ef}|Char{g}");
+    assert!(regex.to_string() == "ab(cd)*ef|g");
+    test_match("ab(cd)*ef|g", "abcdcdef");
+    test_no_match("ab(cd)*ef|g", "abcdce");
+    test_match("x\u{e9}\u{e9}y", "x\u{e9}\u{e9}y");
+    assert!(Regex::from_str("abc").find("xxabcx").map(|m| m.range()) == Some(2..5));
+
+    // Nesting collapses to just the groups.
+    assert!(Regex::from_str("(((a)))").debug_tree() ==
+            "Grp #1\n  Grp #2\n    Grp #3\n      Char 'a'\n");
+
+    // Nested and empty sequences in a tree are merged away.
+    let ast = Ast::Concat(vec!(
+        Ast::Char('a'),
+        Ast::Concat(vec!(Ast::Char('b'), Ast::Concat(Vec::new()), Ast::Char('c'))),
+        Ast::Concat(Vec::new())
+    ));
+    let regex = Regex::from_ast(&ast, vec!(None), NodeOptions::default());
+    assert!(format!("{:?}", regex) == "Regex Lit{abc}");
+    assert!(regex.match_str("abc").is_some());
+    assert!(regex.ast() == Regex::from_str("abc").ast());
+}
+
+#[test]
+fn test_literal() {
+    test_match("hello", "hello");
+    test_no_match("hello", "hellp");
+    test_no_match("hello", "hell");
+    assert!(Regex::from_str("lo w").find("hello world").map(|m| m.range()) == Some(3..7));
+
+    let chars : Vec<char> = "h\u{e9}llo".chars().collect();
+    assert!(Regex::from_str("h\u{e9}llo").match_char_slice(&chars).is_some());
+    assert!(Regex::from_str("h\u{e9}llo!").match_char_slice(&chars).is_none());
+}
+
+#[test]
+fn test_parse_fuzz() {
+    // Random patterns made mostly of metachars must give a regex or an
+    // error, never a panic, and a regex must print as a pattern giving the
+    // same regex.
+    let alphabet : Vec<char> = "()[]{}<>?*+|\\^$.-&:=!,^k0129adiszAGwu ".chars().collect();
+    let mut state : u64 = 0x2545_f491_4f6c_dd1d;
+    for _ in 0..20000 {
+        let mut pattern = String::new();
+        // A xorshift generator keeps the test deterministic.
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        for _ in 0..(state % 12) {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            pattern.push(alphabet[(state % alphabet.len() as u64) as usize]);
+        }
+        if let Ok(regex) = Regex::new(&pattern) {
+            let _ = regex.find("aa(b)\n1");
+            let shown = regex.to_string();
+            assert!(Regex::new(&shown).ok() == Some(regex), "{:?} shown as {:?}", pattern, shown);
+        }
+    }
+
+    let deep = "(".repeat(100000) + &")".repeat(100000);
+    assert!(Regex::new(&deep).err() == Some(ParseError::NestingTooDeep {
+        limit : MAX_NESTING,
+        position : MAX_NESTING
+    }));
+    assert!(Regex::new(&("a".to_string() + &"?".repeat(100000))).is_err());
+    let limit = "(".repeat(MAX_NESTING) + "a" + &")".repeat(MAX_NESTING);
+    assert!(Regex::new(&limit).expect("").match_str("a").is_some());
+    let limit = "(?:".repeat(MAX_NESTING - 1) + "a?" + &")?".repeat(MAX_NESTING - 1);
+    assert!(Regex::new(&limit).expect("").match_str("a").is_some());
+    assert!(Regex::new(&(limit + "*")).is_err());
+    assert!(Regex::new(&"(?:".repeat(100000)).is_err());
+    assert!(Regex::new(&"[".repeat(100000)).is_err());
+    assert!(Regex::try_from_str("\\").is_err());
+}
+
+#[test]
+#[cfg(not(feature = "unicode"))]
+fn test_ascii_classes() {
+    test_no_match("\\d", "\u{663}");
+    test_no_match("\\w", "é");
+    test_match("\\s\\S", "\tx");
+}
+
+#[test]
+#[cfg(feature = "unicode")]
+fn test_unicode_classes() {
+    // Arabic-Indic digits.
+    test_match("\\d+", "\u{660}\u{663}\u{669}");
+    test_no_match("\\d", "\u{66A}");
+    test_match("\\w+", "héllo_\u{3b1}");
+    test_match("\\s", "\u{2003}");
+    test_no_match("\\D", "\u{1D7CE}");
+}
+
+#[test]
+fn test_dot_matches_grapheme() {
+    // An 'e' with a combining acute accent, and a flag.
+    let regex = RegexBuilder::new("a.c").dot_matches_grapheme(true).build().unwrap();
+    assert!(regex.match_str("ae\u{301}c").is_some());
+    assert!(regex.match_str("a\u{1F1EB}\u{1F1F7}c").is_some());
+    assert!(regex.match_str("a\nc").is_none());
+    assert!(Regex::from_str("a.c").match_str("ae\u{301}c").is_none());
+    assert!(regex != Regex::from_str("a.c"));
+
+    let regex = RegexBuilder::new("(.)(.)").dot_matches_grapheme(true).build().unwrap();
+    let caps = regex.captures("e\u{301}\u{1F469}\u{200D}\u{1F4BB}").unwrap();
+    assert!(caps.get(1) == Some("e\u{301}"));
+    assert!(caps.get(2) == Some("\u{1F469}\u{200D}\u{1F4BB}"));
+}
+
+#[test]
+fn test_dollar_before_newline() {
+    let regex = RegexBuilder::new("abc$").dollar_before_newline(true).build().unwrap();
+    assert!(regex.find("abc\n").map(|m| m.range()) == Some(0..3));
+    assert!(regex.find("abc").map(|m| m.range()) == Some(0..3));
+    assert!(regex.find("abc\n\n").is_none());
+    assert!(regex.find("abcd").is_none());
+    assert!(Regex::from_str("abc$").find("abc\n").is_none());
+}
+#[test]
+fn test_repeat_alternation() {
+    // Each repetition can take a different alternative, and earlier ones
+    // are retried if the rest fails.
+    test_match("(ab|a)*c", "abac");
+    test_match("(ab|a)*c", "aac");
+    test_match("(a|ab)*c", "abababc");
+    test_match("(?:a|ab)*c", "abaabc");
+    test_no_match("(ab|a)*c", "abbc");
+    assert!(Regex::from_str("(ab|a)*c").captures("abac").unwrap().get(1) == Some("a"));
+}
+
+#[test]
+fn test_word_boundary() {
+    let regex = Regex::from_str("\\bfoo\\b");
+    assert!(regex.find("a foo b").map(|m| m.range()) == Some(2..5));
+    assert!(regex.find("foo-bar").map(|m| m.range()) == Some(0..3));
+    assert!(regex.find("food").is_none());
+    assert!(Regex::from_str("\\Boo\\B").find("foo boot").map(|m| m.range()) == Some(5..7));
+    assert!(Regex::from_str("a\\b\\B").to_string() == "a\\b\\B");
+}
+
+#[test]
+fn test_word_chars() {
+    let regex = RegexBuilder::new("\\bfoo-bar\\b").word_chars(|c| c.is_alphanumeric() || c == '-')
+        .build().unwrap();
+    assert!(regex.find("a foo-bar b").map(|m| m.range()) == Some(2..9));
+    assert!(regex.find("foo-bar-baz").is_none());
+    assert!(Regex::from_str("\\bfoo-bar\\b").find("foo-bar-baz").map(|m| m.range()) == Some(0..7));
+
+    let regex = RegexBuilder::new("\\w+").word_chars(|c| c.is_alphanumeric() || c == '-')
+        .build().unwrap();
+    assert!(regex.match_str("foo-bar").is_some());
+    assert!(regex.match_str("foo_bar").is_none());
+    assert!(regex != Regex::from_str("\\w+"));
+}
+
+#[test]
+fn test_long_repeat() {
+    use std::time::Duration;
+
+    // Each iteration of a repeat steps along the input by position, and
+    // the iterations are kept on the heap, so long repeats take linear time
+    // whether or not the repeated node can match more than one way.
+    let s = "a".repeat(100000);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    assert!(Regex::from_str("a*").match_until(&s, deadline).unwrap().is_some());
+    assert!(Regex::from_str("a*b").match_until(&s, deadline) == Ok(None));
+
+    let s = "ab".repeat(50000);
+    assert!(Regex::from_str("(ab)*").match_until(&s, deadline).unwrap().is_some());
+    assert!(Regex::from_str("(?:a|b)*").match_until(&s, deadline).unwrap().is_some());
+    assert!(Regex::from_str("(?:a|b)*c").match_until(&s, deadline) == Ok(None));
+}
+
+#[test]
+fn test_long_group_repeat() {
+    // Repeats of groups and alternations can backtrack into each
+    // repetition, but mustn't use stack for each one.
+    let s = "ab".repeat(20000);
+    let regex = Regex::from_str("(ab)*");
+    let result = regex.match_str(&s).unwrap();
+    assert!(result[&0] == s);
+    assert!(result[&1] == "ab");
+    assert!(Regex::from_str("(?:a|b)*c").match_str(&s).is_none());
+
+    // Backtracking into a repetition still finds the same match.
+    let mut mr = MatchResult::new();
+    mr.insert(1, "a".to_string());
+    test_result("(a|ab)*c", "abac", mr);
+    let mut mr = MatchResult::new();
+    mr.insert(1, "a".to_string());
+    test_result("(a|ab)*?b", "abab", mr);
+}
+
+#[test]
+fn test_display_round_trip() {
+    let patterns = [
+        "", "a", "abc", "a|b|", "(a)(?<x>b)", "(?:ab)+", "(?:a|b)c", "a(?:b|c)d",
+        "a*b+?c??d*+", "a{2}", "a{2,}?", "a{2,5}+", "(?:a*)*", "(?:a{2}){3}", "((a)*)+",
+        "[a-z]", "[^a-z0-9_]", "[\\-\\]\\^\\[\\\\]", "[a\\-z]", "[a&]", "[a-z&&[^m]]",
+        "\\d\\D\\w\\W\\s\\S", ".", "^a$", "\\Aa\\z", "a\\Z", "\\Ga", "\\bfoo\\B",
+        "\\(\\)\\[\\]\\{\\}\\*\\+\\?\\.\\|\\^\\$\\\\", "a\\{", "x{", "x{a}",
+        "(a)\\1", "(a)\\1(?:0)", "(?<n>a)\\k<n>", "(a)(?(1)b|c)", "(a)(?(1)b|(?:c|d))",
+        "(a)(?(1)(?:b|c)|d)",
+        "(?<=ab)c", "(?<!a|b)c", "(?i)aBc", "(?i:a)b", "a(?i)b", "[\\t\\n]", "\\x41\\u{3bb}",
+        "\u{3bb}+", "(?:)", "(?:)*", "()", "(|a)", "a||b", "ab|ac|ad",
+    ];
+    for pattern in &patterns {
+        let regex = match Regex::new(pattern) {
+            Ok(regex) => regex,
+            Err(e) => panic!("{:?} failed to parse: {}", pattern, e)
+        };
+        let shown = regex.to_string();
+        match Regex::new(&shown) {
+            Ok(reparsed) => {
+                assert!(reparsed == regex, "{:?} shown as {:?} parses differently", pattern, shown)
+            }
+            Err(e) => panic!("{:?} shown as {:?} failed to parse: {}", pattern, shown, e)
+        }
+    }
+}
+
+#[test]
+fn test_hv_space() {
+    test_match("a\\hb", "a\tb");
+    test_no_match("a\\hb", "a\nb");
+    test_match("a\\vb", "a\nb");
+    test_match("\\v+", "\r\n\u{b}\u{c}");
+    test_no_match("\\v", " ");
+    test_match("\\H\\V", "\na");
+    test_no_match("\\H", "\t");
+    test_match("[\\h,]+", " ,\t");
+    test_match("[^\\v]+", "a b");
+    test_no_match("[^\\v]", "\n");
+    test_match("[\\d\\-]+", "12-3");
+    assert!(Regex::try_from_str("[a-\\d]").is_err());
+    assert!(Regex::try_from_str("[\\d-z]").is_err());
+}
+
+#[test]
+fn test_without_captures() {
+    let regex = Regex::from_str("(a|b)(?<x>c)*d");
+    let stripped = regex.without_captures();
+    assert!(regex.group_count() == 2);
+    assert!(stripped.group_count() == 0);
+    assert!(stripped.to_string() == "(?:a|b)c*d");
+    for s in &["ad", "bccd", "abd", "d", "acdd"] {
+        assert!(regex.match_str(s).is_some() == stripped.match_str(s).is_some());
+        assert!(regex.find(s) == stripped.find(s));
+    }
+
+    // Groups a backreference depends on keep capturing.
+    let stripped = Regex::from_str("(a)(?<x>b)\\k<x>").without_captures();
+    assert!(stripped.group_count() == 1);
+    assert!(stripped.to_string() == "a(?<x>b)\\k<x>");
+    assert!(stripped.match_str("abb").is_some());
+    assert!(stripped.match_str("aba").is_none());
+
+    // So do groups a conditional depends on.
+    let stripped = Regex::from_str("(a)?(b)(?(1)c|d)").without_captures();
+    assert!(stripped.group_count() == 1);
+    assert!(stripped.to_string() == "(a)?b(?(1)c|d)");
+    assert!(stripped.match_str("abc").is_some());
+    assert!(stripped.match_str("bd").is_some());
+    assert!(stripped.match_str("abd").is_none());
+}
+
+#[test]
+fn test_match_str_lazy() {
+    let regex = Regex::from_str("(a+)(a+)");
+    let greedy = regex.match_str("aaaa").unwrap();
+    assert!(greedy[&1] == "aaa" && greedy[&2] == "a");
+    let lazy = regex.match_str_lazy("aaaa").unwrap();
+    assert!(lazy[&1] == "a" && lazy[&2] == "aaa");
+    assert!(regex.match_str_lazy("a").is_none());
+}
+
+#[test]
+fn test_top_level_alternatives() {
+    assert!(Regex::from_str("a|b|c").top_level_alternatives() == 3);
+    assert!(Regex::from_str("abc").top_level_alternatives() == 1);
+    assert!(Regex::from_str("(a|b)c").top_level_alternatives() == 1);
+    assert!(Regex::from_str("a|").top_level_alternatives() == 2);
+    assert!(Regex::from_str("ab|ac|d").top_level_alternatives() == 3);
+    assert!(Regex::from_str("ab|ac").top_level_alternatives() == 2);
+
+    let regex = Regex::from_str("a|b").or(&Regex::from_str("c"));
+    assert!(regex.top_level_alternatives() == 3);
+    assert!(regex.concat(&Regex::from_str("d")).top_level_alternatives() == 1);
+    assert!(regex.repeat(0, None).top_level_alternatives() == 1);
+    assert!(regex.to_lazy().top_level_alternatives() == 3);
+}
+
+#[test]
+fn test_negated_class_at_end() {
+    // A negated class still needs a char to match.
+    test_no_match("[^a]", "");
+    test_match("[^a]", "b");
+    test_no_match("[^a]", "a");
+    test_no_match("x[^a]", "x");
+    test_match("[^a]*", "");
+    assert!(Regex::from_str("[^a]").find("xa").is_some());
+    assert!(Regex::from_str("[^a]").find("a").is_none());
+}
+
+#[test]
+fn test_is_match_dfa() {
+    let patterns = [
+        "a", "abc", "a|bc", "(a|b)*c", "a+b?", "^ab", "ab$", "\\Aa*\\z", "x{2,3}y", "x{2,}",
+        "[^a-c]d", "(?i)AbC", ".*z", "(?:a|)+b", "", "^$", "\\d+\\s\\w*",
+    ];
+    let inputs = [
+        "", "a", "abc", "xbc", "aabac", "zzab", "xxxy", "xy", "Abc", "aab", "a\nz", "12 x",
+    ];
+    for pattern in &patterns {
+        let regex = Regex::from_str(pattern);
+        for s in &inputs {
+            assert!(regex.is_match_dfa(s) == Ok(regex.find(s).is_some()),
+                    "{:?} on {:?}", pattern, s);
+        }
+    }
+
+    // No backtracking, so this takes no time at all.
+    let s = "a".repeat(1000);
+    assert!(Regex::from_str("(a|a)*b").is_match_dfa(&s) == Ok(false));
+
+    let regex = RegexBuilder::new("b$").dollar_before_newline(true).build().unwrap();
+    assert!(regex.is_match_dfa("ab\n") == Ok(true));
+    assert!(regex.is_match_dfa("ab\n\n") == Ok(false));
+
+    assert!(Regex::from_str("(a)\\1").is_match_dfa("aa")
+            == Err(MatchError::Unsupported { construct : "backreferences" }));
+    assert!(Regex::from_str("(?<=a)b").is_match_dfa("ab").is_err());
+    assert!(Regex::from_str("\\ba").is_match_dfa("a").is_err());
+    assert!(Regex::from_str("a*+").is_match_dfa("a").is_err());
+    assert!(Regex::from_str("(?:a{100}){100}").is_match_dfa("a").is_err());
+}
+
+#[test]
+fn test_regex_syntax() {
+    let regex = RegexBuilder::new("a*b").syntax(RegexSyntax::new().literal('*')).build().unwrap();
+    assert!(regex.match_str("a*b").is_some());
+    assert!(regex.match_str("aab").is_none());
+    assert!(regex.to_string() == "a\\*b");
+
+    let syntax = RegexSyntax::new().escape('%').literal('(').literal(')').clone();
+    let regex = RegexBuilder::new("f(%d+)%.\\[%%x]").syntax(&syntax).build().unwrap();
+    assert!(regex.match_str("f(12).\\%").is_some());
+    assert!(regex.match_str("f(12).\\x").is_some());
+    assert!(regex.match_str("f12.\\x").is_none());
+    assert!(RegexBuilder::new("a%").syntax(&syntax).build()
+            == Err(ParseError::UnterminatedEscape { position : 1 }));
+}
+
+#[test]
+fn test_find_ranges() {
+    assert!(Regex::from_str("\\d+").find_ranges("a12b3") == vec!(1..3, 4..5));
+    assert!(Regex::from_str("x*").find_ranges("ab") == vec!(0..0, 1..1, 2..2));
+    assert!(Regex::from_str("\u{3bb}").find_ranges("a\u{3bb}\u{3bb}") == vec!(1..3, 3..5));
+    assert!(Regex::from_str("z").find_ranges("ab").is_empty());
+}
+
+#[test]
+fn test_end_before_newline_anchor() {
+    test_match("abc\\Z", "abc");
+    test_match("abc\\Z\n", "abc\n");
+    assert!(Regex::from_str("abc\\Z").find("abc\n").map(|m| m.range()) == Some(0..3));
+    assert!(Regex::from_str("abc\\Z").find("abc\n\n").is_none());
+    assert!(Regex::from_str("abc\\z").find("abc\n").is_none());
+    assert!(Regex::from_str("abc\\z").find("abc").map(|m| m.range()) == Some(0..3));
+    assert!(Regex::from_str("a\\Z").to_string() == "a\\Z");
+    let regex = RegexBuilder::new("a$").dollar_before_newline(true).build().unwrap();
+    assert!(regex.to_string() == "a\\Z");
+}
+
+#[test]
+fn test_ascii_mode() {
+    let ascii = |pattern : &str| RegexBuilder::new(pattern).ascii(true).build().unwrap();
+    assert!(ascii("\\w").match_str("\u{e9}").is_none());
+    assert!(ascii("\\w").match_str("e").is_some());
+    assert!(ascii("\\W").match_str("\u{e9}").is_some());
+    assert!(ascii("[\\d]").match_str("\u{663}").is_none());
+    assert!(ascii(".").match_str("\u{e9}").is_none());
+    assert!(ascii(".").match_str("\n").is_none());
+    assert!(ascii("(?i)\u{e9}").match_str("\u{c9}").is_none());
+    assert!(ascii("(?i)[a-c]").match_str("B").is_some());
+    assert!(Regex::from_str("(?i)\u{e9}").match_str("\u{c9}").is_some());
+    assert!(ascii("\\bx").find("\u{e9}x").map(|m| m.range()) == Some(2..3));
+    assert!(ascii("\\bx") != Regex::from_str("\\bx"));
+}
+
+#[test]
+fn test_consume() {
+    let (mr, rest) = Regex::from_str("\\d+").consume("123abc").unwrap();
+    assert!(mr[&0] == "123");
+    assert!(rest == "abc");
+    assert!(Regex::from_str("[a-z]+=").consume(rest).is_none());
+
+    let (mr, rest) = Regex::from_str("([a-z]+)=").consume("key=value").unwrap();
+    assert!(mr[&1] == "key" && rest == "value");
+    assert!(Regex::from_str("\\d").consume("abc").is_none());
+    assert!(Regex::from_str("x*").consume("abc").unwrap().1 == "abc");
+}
+
+#[test]
+fn test_match_with_repeat_limit() {
+    // Failing tries every way of splitting the 'a's between repetitions.
+    let regex = Regex::from_str("(a*)*b");
+    let s = "a".repeat(30);
+    assert!(regex.match_with_repeat_limit(&s, 1000)
+            == Err(MatchError::RepeatLimitExceeded { limit : 1000 }));
+
+    let mr = regex.match_with_repeat_limit("aab", 1000).unwrap().expect("");
+    assert!(mr[&0] == "aab");
+    assert!(Regex::from_str("a*b").match_with_repeat_limit(&s, 1000) == Ok(None));
+    assert!(Regex::from_str("a*").match_with_repeat_limit(&s, 10).is_err());
+}
+
+#[test]
+fn test_captures_to_json() {
+    let caps = Regex::from_str("(\\w+)=(?<value>.*)").captures("say=\"hi\"\\").unwrap();
+    assert!(caps.to_json() ==
+            "{\"0\":\"say=\\\"hi\\\"\\\\\",\"1\":\"say\",\"value\":\"\\\"hi\\\"\\\\\"}");
+
+    let caps = Regex::from_str("(a)(b)?").captures("a").unwrap();
+    assert!(caps.to_json() == "{\"0\":\"a\",\"1\":\"a\",\"2\":null}");
+
+    // Keys stay unique when groups share a name.
+    let regex = Regex::from_str("(?<x>a)").concat(&Regex::from_str("(?<x>b)"));
+    let caps = regex.captures("ab").unwrap();
+    assert!(caps.to_json() == "{\"0\":\"ab\",\"x\":\"a\",\"2\":\"b\"}");
+}
+
+#[test]
+fn test_longest_alternative() {
+    let longest = |pattern : &str| {
+        RegexBuilder::new(pattern).longest_alternative(true).build().unwrap()
+    };
+
+    assert!(Regex::from_str("(a|ab)").find("ab").map(|m| m.range()) == Some(0..1));
+    let regex = longest("(a|ab)");
+    assert!(regex.find("ab").map(|m| m.range()) == Some(0..2));
+    assert!(regex.captures("ab").unwrap().get(1) == Some("ab"));
+
+    // Only the alternation's own match counts, but if the longer branch
+    // fails later, the shorter one is still tried.
+    assert!(longest("(?:ab|a)bc").find("abc").map(|m| m.range()) == Some(0..3));
+    assert!(longest("(?:if|in|int)\\b").find("int x").map(|m| m.range()) == Some(0..3));
+    assert!(longest("(x|y)|(xy)").captures("xy").unwrap().get(1).is_none());
+
+    // The option changes what matches, so it counts for equality.
+    assert!(longest("a|ab") != Regex::from_str("a|ab"));
+    assert!(longest("a|ab") == longest("a|ab"));
+}
+
+#[test]
+fn test_derived_options() {
+    // Regexes derived from a regex keep the options it was built with.
+    let derived = |regex : Regex| vec!(
+        regex.to_lazy(),
+        regex.concat(&Regex::from_str("")),
+        regex.or(&Regex::from_str("z")),
+        regex.repeat(1, Some(1)),
+        regex.reverse().unwrap(),
+        regex.without_captures()
+    );
+
+    let regex = RegexBuilder::new("(a|aa)").longest_alternative(true).build().unwrap();
+    for regex in derived(regex) {
+        assert!(regex.find("aa").map(|m| m.range()) == Some(0..2));
+    }
+    let regex = RegexBuilder::new("\\bx\\b").ascii(true).build().unwrap();
+    for regex in derived(regex) {
+        assert!(regex.find("\u{e9}x\u{e9}").map(|m| m.range()) == Some(2..3));
+    }
+    let regex = RegexBuilder::new("(.)").dot_matches_grapheme(true).build().unwrap();
+    for regex in derived(regex) {
+        assert!(regex.match_str("e\u{301}").is_some());
+    }
+    let regex = RegexBuilder::new("(?s:.)").dot_matches_grapheme(true).build().unwrap();
+    for regex in derived(regex) {
+        assert!(regex.match_str("\n").is_some());
+        assert!(regex.match_str("e\u{301}").is_some());
+    }
+    let regex = RegexBuilder::new("\\b\\w+").word_chars(|c| c.is_alphanumeric() || c == '-')
+        .build().unwrap();
+    for regex in derived(regex) {
+        assert!(regex.match_str("a-b").is_some());
+        assert!(regex.match_str("a_b").is_none());
+    }
+}
+
+#[test]
+fn test_unsupported_construct() {
+    let unsupported = |pattern : &str| match Regex::try_from_str(pattern).err() {
+        Some(ParseError::UnsupportedConstruct { construct, position }) => {
+            Some((construct, position))
+        }
+        _ => None
+    };
+    assert!(unsupported("a(?=b)") == Some(("lookahead", 1)));
+    assert!(unsupported("(?!b)") == Some(("lookahead", 0)));
+    assert!(unsupported("(?>a+)") == Some(("atomic groups", 0)));
+    assert!(unsupported("a\\p{L}") == Some(("Unicode properties", 1)));
+    assert!(unsupported("[\\PL]") == Some(("Unicode properties", 1)));
+
+    // Lookbehind is supported.
+    assert!(unsupported("(?<=a)b").is_none());
+    assert!(Regex::try_from_str("(?=a)").err().unwrap().to_string()
+            == "Syntax error at position 0. Unsupported construct: lookahead.");
+}
+
+#[test]
+fn test_split_inclusive() {
+    let regex = Regex::from_str("\n");
+    let lines : Vec<&str> = regex.split_inclusive("one\ntwo\n\nthree").collect();
+    assert!(lines == vec!("one\n", "two\n", "\n", "three"));
+    let lines : Vec<&str> = regex.split_inclusive("one\n").collect();
+    assert!(lines == vec!("one\n"));
+    assert!(regex.split_inclusive("").next().is_none());
+
+    let regex = Regex::from_str("[,;] *");
+    let pieces : Vec<&str> = regex.split_inclusive("a, b;c").collect();
+    assert!(pieces == vec!("a, ", "b;", "c"));
+    let regex = Regex::from_str("x*");
+    let pieces : Vec<&str> = regex.split_inclusive("ab").collect();
+    assert!(pieces == vec!("a", "b"));
+}
+
+#[test]
+fn test_split_captures() {
+    let delim = |s : &str| {
+        let mut mr = MatchResult::new();
+        mr.insert(0, s.to_string());
+        Piece::Delim(mr)
+    };
+
+    let regex = Regex::from_str("[,;]");
+    let pieces : Vec<Piece> = regex.split_captures("a,b;c").collect();
+    assert!(pieces == vec!(Piece::Text("a"), delim(","), Piece::Text("b"), delim(";"),
+                           Piece::Text("c")));
+    let pieces : Vec<Piece> = regex.split_captures(",").collect();
+    assert!(pieces == vec!(Piece::Text(""), delim(","), Piece::Text("")));
+    assert!(regex.split_captures("").collect::<Vec<Piece>>() == vec!(Piece::Text("")));
+
+    let regex = Regex::from_str(" *(=|<=) *");
+    match regex.split_captures("x <= y").nth(1) {
+        Some(Piece::Delim(mr)) => assert!(mr.get(&1).map(|s| s.as_str()) == Some("<=")),
+        _ => panic!()
+    };
+}
+
+#[test]
+fn test_match_all() {
+    let regex = Regex::from_str("(\\d+)-(\\d+)");
+    let inputs = ["1-2", "12-345", "1-", "x1-2", "", "3-4"];
+    assert!(regex.match_all(inputs.iter().cloned()) ==
+            vec!(true, true, false, false, false, true));
+    assert!(regex.match_all(Vec::new()).is_empty());
+
+    let lines = String::from("ab\nb\naab");
+    assert!(Regex::from_str("a*b").match_all(lines.lines()) == vec!(true, true, true));
+}
+
+#[test]
+fn test_group_zero() {
+    let regex = Regex::from_str("a(b)?");
+    let whole = |mr : Option<MatchResult>| mr.and_then(|mr| mr.get(&0).cloned());
+    assert!(whole(regex.match_str("a")) == Some("a".to_string()));
+    assert!(whole(regex.match_str_lazy("ab")) == Some("ab".to_string()));
+    let chars : Vec<char> = "ab".chars().collect();
+    assert!(whole(regex.match_char_slice(&chars)) == Some("ab".to_string()));
+    assert!(whole(regex.match_chars(&mut "ab".chars())) == Some("ab".to_string()));
+    assert!(whole(regex.consume("abc").map(|(mr, _)| mr)) == Some("ab".to_string()));
+    assert!(whole(regex.find_with_captures("xab").map(|(_, mr)| mr)) == Some("ab".to_string()));
+    match regex.full_match("ab") {
+        FullMatch::Full(mr) => assert!(whole(Some(mr)) == Some("ab".to_string())),
+        _ => panic!()
+    }
+
+    let caps = regex.captures("a").unwrap();
+    assert!(caps.get(0) == Some("a"));
+    assert!(caps.iter_spans().next() == Some(Some(0..1)));
+    let wholes : Vec<Option<String>> = regex.captures_iter("xab a")
+        .map(|caps| caps.get(0).map(|s| s.to_string()))
+        .collect();
+    assert!(wholes == vec!(Some("ab".to_string()), Some("a".to_string())));
+    let mut m = regex.incremental();
+    m.feed("ab");
+    assert!(whole(m.finish()) == Some("ab".to_string()));
+
+    // The tree only has the pattern's own groups.
+    assert!(regex.ast() == Regex::from_str("a(b)?").ast());
+    assert!(regex.group_count() == 1);
+}
+
+#[test]
+fn test_class_canonical() {
+    assert!(Regex::from_str("[ba]") == Regex::from_str("[ab]"));
+    assert!(Regex::from_str("[ba]").to_string() == "[a-b]");
+    assert!(Regex::from_str("[cba a]").to_string() == "[ a-c]");
+    assert!(Regex::from_str("[c-da-bx]").ast() == Regex::from_str("[xa-d]").ast());
+
+    // Trees built by hand print, build and compare in the same form.
+    let class = Ast::Class { ranges : vec!(('b', 'b'), ('x', 'w'), ('a', 'c')), negated : false };
+    assert!(class.to_string() == "[a-c]");
+    let regex = Regex::from_ast(&class, vec!(None), NodeOptions::default());
+    assert!(regex == Regex::from_str("[abc]"));
+    assert!(regex.match_str("x").is_none());
+}
+
+#[test]
+fn test_dot_matches_newline() {
+    test_no_match("a.b", "a\nb");
+    test_match("(?s)a.b", "a\nb");
+    test_match("(?s:.)\n", "\n\n");
+    test_no_match("(?s:a).b", "a\nb");
+    test_no_match("(?s)a(?-s).b", "a\nb");
+    test_match("(?s)a(?-s:x|.\n)", "ab\n");
+    test_no_match("(?s)a(?-s:x|.\n)", "a\n\n");
+
+    let dotall = |pattern : &str| {
+        RegexBuilder::new(pattern).dot_matches_newline(true).build().unwrap()
+    };
+    assert!(dotall("a.b").match_str("a\nb").is_some());
+    assert!(dotall("(?-s)a.b").match_str("a\nb").is_none());
+    assert!(dotall("(?-s:a.)b").match_str("a\nb").is_none());
+    assert!(dotall("(?-s:a).b").match_str("a\nb").is_some());
+    assert!(dotall(".+").find("x\ny").map(|m| m.range()) == Some(0..3));
+
+    // The tree keeps the flag, so the regex prints as it matches.
+    assert!(dotall("a.").to_string() == "a(?s:.)");
+    assert!(Regex::from_str("(?s).*").to_string() == "(?s:.)*");
+    assert!(Regex::from_str("(?s:.)").ast() == dotall(".").ast());
+
+    let regex = RegexBuilder::new(".").dot_matches_newline(true).ascii(true).build().unwrap();
+    assert!(regex.match_str("\n").is_some());
+    assert!(regex.match_str("\u{e9}").is_none());
+    let regex = RegexBuilder::new("(?s).").dot_matches_grapheme(true).build().unwrap();
+    assert!(regex.match_str("\n").is_some());
+}
+
+#[test]
+fn test_match_str_as_ref() {
+    let regex = Regex::from_str("a(b+)");
+    let borrowed : Cow<str> = Cow::Borrowed("abb");
+    let owned : Cow<str> = Cow::Owned("ab".to_string());
+    let group = |mr : Option<MatchResult>| mr.and_then(|mr| mr.get(&1).cloned());
+    assert!(group(regex.match_str(&borrowed)) == Some("bb".to_string()));
+    assert!(group(regex.match_str(&owned)) == Some("b".to_string()));
+    assert!(regex.match_str(Cow::Borrowed("ac")).is_none());
+    assert!(regex.match_str(String::from("ab")).is_some());
+
+    let line = String::from("abbb");
+    assert!(regex.match_str(&line).is_some());
+    assert!(regex.match_str(line.clone()).is_some());
+    assert!(regex.match_str(&line[..2]).is_some());
+    assert!(regex.match_str(&line[..1]).is_none());
+}
+
+#[test]
+fn test_group_numbering() {
+    let mut mr = MatchResult::new();
+    mr.insert(1, "y".to_string());
+    test_result("(?:x)(y)", "xy", mr);
+
+    // Only capturing groups are numbered, whatever else opens with '('.
+    let regex = Regex::from_str("(?:a(?i:b))(?<n>c)(?<=c)(d)\\2(?(1)e|f)");
+    assert!(regex.group_count() == 2);
+    let caps = regex.captures("aBcdde").unwrap();
+    assert!(caps.get(1) == Some("c") && caps.name("n") == Some("c"));
+    assert!(caps.get(2) == Some("d"));
+    assert!(regex.captures("aBcdd").is_none());
+}
+
+#[test]
+fn test_relative_backref() {
+    test_match("(\\w)\\k<-1>", "aa");
+    test_no_match("(\\w)\\k<-1>", "ab");
+    test_match("(a)(b)\\k<-2>\\k{-1}", "abab");
+    test_match("(a(b)\\k<-1>)c", "abbc");
+    test_match("(a(b))\\k<-2>", "abab");
+
+    let regex = Regex::from_str("(x)(y)\\k<-2>");
+    assert!(regex.to_string() == "(x)(y)\\1");
+    for pattern in &["\\k<-1>", "(a)\\k<-2>", "(a)\\k<-0>", "(a)\\k<-x>", "(a)\\k<-1"] {
+        let position = pattern.find('\\').unwrap();
+        assert!(Regex::try_from_str(pattern).err() ==
+                Some(ParseError::InvalidBackreference { position : position }));
+    }
+}
+
+#[test]
+fn test_intersects() {
+    let intersects = |a : &str, b : &str| Regex::from_str(a).intersects(&Regex::from_str(b));
+    assert!(intersects("a.*", ".*b") == Some(true));
+    assert!(intersects("abc", "abd") == Some(false));
+    assert!(intersects("[a-m]+", "[n-z]+") == Some(false));
+    assert!(intersects("[a-m]+", "[^a-f]*") == Some(true));
+    assert!(intersects("(ab)*", "a(ba)*b") == Some(true));
+    assert!(intersects("(aa)*", "a(aa)*") == Some(false));
+    assert!(intersects("x*", "y*") == Some(true));
+    assert!(intersects("\\d{3}-\\d{4}", "[0-9-]{7}") == Some(false));
+    assert!(intersects("\\d{3}-\\d{4}", "[0-9-]{8}") == Some(true));
+
+    // Anchors are checked where they'd hold.
+    assert!(intersects("a$", "a") == Some(true));
+    assert!(intersects("a\\Z", "a") == Some(true));
+    assert!(intersects("a\\Z\n", "a\n") == Some(true));
+    assert!(Regex::from_str("a\\Z\n").match_str("a\n").is_some());
+    assert!(intersects("a\\z\n", "a\n") == Some(false));
+    assert!(intersects("a\\Z\nb", "a\nb") == Some(false));
+    assert!(intersects("a^", "a") == Some(false));
+
+    // Without an automaton, the regex that can be listed is.
+    assert!(intersects("\\bab\\b", "[a-z]+") == Some(true));
+    assert!(intersects("(a)?(?(1)b|c)", "[^b]") == Some(true));
+    assert!(intersects("(a|b)\\1", "bb") == Some(true));
+    assert!(intersects("(a|b)\\1", "ab") == Some(false));
+    assert!(intersects("(a+)\\1", "a*").is_none());
+}
+
+#[test]
+fn test_match_type() {
+    let text = "id: \u{3bb}42;";
+    let m = Regex::from_str("\\d+").find(text).unwrap();
+    assert!(m.as_str() == "42");
+    assert!(m.start() == 6 && m.end() == 8);
+    assert!(m.range() == (6..8));
+    assert!(&text[m.range()] == m.as_str());
+
+    let m = Regex::from_str("x*").find("abc").unwrap();
+    assert!(m.as_str().is_empty() && m.range() == (0..0));
+    assert!(Regex::from_str("z").find("abc").is_none());
+}
+
+#[test]
+fn test_partial_match() {
+    let regex = Regex::from_str("abc");
+    assert!(regex.partial_match("ab") == PartialResult::Partial);
+    assert!(regex.partial_match("") == PartialResult::Partial);
+    assert!(regex.partial_match("abd") == PartialResult::None);
+    assert!(regex.partial_match("abcd") == PartialResult::None);
+    let mut mr = MatchResult::new();
+    mr.insert(0, "abc".to_string());
+    assert!(regex.partial_match("abc") == PartialResult::Full(mr));
+
+    let regex = Regex::from_str("(\\d+)-(\\d+);");
+    assert!(regex.partial_match("12-") == PartialResult::Partial);
+    assert!(regex.partial_match("12-3") == PartialResult::Partial);
+    assert!(regex.partial_match("12-;") == PartialResult::None);
+    match regex.partial_match("12-3;") {
+        PartialResult::Full(mr) => assert!(mr.get(&2).map(|s| s.as_str()) == Some("3")),
+        _ => panic!()
+    }
+    assert!(Regex::from_str("a|b(c|d)e").partial_match("bd") == PartialResult::Partial);
+    assert!(matches!(Regex::from_str("x+").partial_match("xxx"), PartialResult::Full(_)));
+}
+
+#[test]
+fn test_capture_only() {
+    let regex = RegexBuilder::new("(\\w+)@(\\w+)\\.(\\w+)").capture_only(&[2]).build().unwrap();
+    let mr = regex.match_str("me@example.org").unwrap();
+    assert!(mr.keys().cloned().collect::<Vec<usize>>() == vec!(0, 2));
+    assert!(mr.get(&2).map(|s| s.as_str()) == Some("example"));
+
+    let caps = regex.captures("me@example.org").unwrap();
+    assert!(caps.get(0) == Some("me@example.org"));
+    assert!(caps.get(1).is_none() && caps.get(3).is_none());
+    assert!(caps.get_all(1).is_empty());
+    assert!(caps.iter_spans().collect::<Vec<_>>() == vec!(Some(0..14), None, Some(3..10), None));
+
+    // Groups left out still work for backreferences.
+    let pattern = "(a+)-(\\1)";
+    let regex = RegexBuilder::new(pattern).capture_only(&[2]).build().unwrap();
+    let mr = regex.match_str("aa-aa").unwrap();
+    assert!(mr.len() == 2 && mr.get(&2).map(|s| s.as_str()) == Some("aa"));
+    assert!(regex.match_str("aa-a").is_none());
+
+    // Derived regexes report the same groups, renumbered as they are.
+    let reported = |regex : &Regex, s : &str| {
+        regex.match_str(s).unwrap().keys().cloned().collect::<Vec<usize>>()
+    };
+    assert!(reported(&regex.to_lazy(), "aa-aa") == vec!(0, 2));
+    let other = RegexBuilder::new("(b)(c)").capture_only(&[1]).build().unwrap();
+    let combined = regex.concat(&other);
+    assert!(reported(&combined, "aa-aabc") == vec!(0, 2, 3));
+    assert!(reported(&combined.or(&Regex::from_str("(x)")), "x") == vec!(0, 5));
+    assert!(reported(&Regex::from_str("(x)").concat(&other), "xbc") == vec!(0, 1, 2));
+    let pattern = "(a)(b)\\2";
+    let stripped = RegexBuilder::new(pattern).capture_only(&[2]).build().unwrap()
+        .without_captures();
+    assert!(reported(&stripped, "abb") == vec!(0, 1));
+
+    // They count for equality.
+    assert!(regex != Regex::from_str("(a+)-(\\1)"));
+    assert!(combined == regex.concat(&other));
 }